@@ -0,0 +1,110 @@
+//! Compression throughput and ratio on representative frames.
+//!
+//! Run with `cargo bench --bench compression`. Throughput is reported in
+//! bytes of raw RGBA per second. For the ratio a running server achieves on
+//! real content, see `retrostream_compression_ratio` in `/metrics`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use screen_stream_backend::{
+    compression::{compress_into, Compressor},
+    config::{CompressionConfig, Config},
+    testcard,
+};
+use std::hint::black_box;
+use std::sync::{atomic::AtomicU64, Arc};
+
+const WIDTH: u32 = 1280;
+const HEIGHT: u32 = 720;
+
+/// zstd levels swept by `bench_compress`, including the default of 3
+const LEVELS: [i32; 4] = [1, 3, 9, 19];
+
+/// Smooth diagonal gradient, like a desktop background
+fn gradient() -> Vec<u8> {
+    let mut rgba = Vec::with_capacity((WIDTH * HEIGHT * 4) as usize);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            rgba.extend_from_slice(&[(x * 255 / WIDTH) as u8, (y * 255 / HEIGHT) as u8, 128, 255]);
+        }
+    }
+    rgba
+}
+
+/// Incompressible pixels, the worst case
+fn noise() -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..WIDTH * HEIGHT * 4)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn frames() -> [(&'static str, Vec<u8>); 3] {
+    [
+        ("gradient", gradient()),
+        ("demo", testcard::demo_frame(WIDTH, HEIGHT, 1.0)),
+        ("noise", noise()),
+    ]
+}
+
+fn bench_compress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compress");
+    group.sample_size(10);
+    let mut out = Vec::new();
+
+    for (name, frame) in frames() {
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        for level in LEVELS {
+            group.bench_with_input(BenchmarkId::new(name, level), &frame, |b, frame| {
+                b.iter(|| {
+                    out.clear();
+                    compress_into(black_box(frame), level, &mut out).unwrap();
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+/// The whole per-frame path with the default `CompressionConfig`, hold
+/// markers aside: delta selection, compression and message assembly.
+fn bench_create_frame_message(c: &mut Criterion) {
+    let config = Config::default();
+    let mut group = c.benchmark_group("create_frame_message");
+    group.sample_size(10);
+
+    for (name, frame) in frames() {
+        // Every iteration feeds the same frame, which would otherwise be
+        // sent as a hold marker
+        let compression = CompressionConfig {
+            max_hold_frames: 0,
+            ..config.compression.clone()
+        };
+        let mut compressor = Compressor::new(
+            compression,
+            config.capture.quality,
+            config.capture.fps,
+            Arc::new(AtomicU64::new(0)),
+        );
+
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                compressor
+                    .create_frame_message(black_box(frame.clone()), WIDTH, HEIGHT)
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_compress, bench_create_frame_message);
+criterion_main!(benches);
@@ -56,6 +56,13 @@ class App {
       this.logger.error('Stream error:', error);
     });
 
+    this.streamClient.on('hello', (info) => {
+      this.logger.info(`Stream: ${info.width ?? '?'}x${info.height ?? '?'} @ ${info.fps} FPS, ${info.format}`);
+      if (info.width && info.height) {
+        this.uiController.setFrameSize(info.width, info.height);
+      }
+    });
+
     this.streamClient.on('frame', (data) => {
       this.uiController.renderFrame(data.data, data.metadata);
       this.uiController.updateStats({
@@ -1,21 +1,69 @@
 import { EventEmitter } from '../utils/EventEmitter';
 import { Logger } from '../utils/Logger';
+import { crc32 } from '../utils/Crc32';
 import { Config, QualityConfig, QualitySettings } from '../config/Config';
 import { FrameBuffer } from './FrameBuffer';
 import { Decompressor } from './Decompressor';
 
+// Must match PROTOCOL_VERSION in the backend's compression.rs
+const PROTOCOL_VERSION = 5;
+// Must match AUDIO_PROTOCOL_VERSION in the backend's audio.rs
+const AUDIO_PROTOCOL_VERSION = 1;
+
+// Indexed by the header's color format byte
+const COLOR_FORMATS = ['rgba', 'bgra', 'rgb', 'gray'] as const;
+export type ColorFormat = (typeof COLOR_FORMATS)[number];
+const BYTES_PER_PIXEL: Record<ColorFormat, number> = { rgba: 4, bgra: 4, rgb: 3, gray: 1 };
+
 export interface FrameMetadata {
   width: number;
   height: number;
   compressed: boolean;
   timestamp: number;
   frameId: number;
+  // Rate the server captures at, which can be below the display rate
+  sourceFps: number;
+  // Same picture as the previous frame, sent without a payload
+  hold: boolean;
+  // Compressed with the zstd dictionary served at GET /dictionary
+  dictionary: boolean;
+  // Delta frame made of changed tiles rather than changed-pixel spans
+  tiles: boolean;
+  // Pixel layout of raw payloads, see capture.color_format
+  colorFormat: ColorFormat;
   latency?: number;
 }
 
+// Sent by the server as a JSON text message before the first frame
+export interface StreamInfo {
+  // Sent back as ?session= on reconnect to count as the same viewer
+  session: string;
+  protocol_version: number;
+  width: number | null;
+  height: number | null;
+  fps: number;
+  format: 'zstd' | 'png' | 'jpeg' | 'webp';
+  // Frame timestamps minus this are monotonic ms since the server started
+  clock_base: number;
+}
+
+// An audio message, sent with capture.audio on the server
+export interface AudioPacket {
+  sampleRate: number;
+  channels: number;
+  // Same clock as FrameMetadata.timestamp
+  timestamp: number;
+  // Counts up by one per packet
+  sequence: number;
+  // Interleaved
+  samples: Int16Array;
+}
+
 export interface StreamStats {
   fps: number;
   latency: number;
+  // Round trip of the last ping command, in ms
+  rtt: number;
   droppedFrames: number;
   totalFrames: number;
 }
@@ -25,6 +73,8 @@ interface StreamEvents extends Record<string, unknown> {
   disconnected: void;
   error: Error;
   frame: { data: ImageData; metadata: FrameMetadata };
+  audio: AudioPacket;
+  hello: StreamInfo;
   stats: StreamStats;
 }
 
@@ -43,10 +93,15 @@ export class StreamClient extends EventEmitter<StreamEvents> {
   private currentQuality: keyof QualitySettings = 'medium';
   private isConnected = false;
   private isPaused = false;
+  private session?: string;
+  // Send time of each unanswered ping, by nonce
+  private pings = new Map<number, number>();
+  private nextNonce = 0;
   
   private stats: StreamStats = {
     fps: 0,
     latency: 0,
+    rtt: 0,
     droppedFrames: 0,
     totalFrames: 0,
   };
@@ -74,8 +129,13 @@ export class StreamClient extends EventEmitter<StreamEvents> {
 
     try {
       this.logger.info(`Connecting to ${this.config.getServerUrl()}`);
-      
-      this.ws = new WebSocket(this.config.getServerUrl());
+
+      // Resuming skips the hello; the earlier one still applies
+      const url = new URL(this.config.getServerUrl());
+      if (this.session) {
+        url.searchParams.set('session', this.session);
+      }
+      this.ws = new WebSocket(url.toString());
       this.ws.binaryType = 'arraybuffer';
       
       this.setupWebSocketHandlers();
@@ -141,7 +201,22 @@ export class StreamClient extends EventEmitter<StreamEvents> {
     });
   }
 
-  private handleMessage(data: ArrayBuffer): void {
+  private handleMessage(data: ArrayBuffer | string): void {
+    if (typeof data === 'string') {
+      this.handleTextMessage(data);
+      return;
+    }
+
+    // Audio messages start with "RA" rather than "RS"
+    const view = new DataView(data);
+    if (data.byteLength >= 2 && view.getUint8(0) === 0x52 && view.getUint8(1) === 0x41) {
+      const audio = this.parseAudioMessage(data);
+      if (audio) {
+        this.emit('audio', audio);
+      }
+      return;
+    }
+
     try {
       const frameData = this.parseFrameMessage(data);
       if (frameData) {
@@ -152,36 +227,101 @@ export class StreamClient extends EventEmitter<StreamEvents> {
     }
   }
 
+  private handleTextMessage(text: string): void {
+    try {
+      const message = JSON.parse(text);
+      if (message.type === 'hello') {
+        const info = message as StreamInfo;
+        this.session = info.session;
+        if (info.protocol_version !== PROTOCOL_VERSION) {
+          this.logger.warning(`Server speaks protocol version ${info.protocol_version}, expected ${PROTOCOL_VERSION}`);
+        }
+        this.emit('hello', info);
+      } else if (message.type === 'pong') {
+        const sentAt = this.pings.get(message.nonce);
+        if (sentAt !== undefined) {
+          this.pings.delete(message.nonce);
+          this.stats.rtt = Math.round(performance.now() - sentAt);
+        }
+      } else if (message.type === 'error') {
+        this.logger.warning(`Server rejected a command (${message.code}): ${message.message}`);
+      }
+    } catch (error) {
+      this.logger.error('Failed to parse server message:', error);
+    }
+  }
+
+  // Message layout: ["RA"][version u8][u32 sample rate][u8 channels][u8 format][u64 timestamp][u64 sequence][s16le samples]
+  private parseAudioMessage(data: ArrayBuffer): AudioPacket | null {
+    const view = new DataView(data);
+    const headerLength = 25;
+    if (data.byteLength < headerLength || view.getUint8(2) !== AUDIO_PROTOCOL_VERSION || view.getUint8(8) !== 0) {
+      this.logger.warning('Unsupported audio message');
+      return null;
+    }
+
+    return {
+      sampleRate: view.getUint32(3, true),
+      channels: view.getUint8(7),
+      timestamp: Number(view.getBigUint64(9, true)),
+      sequence: Number(view.getBigUint64(17, true)),
+      samples: new Int16Array(data.slice(headerLength)),
+    };
+  }
+
+  // Message layout: ["RS"][version u8][42-byte header][payload], see FrameHeader::to_bytes
   private parseFrameMessage(data: ArrayBuffer): { header: FrameMetadata; payload: ArrayBuffer } | null {
     const view = new DataView(data);
+    const prefixLength = 3;
+    const headerLength = 42;
     
-    if (data.byteLength < 4) {
+    if (data.byteLength < prefixLength + headerLength) {
       this.logger.warning('Frame message too short');
       return null;
     }
 
-    const headerLength = view.getUint32(0, true);
-    
-    if (data.byteLength < 4 + headerLength) {
-      this.logger.warning('Invalid frame message format');
+    if (view.getUint8(0) !== 0x52 || view.getUint8(1) !== 0x53) {
+      this.logger.warning('Not a frame message');
       return null;
     }
 
-    const headerBytes = data.slice(4, 4 + headerLength);
-    const headerText = new TextDecoder().decode(headerBytes);
-    
-    try {
-      const header = JSON.parse(headerText) as FrameMetadata;
-      const payload = data.slice(4 + headerLength);
-      
-      // Calculate latency
-      header.latency = Date.now() - header.timestamp;
-      
-      return { header, payload };
-    } catch (error) {
-      this.logger.error('Failed to parse frame header:', error);
+    const version = view.getUint8(2);
+    if (version !== PROTOCOL_VERSION) {
+      this.logger.warning(`Unsupported protocol version ${version}, expected ${PROTOCOL_VERSION}`);
+      return null;
+    }
+
+    const flags = view.getUint8(prefixLength + 8);
+    const colorFormat = COLOR_FORMATS[view.getUint8(prefixLength + 41)];
+    if (!colorFormat) {
+      this.logger.warning('Unknown color format');
+      return null;
+    }
+    const header: FrameMetadata = {
+      width: view.getUint32(prefixLength, true),
+      height: view.getUint32(prefixLength + 4, true),
+      compressed: (flags & 0x01) !== 0,
+      timestamp: Number(view.getBigUint64(prefixLength + 11, true)),
+      frameId: Number(view.getBigUint64(prefixLength + 19, true)),
+      sourceFps: view.getUint16(prefixLength + 39, true),
+      hold: (flags & 0x10) !== 0,
+      dictionary: (flags & 0x20) !== 0,
+      tiles: (flags & 0x40) !== 0,
+      colorFormat,
+    };
+    const payload = data.slice(prefixLength + headerLength);
+
+    // Sent with compression.verify_checksums
+    if ((flags & 0x08) !== 0 && crc32(new Uint8Array(payload)) !== view.getUint32(prefixLength + 35, true)) {
+      this.logger.warning(`Frame ${header.frameId} failed its checksum, dropping it`);
+      this.stats.droppedFrames++;
       return null;
     }
+
+    // Calculate latency
+    header.latency = Date.now() - header.timestamp;
+
+    return { header, payload };
   }
 
   private async processFrame(metadata: FrameMetadata, payload: ArrayBuffer): Promise<void> {
@@ -190,11 +330,20 @@ export class StreamClient extends EventEmitter<StreamEvents> {
       this.stats.totalFrames++;
       this.updateFPS();
 
-      // For now, assume data is uncompressed RGBA
+      // Nothing changed: show the latest frame again to keep the pace
+      if (metadata.hold) {
+        const latestFrame = this.frameBuffer.getLatestFrame();
+        if (latestFrame) {
+          this.emit('frame', { data: latestFrame.imageData, metadata });
+        }
+        return;
+      }
+
+      // For now, assume data is uncompressed
       const frameData = new Uint8Array(payload);
       
       // Validate frame size
-      const expectedSize = metadata.width * metadata.height * 4;
+      const expectedSize = metadata.width * metadata.height * BYTES_PER_PIXEL[metadata.colorFormat];
       if (frameData.length !== expectedSize) {
         this.logger.warning(`Frame size mismatch: expected ${expectedSize}, got ${frameData.length}`);
         return;
@@ -202,7 +351,7 @@ export class StreamClient extends EventEmitter<StreamEvents> {
 
       // Create ImageData
       const imageData = new ImageData(
-        new Uint8ClampedArray(frameData),
+        toRgba(frameData, metadata.colorFormat),
         metadata.width,
         metadata.height
       );
@@ -258,7 +407,10 @@ export class StreamClient extends EventEmitter<StreamEvents> {
   private startHeartbeat(): void {
     this.heartbeatTimer = window.setInterval(() => {
       if (this.ws?.readyState === WebSocket.OPEN) {
-        this.ws.send(JSON.stringify({ type: 'ping' }));
+        const nonce = this.nextNonce++;
+        this.pings.clear();
+        this.pings.set(nonce, performance.now());
+        this.ws.send(JSON.stringify({ cmd: 'ping', nonce }));
       }
     }, this.config.getHeartbeatInterval());
   }
@@ -361,3 +513,27 @@ export class StreamClient extends EventEmitter<StreamEvents> {
     return this.isConnected;
   }
 }
+
+// Expand pixels in any color format to the RGBA that ImageData takes
+function toRgba(pixels: Uint8Array, format: ColorFormat): Uint8ClampedArray {
+  if (format === 'rgba') {
+    return new Uint8ClampedArray(pixels);
+  }
+  const bpp = BYTES_PER_PIXEL[format];
+  const rgba = new Uint8ClampedArray((pixels.length / bpp) * 4);
+  for (let i = 0, j = 0; i < pixels.length; i += bpp, j += 4) {
+    if (format === 'gray') {
+      rgba[j] = rgba[j + 1] = rgba[j + 2] = pixels[i];
+    } else if (format === 'bgra') {
+      rgba[j] = pixels[i + 2];
+      rgba[j + 1] = pixels[i + 1];
+      rgba[j + 2] = pixels[i];
+    } else {
+      rgba[j] = pixels[i];
+      rgba[j + 1] = pixels[i + 1];
+      rgba[j + 2] = pixels[i + 2];
+    }
+    rgba[j + 3] = format === 'bgra' ? pixels[i + 3] : 255;
+  }
+  return rgba;
+}
@@ -164,6 +164,13 @@ export class UIController extends EventEmitter<UIEvents> {
     this.canvas.style.height = `${scaledHeight}px`;
   }
 
+  // Size the canvas from the server's hello, ahead of the first frame
+  public setFrameSize(width: number, height: number): void {
+    if (this.canvas && (this.canvas.width !== width || this.canvas.height !== height)) {
+      this.resizeCanvas(width, height);
+    }
+  }
+
   public renderFrame(frameData: ImageData, metadata: FrameMetadata): void {
     if (!this.ctx || !this.canvas) return;
 
@@ -0,0 +1,253 @@
+use crate::config::ServerConfig;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::Notify;
+
+/// Limits concurrent viewers to `max_connections`, optionally holding
+/// extra clients in a bounded FIFO queue until a slot frees up.
+pub struct Admission {
+    inner: Mutex<Inner>,
+    next_ticket: AtomicU64,
+    queue_when_full: bool,
+    max_queue_length: usize,
+}
+
+struct Inner {
+    /// Slots no client holds
+    free: usize,
+    /// Queued tickets, front first, with what wakes each once it's handed
+    /// a slot
+    waiting: VecDeque<(u64, Arc<Notify>)>,
+    /// Tickets handed a slot that they haven't taken yet
+    granted: HashSet<u64>,
+}
+
+pub enum Admit {
+    /// A slot was free; it is held for the life of the connection
+    Now(Slot),
+    /// At capacity, waiting in the queue
+    Queued(QueueTicket),
+}
+
+impl Admission {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                free: config.max_connections,
+                waiting: VecDeque::new(),
+                granted: HashSet::new(),
+            }),
+            next_ticket: AtomicU64::new(0),
+            queue_when_full: config.queue_when_full,
+            max_queue_length: config.max_queue_length,
+        }
+    }
+
+    /// Admit a new client, queue it, or return `None` to reject it.
+    pub fn try_admit(self: &Arc<Self>) -> Option<Admit> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // Don't let newcomers jump ahead of clients already queued
+        if inner.waiting.is_empty() && inner.free > 0 {
+            inner.free -= 1;
+            return Some(Admit::Now(Slot(self.clone())));
+        }
+
+        if !self.queue_when_full || inner.waiting.len() >= self.max_queue_length {
+            return None;
+        }
+
+        let id = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let admitted = Arc::new(Notify::new());
+        inner.waiting.push_back((id, admitted.clone()));
+
+        Some(Admit::Queued(QueueTicket {
+            admission: self.clone(),
+            id,
+            admitted,
+        }))
+    }
+
+    /// A slot was freed: hand it to the front of the queue, or leave it
+    /// free if nobody is waiting
+    fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.waiting.pop_front() {
+            Some((id, admitted)) => {
+                inner.granted.insert(id);
+                admitted.notify_one();
+            }
+            None => inner.free += 1,
+        }
+    }
+}
+
+/// A connection slot, freed for the next client on drop
+pub struct Slot(Arc<Admission>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// A place in the admission queue. Dropping it leaves the queue.
+pub struct QueueTicket {
+    admission: Arc<Admission>,
+    id: u64,
+    admitted: Arc<Notify>,
+}
+
+impl QueueTicket {
+    /// 1-based position in the queue, or 0 once handed a slot
+    pub fn position(&self) -> usize {
+        let inner = self.admission.inner.lock().unwrap();
+        inner
+            .waiting
+            .iter()
+            .position(|&(id, _)| id == self.id)
+            .map_or(0, |i| i + 1)
+    }
+
+    /// Wait for a slot. Freed slots go to the front of the queue, so
+    /// clients are admitted in the order they queued, whenever they
+    /// started waiting. Cancel safe.
+    pub async fn wait(&self) -> Slot {
+        // A slot handed over before this is awaited leaves a permit behind
+        self.admitted.notified().await;
+        self.admission.inner.lock().unwrap().granted.remove(&self.id);
+        Slot(self.admission.clone())
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        let mut inner = self.admission.inner.lock().unwrap();
+        inner.waiting.retain(|&(id, _)| id != self.id);
+        // Handed a slot but gone before taking it: pass it on
+        if inner.granted.remove(&self.id) {
+            drop(inner);
+            self.admission.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::time::Duration;
+
+    fn admission(max_connections: usize, queue_when_full: bool, max_queue_length: usize) -> Arc<Admission> {
+        let mut config = Config::default().server;
+        config.max_connections = max_connections;
+        config.queue_when_full = queue_when_full;
+        config.max_queue_length = max_queue_length;
+        Arc::new(Admission::new(&config))
+    }
+
+    fn admitted(admit: Option<Admit>) -> Slot {
+        match admit {
+            Some(Admit::Now(slot)) => slot,
+            _ => panic!("expected to be admitted"),
+        }
+    }
+
+    fn queued(admit: Option<Admit>) -> QueueTicket {
+        match admit {
+            Some(Admit::Queued(ticket)) => ticket,
+            _ => panic!("expected to be queued"),
+        }
+    }
+
+    #[test]
+    fn rejects_at_capacity_without_queueing() {
+        let admission = admission(1, false, 4);
+        let slot = admitted(admission.try_admit());
+        assert!(admission.try_admit().is_none());
+
+        drop(slot);
+        let _slot = admitted(admission.try_admit());
+    }
+
+    #[test]
+    fn queue_is_bounded_and_keeps_its_order() {
+        let admission = admission(1, true, 2);
+        let _slot = admitted(admission.try_admit());
+        let first = queued(admission.try_admit());
+        let second = queued(admission.try_admit());
+        assert!(admission.try_admit().is_none());
+        assert_eq!((first.position(), second.position()), (1, 2));
+
+        // Leaving moves everyone behind up and makes room at the back
+        drop(first);
+        assert_eq!(second.position(), 1);
+        let third = queued(admission.try_admit());
+        assert_eq!(third.position(), 2);
+    }
+
+    #[test]
+    fn newcomers_wait_behind_the_queue() {
+        let admission = admission(1, true, 4);
+        let slot = admitted(admission.try_admit());
+        let waiting = queued(admission.try_admit());
+
+        // The freed slot is for the client already queued, even before it
+        // comes to take it
+        drop(slot);
+        assert_eq!(waiting.position(), 0);
+        assert_eq!(queued(admission.try_admit()).position(), 1);
+    }
+
+    #[tokio::test]
+    async fn queued_clients_are_admitted_in_queue_order() {
+        let admission = admission(1, true, 4);
+        let slot = admitted(admission.try_admit());
+        let first = queued(admission.try_admit());
+        let second = queued(admission.try_admit());
+
+        // The second client starts waiting first, e.g. because its upgrade
+        // finished sooner
+        let second = tokio::spawn(async move { (second.wait().await, second) });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let first = tokio::spawn(async move { (first.wait().await, first) });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!first.is_finished() && !second.is_finished());
+
+        drop(slot);
+        let (first_slot, _first) = tokio::time::timeout(Duration::from_secs(5), first)
+            .await
+            .expect("first in line was never admitted")
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!second.is_finished());
+
+        drop(first_slot);
+        let (_slot, _second) = tokio::time::timeout(Duration::from_secs(5), second)
+            .await
+            .expect("second in line was never admitted")
+            .unwrap();
+    }
+
+    #[test]
+    fn slot_of_a_client_that_left_goes_to_the_next() {
+        let admission = admission(1, true, 4);
+        let slot = admitted(admission.try_admit());
+        let first = queued(admission.try_admit());
+        let second = queued(admission.try_admit());
+
+        // Handed to the first client, which leaves before taking it
+        drop(slot);
+        assert_eq!((first.position(), second.position()), (0, 1));
+        drop(first);
+        assert_eq!(second.position(), 0);
+
+        // and back to free once nobody is left to take it, but only one
+        drop(second);
+        let _slot = admitted(admission.try_admit());
+        queued(admission.try_admit());
+    }
+}
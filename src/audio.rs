@@ -0,0 +1,235 @@
+use crate::{
+    clock,
+    compression::FrameMessage,
+    error::{AppError, AppResult},
+};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SampleFormat, SizedSample,
+};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+
+/// First bytes of every audio message. Frame messages start with
+/// `compression::FRAME_MAGIC` instead, so clients can route each binary
+/// message by its first two bytes.
+pub const AUDIO_MAGIC: [u8; 2] = *b"RA";
+
+/// Version of the audio message layout, see `packet`
+pub const AUDIO_PROTOCOL_VERSION: u8 = 1;
+
+/// `AudioHeader.format` of interleaved signed 16-bit little-endian PCM,
+/// the only format sent so far
+const FORMAT_S16LE: u8 = 0;
+
+/// Audio per packet. Short enough to keep latency low, long enough that
+/// headers are a small fraction of each message.
+const PACKET_MS: u32 = 20;
+
+/// Callback buffers waiting to be packetized. Buffers beyond this are
+/// dropped rather than blocking the audio callback.
+const CALLBACK_QUEUE: usize = 64;
+
+/// Capture `capture.audio_device`, or the default input device, and
+/// publish it to `audio_tx` as packets of `PACKET_MS`, paced by the audio
+/// device rather than the frame rate.
+pub fn spawn(device_name: Option<String>, audio_tx: broadcast::Sender<FrameMessage>) -> AppResult<()> {
+    let (samples_tx, samples_rx) = mpsc::channel(CALLBACK_QUEUE);
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+
+    // cpal streams can't move between threads on every platform, so the
+    // stream lives on a thread of its own for the rest of the process
+    std::thread::Builder::new()
+        .name("audio-capture".to_string())
+        .spawn(move || match open_stream(device_name.as_deref(), samples_tx) {
+            Ok((stream, sample_rate, channels)) => {
+                let _ = ready_tx.send(Ok((sample_rate, channels)));
+                let _stream = stream;
+                loop {
+                    std::thread::park();
+                }
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+            }
+        })?;
+
+    let (sample_rate, channels) = ready_rx
+        .recv()
+        .map_err(|_| AppError::CaptureError("Audio capture thread exited".to_string()))??;
+    tokio::spawn(packetize(samples_rx, sample_rate, channels, audio_tx));
+    Ok(())
+}
+
+fn open_stream(
+    device_name: Option<&str>,
+    samples_tx: mpsc::Sender<Vec<i16>>,
+) -> AppResult<(cpal::Stream, u32, u16)> {
+    let error = |e: &dyn std::fmt::Display| AppError::CaptureError(format!("Audio capture failed: {}", e));
+    let host = cpal::default_host();
+    let device = match device_name {
+        Some(name) => host
+            .input_devices()
+            .map_err(|e| error(&e))?
+            .find(|device| device.name().is_ok_and(|n| n.contains(name))),
+        None => host.default_input_device(),
+    }
+    .ok_or_else(|| AppError::CaptureError(format!("No audio input device {:?}", device_name)))?;
+
+    let config = device.default_input_config().map_err(|e| error(&e))?;
+    let (sample_rate, channels) = (config.sample_rate().0, config.channels());
+    info!(
+        "Capturing audio from {} at {} Hz, {} channels",
+        device.name().unwrap_or_default(),
+        sample_rate,
+        channels
+    );
+
+    let stream = match config.sample_format() {
+        SampleFormat::I16 => build_stream::<i16>(&device, &config.into(), samples_tx),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config.into(), samples_tx),
+        SampleFormat::I32 => build_stream::<i32>(&device, &config.into(), samples_tx),
+        SampleFormat::F32 => build_stream::<f32>(&device, &config.into(), samples_tx),
+        other => {
+            return Err(AppError::CaptureError(format!(
+                "Unsupported audio sample format {}",
+                other
+            )))
+        }
+    }
+    .map_err(|e| error(&e))?;
+    stream.play().map_err(|e| error(&e))?;
+
+    Ok((stream, sample_rate, channels))
+}
+
+/// An input stream converting whatever the device delivers to i16
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    samples_tx: mpsc::Sender<Vec<i16>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    i16: FromSample<T>,
+{
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let samples = data.iter().map(|&s| i16::from_sample_(s)).collect();
+            // A full queue means packetizing fell behind; drop the buffer
+            let _ = samples_tx.try_send(samples);
+        },
+        |e| warn!("Audio stream error: {}", e),
+        None,
+    )
+}
+
+/// Cut the callback buffers into packets of exactly `PACKET_MS`.
+async fn packetize(
+    mut samples_rx: mpsc::Receiver<Vec<i16>>,
+    sample_rate: u32,
+    channels: u16,
+    audio_tx: broadcast::Sender<FrameMessage>,
+) {
+    let packet_len = (sample_rate * PACKET_MS / 1000) as usize * channels as usize;
+    let mut pending = Vec::with_capacity(packet_len * 2);
+    let mut sequence = 0;
+
+    while let Some(samples) = samples_rx.recv().await {
+        pending.extend_from_slice(&samples);
+        while pending.len() >= packet_len {
+            let header = AudioHeader {
+                sample_rate,
+                channels: channels.min(u8::MAX as u16) as u8,
+                format: FORMAT_S16LE,
+                timestamp: clock::now_ms(),
+                sequence,
+            };
+            sequence += 1;
+            // Fails only while nobody is listening
+            let _ = audio_tx.send(packet(&header, &pending[..packet_len]));
+            pending.drain(..packet_len);
+        }
+    }
+}
+
+/// Fields of an audio message, after the magic and version
+pub struct AudioHeader {
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// 0: interleaved s16le
+    pub format: u8,
+    /// Capture time, on the same clock as `FrameHeader.timestamp`
+    pub timestamp: u64,
+    /// Counts up by one per packet, so clients can spot gaps
+    pub sequence: u64,
+}
+
+/// Size of an encoded `AudioHeader`
+pub const AUDIO_HEADER_LEN: usize = 22;
+
+/// Build an audio message:
+/// `["RA"][version u8][u32 sample rate][u8 channels][u8 format][u64 timestamp][u64 sequence][samples]`,
+/// all little-endian.
+pub fn packet(header: &AudioHeader, samples: &[i16]) -> FrameMessage {
+    let mut message = Vec::with_capacity(AUDIO_MAGIC.len() + 1 + AUDIO_HEADER_LEN + samples.len() * 2);
+    message.extend_from_slice(&AUDIO_MAGIC);
+    message.push(AUDIO_PROTOCOL_VERSION);
+    message.extend_from_slice(&header.sample_rate.to_le_bytes());
+    message.push(header.channels);
+    message.push(header.format);
+    message.extend_from_slice(&header.timestamp.to_le_bytes());
+    message.extend_from_slice(&header.sequence.to_le_bytes());
+    for sample in samples {
+        message.extend_from_slice(&sample.to_le_bytes());
+    }
+    message.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_has_the_audio_tag_and_metadata() {
+        let header = AudioHeader {
+            sample_rate: 48_000,
+            channels: 2,
+            format: FORMAT_S16LE,
+            timestamp: 1_700_000_000_123,
+            sequence: 7,
+        };
+        let message = packet(&header, &[1, -2, i16::MAX]);
+
+        assert_eq!(message[..2], AUDIO_MAGIC);
+        assert_eq!(message[2], AUDIO_PROTOCOL_VERSION);
+        let header_bytes = &message[3..3 + AUDIO_HEADER_LEN];
+        assert_eq!(u32::from_le_bytes(header_bytes[..4].try_into().unwrap()), 48_000);
+        assert_eq!(header_bytes[4], 2);
+        assert_eq!(header_bytes[5], FORMAT_S16LE);
+        assert_eq!(u64::from_le_bytes(header_bytes[6..14].try_into().unwrap()), 1_700_000_000_123);
+        assert_eq!(u64::from_le_bytes(header_bytes[14..22].try_into().unwrap()), 7);
+        assert_eq!(message[3 + AUDIO_HEADER_LEN..], [1, 0, 0xfe, 0xff, 0xff, 0x7f]);
+        // Never mistaken for a frame
+        assert_ne!(message[..2], crate::compression::FRAME_MAGIC);
+    }
+
+    #[tokio::test]
+    async fn samples_are_cut_into_numbered_packets() {
+        let (samples_tx, samples_rx) = mpsc::channel(CALLBACK_QUEUE);
+        let (audio_tx, mut audio_rx) = broadcast::channel(16);
+        tokio::spawn(packetize(samples_rx, 8_000, 1, audio_tx));
+
+        // 20ms at 8kHz mono is 160 samples; 400 make two packets and a bit
+        samples_tx.send(vec![3; 250]).await.unwrap();
+        samples_tx.send(vec![3; 150]).await.unwrap();
+        for sequence in 0..2 {
+            let message = audio_rx.recv().await.unwrap();
+            assert_eq!(message.len(), 3 + AUDIO_HEADER_LEN + 160 * 2);
+            assert_eq!(u32::from_le_bytes(message[3..7].try_into().unwrap()), 8_000);
+            assert_eq!(u64::from_le_bytes(message[17..25].try_into().unwrap()), sequence);
+        }
+        assert!(audio_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::AppState;
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tracing::warn;
+
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Reject requests without the configured `auth_token`, passed either as
+/// `?token=...` (browsers can't set headers on WebSocket upgrades) or as
+/// `Authorization: Bearer ...`. Open when no token is configured.
+pub async fn require_token(
+    State(state): State<AppState>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenQuery>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = state.config.server.auth_token.as_deref() else {
+        return next.run(request).await;
+    };
+
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let provided = query.token.as_deref().or(bearer);
+
+    if provided.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+        next.run(request).await
+    } else {
+        warn!("Rejecting unauthenticated request from {} to {}", remote_addr, request.uri().path());
+        (StatusCode::UNAUTHORIZED, "Invalid or missing token").into_response()
+    }
+}
+
+/// Compare without exiting early on the first mismatch, so response timing
+/// doesn't reveal how much of the token was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{body::Body, http::Request};
+
+    #[tokio::test]
+    async fn open_without_a_token() {
+        let state = testing::state(|_| {});
+        assert_eq!(testing::get(&state, "/config").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_and_wrong_tokens() {
+        let state = testing::state(|c| c.server.auth_token = Some("secret".to_string()));
+        assert_eq!(testing::get(&state, "/config").await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(testing::get(&state, "/config?token=secreT").await.status(), StatusCode::UNAUTHORIZED);
+        let request = Request::get("/config")
+            .header(header::AUTHORIZATION, "Bearer secret2")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(testing::send(&state, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn accepts_the_right_token() {
+        let state = testing::state(|c| c.server.auth_token = Some("secret".to_string()));
+        assert_eq!(testing::get(&state, "/config?token=secret").await.status(), StatusCode::OK);
+        let request = Request::get("/config")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(testing::send(&state, request).await.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
@@ -0,0 +1,38 @@
+use crate::config::CompressionFormat;
+use serde::Serialize;
+
+/// What this binary supports, so clients and operators can check before
+/// requesting a codec or transport the server wasn't built with.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub codecs: Vec<CompressionFormat>,
+    pub transports: Vec<&'static str>,
+    pub features: Vec<&'static str>,
+}
+
+impl Capabilities {
+    /// Built from compile-time information; codecs or transports behind
+    /// Cargo features should be pushed here under `cfg!(feature = "...")`.
+    pub fn detect() -> Self {
+        let mut features = vec![
+            "admission_queue",
+            "delta_frames",
+            "frame_dump",
+            "motion_detection",
+            "presets",
+        ];
+        // `capture.exclude_windows` isn't listed: whether windows can be
+        // enumerated is only known at runtime, and it's a no-op where not
+        if cfg!(feature = "audio") {
+            features.push("audio");
+        }
+
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            codecs: CompressionFormat::ALL.to_vec(),
+            transports: vec!["websocket", "mjpeg"],
+            features,
+        }
+    }
+}
@@ -1,19 +1,317 @@
-use crate::{compression::Compressor, config::Config, error::AppResult, metrics::Metrics};
-use std::sync::Arc;
-use tokio::sync::broadcast;
-use tracing::{debug, error, warn};
-use xcap::Monitor;
+use crate::{
+    compression::{
+        convert_color_format, read_frame_header, Compressor, EncodedFrame, FrameMessage, PendingFrame,
+        CAPTURE_BIT_DEPTH,
+    },
+    config::{CaptureMode, Config, FallbackFrame, HdrTonemap, OverrunBehavior, ScaleFilter},
+    fanout,
+    dump::FrameDumper,
+    error::{AppError, AppResult},
+    metrics::Metrics,
+    motion::{MotionDetector, MotionEvent},
+    processor::{self, FrameProcessor, Redact},
+    profile::RawFrame,
+    recording::{FrameRecorder, FrameReplayer},
+    source::{self, FrameSource},
+    testcard,
+};
+use futures_util::stream::{FuturesOrdered, StreamExt};
+use image::{imageops::FilterType, ColorType, DynamicImage, RgbaImage};
+use serde::Serialize;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::path::{Path, PathBuf};
+use std::sync::{atomic::AtomicU64, Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::{
+    sync::{broadcast, watch, Notify},
+    task::{JoinError, JoinHandle},
+    time::{Interval, MissedTickBehavior},
+};
+use tracing::{debug, error, info, warn};
+use xcap::{Monitor, Window};
+
+/// A monitor as reported by `GET /monitors`
+#[derive(Debug, Clone, Serialize)]
+pub struct MonitorInfo {
+    /// Value to use for `capture.monitor_index`
+    pub index: usize,
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub is_primary: bool,
+}
+
+/// List the monitors available for capture, in `monitor_index` order.
+pub fn list_monitors() -> AppResult<Vec<MonitorInfo>> {
+    let monitors = Monitor::all()
+        .map_err(|e| AppError::CaptureError(format!("Failed to list monitors: {}", e)))?;
+
+    Ok(monitors
+        .iter()
+        .enumerate()
+        .map(|(index, m)| MonitorInfo {
+            index,
+            name: m.name().unwrap_or_default(),
+            width: m.width().unwrap_or(0),
+            height: m.height().unwrap_or(0),
+            is_primary: m.is_primary().unwrap_or(false),
+        })
+        .collect())
+}
+
+/// A window as reported by `GET /windows`
+#[derive(Debug, Clone, Serialize)]
+pub struct WindowInfo {
+    /// Value to use for `capture.window_id`
+    pub id: u32,
+    pub title: String,
+    pub app_name: String,
+    pub width: u32,
+    pub height: u32,
+    pub is_minimized: bool,
+}
+
+/// List the windows available for capture in `window` mode.
+pub fn list_windows() -> AppResult<Vec<WindowInfo>> {
+    let windows = Window::all()
+        .map_err(|e| AppError::CaptureError(format!("Failed to list windows: {}", e)))?;
+
+    Ok(windows
+        .iter()
+        .filter_map(|w| {
+            Some(WindowInfo {
+                id: w.id().ok()?,
+                title: w.title().unwrap_or_default(),
+                app_name: w.app_name().unwrap_or_default(),
+                width: w.width().unwrap_or(0),
+                height: w.height().unwrap_or(0),
+                is_minimized: w.is_minimized().unwrap_or(false),
+            })
+        })
+        .collect())
+}
+
+/// Frame timer for the capture loop. What happens to the ticks a slow
+/// capture overruns is up to `capture.overrun`.
+fn capture_interval(config: &Config) -> Interval {
+    let mut interval = tokio::time::interval(config.frame_interval());
+    interval.set_missed_tick_behavior(match config.capture.overrun {
+        OverrunBehavior::Skip => MissedTickBehavior::Skip,
+        OverrunBehavior::Delay => MissedTickBehavior::Delay,
+        OverrunBehavior::Burst => MissedTickBehavior::Burst,
+    });
+    interval
+}
+
+/// How long the capture loop waits after `failures` errors in a row: `base`
+/// doubled for each failure after the first, up to `max`, less a random
+/// fraction of up to half, so servers that failed together don't all
+/// retry in lockstep.
+fn error_backoff(failures: u32, base: Duration, max: Duration) -> Duration {
+    let factor = 1u32.checked_shl(failures.saturating_sub(1)).unwrap_or(u32::MAX);
+    let delay = base.saturating_mul(factor).min(max);
+    // `RandomState` is randomly keyed each time, see `Sessions`
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    delay.mul_f64(1.0 - random / 2.0)
+}
+
+/// Convert `image` to 8-bit sRGB, tone mapping it with `operator` if it
+/// has more than 8 bits per channel. 8-bit images are SDR already and are
+/// only converted to RGBA.
+pub fn tonemap_to_srgb(image: DynamicImage, operator: HdrTonemap) -> RgbaImage {
+    let color = image.color();
+    let bits = color.bits_per_pixel() / color.channel_count() as u16;
+    if operator == HdrTonemap::Off || bits <= 8 {
+        return image.into_rgba8();
+    }
+
+    // Float images, e.g. Radiance HDR, hold linear light and can go past
+    // 1.0. Integer ones are sRGB-encoded and need decoding first.
+    let linear = matches!(color, ColorType::Rgb32F | ColorType::Rgba32F);
+    let mut pixels = image.into_rgba32f();
+    for pixel in pixels.pixels_mut() {
+        for c in &mut pixel.0[..3] {
+            *c = if linear { c.max(0.0) } else { srgb_to_linear(*c) };
+        }
+    }
+
+    // The brightest channel anywhere maps to white, so highlights roll off
+    // into the top of the range rather than clipping there
+    let peak = pixels.pixels().map(|p| max_channel(&p.0)).fold(1.0, f32::max);
+
+    let mut rgba = RgbaImage::new(pixels.width(), pixels.height());
+    for (out, pixel) in rgba.pixels_mut().zip(pixels.pixels()) {
+        let [r, g, b, a] = pixel.0;
+        let mapped = match operator {
+            HdrTonemap::Aces => [r, g, b].map(|c| aces(c) / aces(peak)),
+            _ => {
+                // Scaling all channels alike keeps each pixel's hue
+                let m = max_channel(&pixel.0);
+                let scale = (1.0 + m / (peak * peak)) / (1.0 + m);
+                [r * scale, g * scale, b * scale]
+            }
+        };
+        let [r, g, b] = mapped.map(|c| to_u8(linear_to_srgb(c.clamp(0.0, 1.0))));
+        out.0 = [r, g, b, to_u8(a.clamp(0.0, 1.0))];
+    }
+    rgba
+}
+
+fn max_channel(rgba: &[f32; 4]) -> f32 {
+    rgba[0].max(rgba[1]).max(rgba[2])
+}
+
+/// Krzysztof Narkowicz's fit of the ACES filmic curve
+fn aces(x: f32) -> f32 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn to_u8(c: f32) -> u8 {
+    (c * 255.0).round() as u8
+}
+
+/// Reject a captured frame that is empty or whose buffer doesn't hold
+/// exactly `width * height` RGBA pixels. Everything downstream indexes the
+/// buffer by its dimensions, so such a frame would panic the capture task
+/// rather than fail one capture.
+fn check_frame_size((rgba, width, height): (Vec<u8>, u32, u32)) -> AppResult<(Vec<u8>, u32, u32)> {
+    let expected = width as usize * height as usize * 4;
+    if expected == 0 || rgba.len() != expected {
+        return Err(AppError::CaptureError(format!(
+            "Captured a {}x{} frame of {} bytes, expected {}",
+            width,
+            height,
+            rgba.len(),
+            expected
+        )));
+    }
+    Ok((rgba, width, height))
+}
+
+/// A captured frame on its way to clients
+enum CapturedFrame {
+    /// Replayed from a recording, already encoded
+    Encoded(FrameMessage),
+    /// Waiting for `PendingFrame::encode` on a worker
+    Pending(PendingFrame),
+}
+
+/// Encodes running on the blocking thread pool, yielded in capture order so
+/// delta frames never overtake their keyframe
+type EncodeQueue = FuturesOrdered<JoinHandle<AppResult<EncodedFrame>>>;
+
+/// Capture loop bookkeeping
+struct LoopStats {
+    frames_sent: u64,
+    error_count: u64,
+    /// Errors since the last successful capture, see `error_backoff`
+    consecutive_errors: u32,
+    high_water_mark: usize,
+    above_high_water: bool,
+    /// Skipping captures for `throttle_on_backpressure`
+    throttled: bool,
+}
+
+impl LoopStats {
+    fn new(high_water_mark: usize) -> Self {
+        Self {
+            frames_sent: 0,
+            error_count: 0,
+            consecutive_errors: 0,
+            high_water_mark,
+            above_high_water: false,
+            throttled: false,
+        }
+    }
+
+    /// A capture succeeded, so the next error backs off from the start
+    fn record_capture(&mut self) {
+        self.consecutive_errors = 0;
+    }
+}
+
+/// The most recent keyframe message, for clients that just connected and
+/// for `/snapshot`. Lock poisoning is ignored: the frame is only ever
+/// replaced whole, so a panic elsewhere can't leave it half-written.
+pub type LatestFrame = Arc<RwLock<Option<FrameMessage>>>;
 
 pub struct ScreenCapture {
     compressor: Compressor,
     config: Arc<Config>,
     metrics: Arc<Metrics>,
     frame_count: u64,
+    dumper: Option<FrameDumper>,
+    last_frame: Option<(Vec<u8>, u32, u32)>,
+    source: Box<dyn FrameSource>,
+    redact: Redact,
+    /// Whether `redact` applies, when it can be switched at runtime
+    redactions_enabled: Option<watch::Receiver<bool>>,
+    /// `capture.processors`, in order
+    processors: Vec<Box<dyn FrameProcessor>>,
+    motion: Option<MotionDetector>,
+    region_warned: bool,
+    /// Failed source captures in a row, see `reinit_source`
+    source_failures: u32,
+    last_reinit: Option<std::time::Instant>,
+    latest_frame: Option<LatestFrame>,
+    monitor_select: Option<watch::Receiver<Option<usize>>>,
+    shutdown: Option<watch::Receiver<bool>>,
+    config_reload: Option<watch::Receiver<Arc<Config>>>,
+    raw_frames: Option<broadcast::Sender<Arc<RawFrame>>>,
+    recorder: Option<FrameRecorder>,
+    replay: Option<FrameReplayer>,
+    resolution: Option<watch::Sender<Option<(u32, u32)>>>,
+    viewer_joined: Option<Arc<Notify>>,
 }
 
 impl ScreenCapture {
-    pub fn new(config: Arc<Config>, metrics: Arc<Metrics>) -> AppResult<Self> {
-        let compressor = Compressor::new(config.compression.clone());
+    pub fn new(
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+        frame_counter: Arc<AtomicU64>,
+    ) -> AppResult<Self> {
+        let mut compressor = Compressor::new(
+            config.compression.clone(),
+            config.capture.quality,
+            config.capture.fps,
+            frame_counter,
+        );
+        compressor.set_color_format(config.capture.color_format);
+        let source = source::from_config(&config.capture)?;
+        let redact = Redact {
+            redactions: config.capture.redactions.clone(),
+        };
+        let processors = processor::from_config(&config.capture.processors);
+
+        if config.capture.high_bit_depth {
+            warn!(
+                "High bit depth capture is not supported by the capture backend, \
+                 falling back to {}-bit",
+                CAPTURE_BIT_DEPTH
+            );
+        }
+        if config.capture.hdr_tonemap != HdrTonemap::Off && config.capture.mode != CaptureMode::File {
+            warn!(
+                "The capture backend only delivers 8-bit SDR frames, capture.hdr_tonemap \
+                 only applies to capture.mode = \"file\""
+            );
+        }
 
         debug!("Screen capture initialized");
 
@@ -22,152 +320,1265 @@ impl ScreenCapture {
             config,
             metrics,
             frame_count: 0,
+            dumper: None,
+            last_frame: None,
+            source,
+            redact,
+            redactions_enabled: None,
+            processors,
+            motion: None,
+            region_warned: false,
+            source_failures: 0,
+            last_reinit: None,
+            latest_frame: None,
+            monitor_select: None,
+            shutdown: None,
+            config_reload: None,
+            raw_frames: None,
+            recorder: None,
+            replay: None,
+            resolution: None,
+            viewer_joined: None,
         })
     }
 
-    fn get_primary_monitor() -> AppResult<Monitor> {
-        let monitors = Monitor::all().map_err(|e| {
-            warn!("Failed to get monitors: {}, falling back to demo mode", e);
-            crate::error::AppError::CompressionError("No monitors available".to_string())
-        })?;
+    /// Write each captured frame into `dir` as a PNG, stopping after `limit` frames.
+    pub fn enable_frame_dump(&mut self, dir: PathBuf, limit: u64) -> AppResult<()> {
+        self.dumper = Some(FrameDumper::new(dir, limit)?);
+        Ok(())
+    }
 
-        monitors
-            .into_iter()
-            .find(|m| m.is_primary().unwrap_or(false))
-            .or_else(|| {
-                // If no primary monitor, get the first one
-                Monitor::all().ok()?.into_iter().next()
-            })
-            .ok_or_else(|| {
-                crate::error::AppError::CompressionError("No monitors available".to_string())
-            })
+    /// Append every frame message to `path` for later replay.
+    pub fn enable_recording(&mut self, path: PathBuf) -> AppResult<()> {
+        self.recorder = Some(FrameRecorder::new(path)?);
+        Ok(())
+    }
+
+    /// Send the frames recorded in `path` instead of capturing the screen,
+    /// looping at the end. Replayed frames skip motion detection and
+    /// per-client re-encoding, which need the raw pixels.
+    pub fn replay_from(&mut self, path: &Path) -> AppResult<()> {
+        self.replay = Some(FrameReplayer::open(path, self.config.frame_interval())?);
+        Ok(())
+    }
+
+    /// Publish motion events derived from consecutive captured frames.
+    pub fn enable_motion_detection(&mut self, events: broadcast::Sender<MotionEvent>) {
+        self.motion = Some(MotionDetector::new(self.config.motion.clone(), events));
+    }
+
+    /// Keep `latest` updated with the most recent keyframe message.
+    pub fn cache_latest_frame(&mut self, latest: LatestFrame) {
+        self.latest_frame = Some(latest);
+    }
+
+    /// Take the monitor index from `select` instead of the config, switching
+    /// monitors whenever it changes.
+    pub fn follow_monitor_selection(&mut self, select: watch::Receiver<Option<usize>>) {
+        self.monitor_select = Some(select);
+    }
+
+    /// Apply `capture.redactions` only while `enabled` is true.
+    pub fn follow_redaction_toggle(&mut self, enabled: watch::Receiver<bool>) {
+        self.redactions_enabled = Some(enabled);
+    }
+
+    /// End the capture loop once `shutdown` becomes true.
+    pub fn stop_on_shutdown(&mut self, shutdown: watch::Receiver<bool>) {
+        self.shutdown = Some(shutdown);
+    }
+
+    /// Stop capturing while nobody is subscribed, until `viewer_joined` is
+    /// notified. Only with `capture.idle_without_viewers`.
+    pub fn idle_without_viewers(&mut self, viewer_joined: Arc<Notify>) {
+        self.viewer_joined = Some(viewer_joined);
+    }
+
+    /// Compress frames with a zstd dictionary, see `Compressor::set_dictionary`.
+    pub fn use_dictionary(&mut self, dictionary: Arc<[u8]>) {
+        self.compressor.set_dictionary(dictionary);
+    }
+
+    /// Pick up reloaded configs from `reload`. See `Config::reloaded` for
+    /// which settings take effect.
+    pub fn follow_config_reloads(&mut self, reload: watch::Receiver<Arc<Config>>) {
+        self.config_reload = Some(reload);
+    }
+
+    /// Also publish unencoded frames to `raw_frames` while anyone listens,
+    /// for per-client re-encoding.
+    pub fn publish_raw_frames(&mut self, raw_frames: broadcast::Sender<Arc<RawFrame>>) {
+        self.raw_frames = Some(raw_frames);
+    }
+
+    /// Keep `resolution` set to the size of the latest frame.
+    pub fn publish_resolution(&mut self, resolution: watch::Sender<Option<(u32, u32)>>) {
+        self.resolution = Some(resolution);
+    }
+
+    fn monitor_index(&self) -> Option<usize> {
+        self.monitor_select
+            .as_ref()
+            .map_or(self.config.capture.monitor_index, |select| *select.borrow())
+    }
+
+    /// Open the frame source, retrying with backoff in case the display
+    /// isn't ready yet. Gives up after `init_retries` and leaves capture in
+    /// fallback mode, where `reinit_source` keeps retrying, or fails in
+    /// modes without a fallback.
+    async fn open_source(&mut self) -> AppResult<()> {
+        let retries = self.config.capture.init_retries;
+        let mut delay = Duration::from_millis(self.config.capture.init_retry_delay_ms);
+        self.source.select_monitor(self.monitor_index());
+
+        for attempt in 0..=retries {
+            match self.source.open() {
+                Ok(()) => {
+                    self.metrics.record_source_opened();
+                    return Ok(());
+                }
+                Err(e) if attempt < retries => {
+                    warn!(
+                        "Capture source not ready ({}), retrying in {:?} (attempt {}/{})",
+                        e,
+                        delay,
+                        attempt + 1,
+                        retries
+                    );
+                    // Waiting on the display is not a stall, keep the watchdog quiet
+                    self.metrics.record_frame_heartbeat();
+                    tokio::time::sleep(delay).await;
+                    delay = std::cmp::min(delay * 2, Duration::from_secs(10));
+                }
+                Err(e) if !self.config.capture.mode.uses_fallback() => {
+                    error!("No capture source available after {} retries: {}", retries, e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!(
+                        "No capture source available after {} retries ({}), using {:?} fallback",
+                        retries, e, self.config.capture.fallback
+                    );
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub async fn start_capture_loop(
         &mut self,
-        frame_tx: broadcast::Sender<Vec<u8>>,
+        frame_tx: fanout::Sender<FrameMessage>,
     ) -> AppResult<()> {
-        let mut interval = tokio::time::interval(std::time::Duration::from_millis(
-            self.config.frame_interval_ms(),
-        ));
+        if self.replay.is_none() {
+            // Leave the display alone until the first viewer, e.g. so macOS
+            // doesn't ask for screen recording permission on an idle server
+            if self.nobody_watching(&frame_tx) {
+                self.idle(&frame_tx).await;
+                if self.shutdown.as_ref().is_some_and(|s| *s.borrow()) {
+                    return Ok(());
+                }
+            }
+            self.open_source().await?;
+        } else {
+            // The recording stands in for the source
+            self.metrics.record_source_opened();
+        }
 
-        let mut frame_count = 0u64;
-        let mut error_count = 0u64;
+        let mut interval = capture_interval(&self.config);
+        let mut stats = LoopStats::new(self.config.queue_high_water_mark());
+        let mut encoding = EncodeQueue::new();
+        let workers = self.config.compression.workers;
 
-        debug!("Starting capture loop at {} FPS", self.config.capture.fps);
+        debug!(
+            "Starting capture loop at {} FPS with {} encode workers",
+            self.config.capture.fps, workers
+        );
 
         loop {
-            interval.tick().await;
+            // Publish encodes as they finish rather than on the next tick
+            let encoded = tokio::select! {
+                _ = Self::wait_for_next_frame(&self.replay, &mut interval, &self.metrics) => None,
+                Some(encoded) = encoding.next() => Some(encoded),
+            };
+            if let Some(encoded) = encoded {
+                self.finish_frame(encoded, &frame_tx, &mut stats).await;
+                continue;
+            }
 
-            match self.capture_frame().await {
-                Ok(frame_data) => {
-                    frame_count += 1;
-                    self.metrics.increment_frames_captured();
-
-                    // Send to all connected clients
-                    let receiver_count = frame_tx.receiver_count();
-                    if receiver_count > 0 {
-                        match frame_tx.send(frame_data) {
-                            Ok(_) => {
-                                self.metrics.increment_frames_sent();
-                                debug!("Frame {} sent to {} clients", frame_count, receiver_count);
-                            }
-                            Err(_) => {
-                                warn!("No active receivers for frame {}", frame_count);
-                            }
-                        }
+            if self.shutdown.as_ref().is_some_and(|s| *s.borrow()) {
+                info!("Shutting down, stopping capture");
+                return Ok(());
+            }
+
+            if let Some(reload) = self.config_reload.as_mut() {
+                if reload.has_changed().unwrap_or(false) {
+                    let config = reload.borrow_and_update().clone();
+                    let fps_changed = config.capture.fps != self.config.capture.fps;
+                    self.compressor
+                        .reconfigure(config.compression.clone(), config.capture.quality, config.capture.fps);
+                    self.config = config;
+                    if fps_changed {
+                        info!("Capture rate changed to {} FPS", self.config.capture.fps);
+                        interval = capture_interval(&self.config);
                     }
                 }
-                Err(e) => {
-                    error_count += 1;
-                    self.metrics.increment_capture_errors();
+            }
+
+            if let Some(select) = self.monitor_select.as_mut() {
+                if select.has_changed().unwrap_or(false) {
+                    let index = *select.borrow_and_update();
+                    info!("Switching capture to monitor {:?}", index);
+                    self.source.select_monitor(index);
+                    match self.source.open() {
+                        Ok(()) => self.metrics.record_source_opened(),
+                        Err(e) => warn!("Monitor {:?} unavailable: {}", index, e),
+                    }
+                }
+            }
+
+            if encoding.is_empty() && self.nobody_watching(&frame_tx) {
+                self.idle(&frame_tx).await;
+                // Pick up right away, without the ticks missed while idle
+                // counting as overruns
+                interval.reset_immediately();
+                continue;
+            }
+
+            if self.config.capture.throttle_on_backpressure {
+                let saturated = self.downstream_saturated(&frame_tx, stats.high_water_mark);
+                if saturated != stats.throttled {
+                    debug!("Capture throttling {}", if saturated { "started" } else { "stopped" });
+                    stats.throttled = saturated;
+                }
+                if saturated {
+                    self.metrics.increment_captures_skipped();
+                    // Idle on purpose, not stalled
+                    self.metrics.record_frame_heartbeat();
+                    continue;
+                }
+            }
+
+            match self.capture_frame().await {
+                Ok(CapturedFrame::Pending(pending)) => {
+                    stats.record_capture();
+                    encoding.push_back(tokio::task::spawn_blocking(move || pending.encode()));
+                }
+                Ok(CapturedFrame::Encoded(frame_data)) => {
+                    stats.record_capture();
+                    self.publish_frame(frame_data, &frame_tx, &mut stats).await;
+                }
+                Err(e) => self.record_error(e, &mut stats).await,
+            }
 
-                    if error_count % 10 == 0 {
-                        error!("Capture error #{}: {}", error_count, e);
+            // Wait for the oldest encode once every worker is busy
+            while encoding.len() >= workers {
+                if let Some(encoded) = encoding.next().await {
+                    self.finish_frame(encoded, &frame_tx, &mut stats).await;
+                }
+            }
+        }
+    }
+
+    /// Whether a frame captured now would go unused: nobody is subscribed,
+    /// or the slowest client is already at the high-water mark. Client
+    /// profiles pace themselves, so any profile encoder counts as demand.
+    fn downstream_saturated(&self, frame_tx: &fanout::Sender<FrameMessage>, high_water_mark: usize) -> bool {
+        if self.raw_frames.as_ref().is_some_and(|tx| tx.receiver_count() > 0) {
+            return false;
+        }
+        frame_tx.receiver_count() == 0 || frame_tx.len() >= high_water_mark
+    }
+
+    /// Whether capture should idle: `idle_without_viewers` is on, no client
+    /// or profile encoder is subscribed, and nothing else needs the frames.
+    fn nobody_watching(&self, frame_tx: &fanout::Sender<FrameMessage>) -> bool {
+        self.viewer_joined.is_some()
+            && self.config.capture.idle_without_viewers
+            && self.recorder.is_none()
+            && self.dumper.is_none()
+            && !(self.motion.is_some() && self.config.motion.webhook_url.is_some())
+            && frame_tx.receiver_count() == 0
+            && self.raw_frames.as_ref().is_none_or(|tx| tx.receiver_count() == 0)
+    }
+
+    /// Wait for a viewer to connect, or for shutdown. Config reloads and
+    /// monitor switches are picked up once capture resumes.
+    async fn idle(&mut self, frame_tx: &fanout::Sender<FrameMessage>) {
+        let Some(viewer_joined) = self.viewer_joined.clone() else {
+            return;
+        };
+        info!("No viewers, capture idle");
+        self.metrics.start_capture_idle();
+
+        while self.nobody_watching(frame_tx) {
+            let shutdown = async {
+                if let Some(shutdown) = self.shutdown.as_mut() {
+                    if shutdown.changed().await.is_ok() {
+                        return;
                     }
+                }
+                std::future::pending().await
+            };
+            tokio::select! {
+                _ = viewer_joined.notified() => {}
+                _ = shutdown => break,
+            }
+        }
 
-                    // Exponential backoff on repeated errors
-                    if error_count > 5 {
-                        let backoff = std::cmp::min(1000, error_count * 100);
-                        tokio::time::sleep(std::time::Duration::from_millis(backoff)).await;
+        self.metrics.end_capture_idle();
+        // Give the watchdog a full timeout for the first frame
+        self.metrics.record_frame_heartbeat();
+        info!("Viewer connected, capture resumed");
+    }
+
+    async fn wait_for_next_frame(replay: &Option<FrameReplayer>, interval: &mut Interval, metrics: &Metrics) {
+        match replay {
+            // Paced by the recording's timestamps instead
+            Some(replay) => tokio::time::sleep(replay.delay()).await,
+            None => {
+                let deadline = interval.tick().await;
+                // A tick this late means the slots since went by unused,
+                // except with Burst, where they still fire one by one
+                if interval.missed_tick_behavior() != MissedTickBehavior::Burst {
+                    let missed = deadline.elapsed().as_nanos() / interval.period().as_nanos();
+                    if missed > 0 {
+                        metrics.add_capture_ticks_missed(missed as u64);
                     }
                 }
             }
         }
     }
 
-    async fn capture_frame(&mut self) -> AppResult<Vec<u8>> {
+    /// Record and publish a frame that came back from an encode worker.
+    async fn finish_frame(
+        &mut self,
+        encoded: Result<AppResult<EncodedFrame>, JoinError>,
+        frame_tx: &fanout::Sender<FrameMessage>,
+        stats: &mut LoopStats,
+    ) {
+        let encoded = match encoded {
+            Ok(Ok(encoded)) => encoded,
+            Ok(Err(e)) => return self.record_error(e, stats).await,
+            Err(e) => {
+                let e = AppError::CompressionError(format!("Encode worker failed: {}", e));
+                return self.record_error(e, stats).await;
+            }
+        };
+
+        self.metrics.observe_compression_duration(encoded.encode_duration);
+        // Workers encode side by side, so each has that many frame intervals
+        let budget = self.config.frame_interval() * self.config.compression.workers as u32;
+        self.compressor.adapt_level(encoded.encode_duration, budget);
+        self.metrics
+            .record_compression_ratio(encoded.raw_len, encoded.message.len());
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.write(&encoded.message) {
+                warn!("Frame recording failed, disabling: {}", e);
+                self.recorder = None;
+            }
+        }
+
+        self.publish_frame(encoded.message, frame_tx, stats).await;
+    }
+
+    /// Send a finished frame message to clients. With the `block` overflow
+    /// strategy this waits until every client has room for it.
+    async fn publish_frame(
+        &mut self,
+        frame_data: FrameMessage,
+        frame_tx: &fanout::Sender<FrameMessage>,
+        stats: &mut LoopStats,
+    ) {
+        stats.frames_sent += 1;
+        self.metrics.increment_frames_captured();
+        self.metrics.record_frame_heartbeat();
+
+        if let (Some(resolution), Ok(header)) = (&self.resolution, read_frame_header(&frame_data)) {
+            let size = Some((header.width, header.height));
+            resolution.send_if_modified(|current| {
+                let changed = *current != size;
+                *current = size;
+                changed
+            });
+        }
+
+        // Cached before the send, so a client subscribing in
+        // between may get this frame twice, which is harmless.
+        // Only keyframes are cached since deltas need their base.
+        if let Some(latest) = &self.latest_frame {
+            if read_frame_header(&frame_data).is_ok_and(|h| h.is_keyframe) {
+                *latest.write().unwrap_or_else(PoisonError::into_inner) = Some(frame_data.clone());
+            }
+        }
+
+        // Send to all connected clients
+        let receiver_count = frame_tx.receiver_count();
+        if receiver_count > 0 {
+            let len = frame_data.len();
+            let send = frame_tx.send(frame_data);
+            tokio::pin!(send);
+            // Waiting on a slow client is intended, so keep the watchdog
+            // from taking it for a stall
+            let mut heartbeat = tokio::time::interval(Duration::from_secs(1));
+            let sent = loop {
+                tokio::select! {
+                    sent = &mut send => break sent,
+                    _ = heartbeat.tick() => self.metrics.record_frame_heartbeat(),
+                }
+            };
+            match sent {
+                Ok(_) => {
+                    self.metrics.increment_frames_sent();
+                    debug!(
+                        "Frame {} ({} bytes) sent to {} clients",
+                        stats.frames_sent, len, receiver_count
+                    );
+                }
+                Err(_) => {
+                    warn!("No active receivers for frame {}", stats.frames_sent);
+                }
+            }
+
+            // How far the slowest client is behind
+            let depth = frame_tx.len();
+            self.metrics.set_frame_queue_depth(depth);
+            if depth >= stats.high_water_mark && !stats.above_high_water {
+                warn!(
+                    "Frame queue depth {} reached high-water mark {} (buffer {}), clients falling behind",
+                    depth, stats.high_water_mark, self.config.buffer_size
+                );
+                stats.above_high_water = true;
+            } else if depth < stats.high_water_mark && stats.above_high_water {
+                info!("Frame queue depth back below high-water mark ({})", depth);
+                stats.above_high_water = false;
+            }
+        }
+    }
+
+    async fn record_error(&self, e: AppError, stats: &mut LoopStats) {
+        stats.error_count += 1;
+        stats.consecutive_errors = stats.consecutive_errors.saturating_add(1);
+        self.metrics.increment_capture_errors();
+
+        if stats.error_count.is_multiple_of(10) {
+            error!("Capture error #{}: {}", stats.error_count, e);
+        }
+
+        let backoff = error_backoff(
+            stats.consecutive_errors,
+            Duration::from_millis(self.config.capture.error_backoff_base_ms),
+            Duration::from_millis(self.config.capture.error_backoff_max_ms),
+        );
+        tokio::time::sleep(backoff).await;
+    }
+
+    /// Open the source again once `reinit_after_failures` captures in a row
+    /// have failed, e.g. to pick up a display that was unplugged and plugged
+    /// back in. Attempts are at least `reinit_interval_ms` apart.
+    fn reinit_source(&mut self) {
+        let threshold = self.config.capture.reinit_after_failures;
+        if threshold == 0 || self.source_failures < threshold {
+            return;
+        }
+        let interval = Duration::from_millis(self.config.capture.reinit_interval_ms);
+        if self.last_reinit.is_some_and(|at| at.elapsed() < interval) {
+            return;
+        }
+        self.last_reinit = Some(std::time::Instant::now());
+
+        info!(
+            "Re-initializing capture source after {} consecutive failures",
+            self.source_failures
+        );
+        self.source.select_monitor(self.monitor_index());
+        match self.source.open() {
+            Ok(()) => self.metrics.record_source_opened(),
+            Err(e) => warn!("Capture source re-initialization failed: {}", e),
+        }
+    }
+
+    async fn capture_frame(&mut self) -> AppResult<CapturedFrame> {
+        if let Some(replay) = self.replay.as_mut() {
+            let message = replay.next_frame()?;
+            self.metrics.record_source_capture();
+            return Ok(CapturedFrame::Encoded(message.into()));
+        }
+
         let start_time = std::time::Instant::now();
 
-        // Try to capture real screen, fallback to demo if it fails
-        let (rgba_data, width, height) = match Self::get_primary_monitor().and_then(|monitor| {
-            monitor.capture_image().map_err(|e| {
-                crate::error::AppError::CompressionError(format!("Screen capture failed: {}", e))
-            })
-        }) {
-            Ok(image) => {
-                let width = image.width();
-                let height = image.height();
-                let rgba = image.into_raw();
+        // Try to capture real screen, fallback if it fails
+        let (mut rgba_data, width, height) = match self.source.capture().and_then(check_frame_size) {
+            Ok((mut rgba, width, height)) => {
+                if self.source_failures >= self.config.capture.reinit_after_failures.max(1) {
+                    info!("Capture recovered after {} failures", self.source_failures);
+                }
+                self.source_failures = 0;
+                self.metrics.record_source_capture();
+
+                // In source pixels, so before cropping and scaling
+                if self.redactions_enabled.as_ref().is_none_or(|enabled| *enabled.borrow()) {
+                    self.redact.process(&mut rgba, width, height);
+                }
+                let (rgba, width, height) = self.crop_to_region(rgba, width, height);
+                let (rgba, width, height) = self.downscale(rgba, width, height);
+                if self.config.capture.fallback == FallbackFrame::LastFrame {
+                    self.last_frame = Some((rgba.clone(), width, height));
+                }
                 (rgba, width, height)
             }
             Err(e) => {
-                // Fallback to demo patterns if screen capture fails
-                warn!("Screen capture failed: {}, using demo mode", e);
-                let width = 1280;
-                let height = 720;
-                (self.generate_demo_frame(width, height), width, height)
+                self.source_failures = self.source_failures.saturating_add(1);
+                self.reinit_source();
+
+                if !self.config.capture.mode.uses_fallback() {
+                    return Err(e);
+                }
+                warn!(
+                    "Capture failed: {}, using {:?} fallback",
+                    e, self.config.capture.fallback
+                );
+                self.fallback_frame()
             }
         };
 
+        // Before anything else sees the frame, so e.g. a blurred region
+        // is blurred everywhere, including profile streams and dumps
+        for processor in &self.processors {
+            processor.process(&mut rgba_data, width, height);
+        }
+
         let capture_duration = start_time.elapsed();
-        self.metrics.record_capture_duration(capture_duration);
+        self.metrics.observe_capture_duration(capture_duration);
+
+        if let Some(dumper) = self.dumper.as_mut() {
+            if let Err(e) = dumper.write(&rgba_data, width, height) {
+                warn!("Frame dump failed, disabling: {}", e);
+                self.dumper = None;
+            } else if dumper.is_done() {
+                self.dumper = None;
+            }
+        }
 
-        // Create frame message with metadata
-        let final_data = self
-            .compressor
-            .create_frame_message(rgba_data.clone(), width, height)?;
+        if let Some(motion) = self.motion.as_mut() {
+            motion.process(&rgba_data, width, height);
+        }
+
+        if let Some(raw_frames) = self.raw_frames.as_ref().filter(|tx| tx.receiver_count() > 0) {
+            let _ = raw_frames.send(Arc::new(RawFrame {
+                rgba: rgba_data.clone(),
+                width,
+                height,
+            }));
+        }
+
+        // Everything above works on RGBA, only clients get the chosen layout
+        let mut pixels = rgba_data;
+        convert_color_format(&mut pixels, self.config.capture.color_format);
+
+        // Frame ID and delta are assigned here, in capture order
+        let pending = self.compressor.prepare_frame(pixels, width, height);
 
         self.frame_count += 1;
 
-        if self.frame_count % 30 == 0 {
+        if self.frame_count.is_multiple_of(30) {
             debug!(
-                "Frame {}: {}ms capture, {}x{}, {} bytes",
+                "Frame {}: {}ms capture, {}x{}",
                 self.frame_count,
                 capture_duration.as_millis(),
                 width,
-                height,
-                final_data.len()
+                height
             );
         }
 
-        Ok(final_data)
+        Ok(CapturedFrame::Pending(pending))
+    }
+
+    /// Crop to `capture.region`, clamped to the frame. Returns the frame
+    /// unchanged if no region is set or it lies entirely off-screen.
+    fn crop_to_region(&mut self, rgba: Vec<u8>, width: u32, height: u32) -> (Vec<u8>, u32, u32) {
+        let Some(region) = self.config.capture.region else {
+            return (rgba, width, height);
+        };
+
+        let x = region.x.min(width);
+        let y = region.y.min(height);
+        let crop_width = region.width.min(width - x);
+        let crop_height = region.height.min(height - y);
+        let off_screen = crop_width == 0 || crop_height == 0;
+
+        if (crop_width, crop_height) != (region.width, region.height) && !self.region_warned {
+            if off_screen {
+                warn!(
+                    "Capture region {:?} is outside the {}x{} monitor, capturing the full screen",
+                    region, width, height
+                );
+            } else {
+                warn!(
+                    "Capture region {:?} extends past the {}x{} monitor, clamping to {}x{}",
+                    region, width, height, crop_width, crop_height
+                );
+            }
+            self.region_warned = true;
+        }
+
+        if off_screen {
+            return (rgba, width, height);
+        }
+
+        let mut cropped = Vec::with_capacity((crop_width * crop_height * 4) as usize);
+        for row in y..y + crop_height {
+            let start = ((row * width + x) * 4) as usize;
+            cropped.extend_from_slice(&rgba[start..start + (crop_width * 4) as usize]);
+        }
+
+        (cropped, crop_width, crop_height)
+    }
+
+    /// Shrink the frame to fit `max_width`/`max_height`, keeping its aspect
+    /// ratio. Frames that already fit are returned untouched.
+    fn downscale(&self, rgba: Vec<u8>, width: u32, height: u32) -> (Vec<u8>, u32, u32) {
+        let max_width = self.config.capture.max_width.unwrap_or(width);
+        let max_height = self.config.capture.max_height.unwrap_or(height);
+        if width <= max_width && height <= max_height {
+            return (rgba, width, height);
+        }
+
+        let scale = (max_width as f64 / width as f64).min(max_height as f64 / height as f64);
+        let scaled_width = ((width as f64 * scale).round() as u32).max(1);
+        let scaled_height = ((height as f64 * scale).round() as u32).max(1);
+
+        let image = RgbaImage::from_raw(width, height, rgba)
+            .expect("capture buffer holds width * height RGBA pixels, see check_frame_size");
+        let filter = match self.config.capture.scale_filter {
+            ScaleFilter::Triangle => FilterType::Triangle,
+            ScaleFilter::Lanczos3 => FilterType::Lanczos3,
+        };
+        let scaled = image::imageops::resize(&image, scaled_width, scaled_height, filter);
+
+        (scaled.into_raw(), scaled_width, scaled_height)
+    }
+
+    fn fallback_frame(&self) -> (Vec<u8>, u32, u32) {
+        let (width, height) = self
+            .last_frame
+            .as_ref()
+            .map_or((1280, 720), |(_, w, h)| (*w, *h));
+
+        let rgba = match self.config.capture.fallback {
+            FallbackFrame::Demo => testcard::demo_frame(width, height, self.frame_count as f32 * 0.1),
+            FallbackFrame::NoSignal => testcard::no_signal_frame(width, height),
+            FallbackFrame::Black => testcard::black_frame(width, height),
+            FallbackFrame::LastFrame => match &self.last_frame {
+                Some((rgba, _, _)) => rgba.clone(),
+                // Nothing captured yet, so there is no last frame to hold
+                None => testcard::no_signal_frame(width, height),
+            },
+        };
+
+        (rgba, width, height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::FrameDecoder;
+    use crate::config::{CaptureRegion, OverflowStrategy};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn capture(configure: impl FnOnce(&mut Config)) -> ScreenCapture {
+        let mut config = Config::default();
+        config.capture.mode = CaptureMode::Demo;
+        configure(&mut config);
+        ScreenCapture::new(Arc::new(config), Arc::new(Metrics::new()), Arc::new(AtomicU64::new(0))).unwrap()
+    }
+
+    /// A frame whose every pixel holds its own coordinates
+    fn numbered(width: u32, height: u32) -> Vec<u8> {
+        (0..height).flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, 0, 255])).collect()
+    }
+
+    fn pixel(rgba: &[u8], width: u32, x: u32, y: u32) -> &[u8] {
+        let start = ((y * width + x) * 4) as usize;
+        &rgba[start..start + 4]
+    }
+
+    #[test]
+    fn crop_keeps_pixels_at_region_offset() {
+        let region = CaptureRegion { x: 5, y: 3, width: 4, height: 2 };
+        let mut capture = capture(|c| c.capture.region = Some(region));
+        let source = numbered(16, 8);
+
+        let (cropped, width, height) = capture.crop_to_region(source.clone(), 16, 8);
+        assert_eq!((width, height), (4, 2));
+        assert_eq!(cropped.len(), 4 * 2 * 4);
+        assert_eq!(pixel(&cropped, width, 0, 0), pixel(&source, 16, 5, 3));
+        assert_eq!(pixel(&cropped, width, 3, 1), pixel(&source, 16, 8, 4));
+    }
+
+    #[test]
+    fn crop_clamps_region_past_the_edge() {
+        let region = CaptureRegion { x: 12, y: 6, width: 10, height: 10 };
+        let mut capture = capture(|c| c.capture.region = Some(region));
+
+        let (cropped, width, height) = capture.crop_to_region(numbered(16, 8), 16, 8);
+        assert_eq!((width, height), (4, 2));
+        assert_eq!(pixel(&cropped, width, 0, 0), [12, 6, 0, 255]);
+    }
+
+    #[test]
+    fn downscale_fits_width_keeping_aspect_ratio() {
+        let capture = capture(|c| {
+            c.capture.max_width = Some(1280);
+            c.capture.scale_filter = ScaleFilter::Triangle;
+        });
+        let (scaled, width, height) = capture.downscale(vec![128; 3840 * 2160 * 4], 3840, 2160);
+        assert_eq!((width, height), (1280, 720));
+        assert_eq!(scaled.len(), 1280 * 720 * 4);
+    }
+
+    #[test]
+    fn downscale_skips_frames_that_fit() {
+        let capture = capture(|c| (c.capture.max_width, c.capture.max_height) = (Some(1280), Some(720)));
+        let frame = numbered(640, 360);
+        assert_eq!(capture.downscale(frame.clone(), 640, 360), (frame, 640, 360));
+    }
+
+    /// Run the capture loop, returning a subscriber to its frames
+    fn run(mut capture: ScreenCapture) -> (fanout::Receiver<FrameMessage>, JoinHandle<AppResult<()>>) {
+        let frame_tx = fanout::channel(64, OverflowStrategy::Block);
+        let frame_rx = frame_tx.subscribe();
+        let task = tokio::spawn(async move { capture.start_capture_loop(frame_tx).await });
+        (frame_rx, task)
+    }
+
+    async fn next_frame(frame_rx: &mut fanout::Receiver<FrameMessage>) -> FrameMessage {
+        tokio::time::timeout(Duration::from_secs(5), frame_rx.recv())
+            .await
+            .expect("timed out waiting for a frame")
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn parallel_encodes_keep_frame_order() {
+        let capture = capture(|c| {
+            c.capture.fps = 240;
+            (c.capture.width, c.capture.height) = (Some(320), Some(180));
+            c.compression.workers = 4;
+            c.compression.max_hold_frames = 0;
+        });
+        let (mut frame_rx, task) = run(capture);
+
+        let mut ids = Vec::new();
+        for _ in 0..40 {
+            ids.push(read_frame_header(&next_frame(&mut frame_rx).await).unwrap().frame_id);
+        }
+        task.abort();
+
+        assert_eq!(ids, (0..40).collect::<Vec<u64>>());
+    }
+
+    /// A source of solid frames, each one shade lighter than the last
+    struct MockSource {
+        width: u32,
+        height: u32,
+        captures: Arc<AtomicUsize>,
+    }
+
+    impl MockSource {
+        fn new(width: u32, height: u32) -> (Box<Self>, Arc<AtomicUsize>) {
+            let captures = Arc::new(AtomicUsize::new(0));
+            let source = Self {
+                width,
+                height,
+                captures: captures.clone(),
+            };
+            (Box::new(source), captures)
+        }
+    }
+
+    impl FrameSource for MockSource {
+        fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+            let shade = self.captures.fetch_add(1, Ordering::Relaxed) as u8;
+            Ok((vec![shade; (self.width * self.height * 4) as usize], self.width, self.height))
+        }
     }
 
-    fn generate_demo_frame(&self, width: u32, height: u32) -> Vec<u8> {
-        let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
-        let time = self.frame_count as f32 * 0.1;
+    #[tokio::test]
+    async fn mock_source_frames_reach_the_channel() {
+        let mut capture = capture(|c| c.capture.fps = 120);
+        let (source, captures) = MockSource::new(32, 16);
+        capture.source = source;
+        let metrics = capture.metrics.clone();
+        let (mut frame_rx, task) = run(capture);
+
+        let mut decoder = FrameDecoder::default();
+        for shade in 0..3 {
+            let (header, rgba) = decoder.decode(&next_frame(&mut frame_rx).await).unwrap().unwrap();
+            assert_eq!((header.width, header.height), (32, 16));
+            assert_eq!(rgba, vec![shade; 32 * 16 * 4]);
+        }
+        task.abort();
+        assert!(captures.load(Ordering::Relaxed) >= 3);
+        assert!(metrics.is_source_opened());
+    }
 
-        for y in 0..height {
-            for x in 0..width {
-                let fx = x as f32 / width as f32;
-                let fy = y as f32 / height as f32;
+    /// A source with no display behind it
+    struct FailingSource;
 
-                // Create animated retro patterns
-                let wave1 = ((fx * 10.0 + time).sin() * 0.5 + 0.5) * 255.0;
-                let wave2 = ((fy * 8.0 + time * 1.3).cos() * 0.5 + 0.5) * 255.0;
-                let wave3 = (((fx + fy) * 6.0 + time * 0.8).sin() * 0.5 + 0.5) * 255.0;
+    impl FrameSource for FailingSource {
+        fn open(&mut self) -> AppResult<()> {
+            Err(AppError::NoMonitorAvailable)
+        }
 
-                // Retro green/cyan color scheme
-                let r = (wave3 * 0.1) as u8;
-                let g = ((wave1 + wave3) * 0.4) as u8;
-                let b = (wave2 * 0.3) as u8;
-                let a = 255u8;
+        fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+            Err(AppError::NoMonitorAvailable)
+        }
+    }
+
+    #[tokio::test]
+    async fn demo_mode_streams_the_demo_pattern() {
+        let capture = capture(|c| {
+            c.capture.mode = CaptureMode::Demo;
+            (c.capture.width, c.capture.height) = (Some(48), Some(32));
+        });
+        let (mut frame_rx, task) = run(capture);
+
+        let (header, rgba) = FrameDecoder::default().decode(&next_frame(&mut frame_rx).await).unwrap().unwrap();
+        task.abort();
+        assert_eq!((header.width, header.height), (48, 32));
+        assert_eq!(rgba, testcard::demo_frame(48, 32, 0.0));
+    }
+
+    #[tokio::test]
+    async fn screen_mode_fails_without_a_display() {
+        let mut capture = capture(|c| {
+            c.capture.mode = CaptureMode::Screen;
+            c.capture.init_retries = 0;
+        });
+        capture.source = Box::new(FailingSource);
+        let (_frame_rx, task) = run(capture);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert!(matches!(result, Err(AppError::NoMonitorAvailable)));
+    }
+
+    #[tokio::test]
+    async fn auto_mode_falls_back_without_a_display() {
+        let mut capture = capture(|c| {
+            c.capture.mode = CaptureMode::Auto;
+            c.capture.init_retries = 0;
+            c.capture.fallback = FallbackFrame::Black;
+        });
+        capture.source = Box::new(FailingSource);
+        let metrics = capture.metrics.clone();
+        let (mut frame_rx, task) = run(capture);
+
+        let (header, rgba) = FrameDecoder::default().decode(&next_frame(&mut frame_rx).await).unwrap().unwrap();
+        task.abort();
+        assert_eq!((header.width, header.height), (1280, 720));
+        assert_eq!(rgba, testcard::black_frame(1280, 720));
+        // Fallback frames are not errors
+        assert_eq!(metrics.get_summary().capture_errors, 0);
+        // but not ready either
+        assert!(!metrics.is_source_opened());
+    }
 
-                rgba_data.extend_from_slice(&[r, g, b, a]);
+    /// A display that drops out for `failures` captures and only comes back
+    /// once it is opened again, like a monitor that was replugged
+    struct ReplugSource {
+        failures: usize,
+        opens: Arc<AtomicUsize>,
+    }
+
+    impl FrameSource for ReplugSource {
+        fn open(&mut self) -> AppResult<()> {
+            self.opens.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+            if self.failures > 0 || self.opens.load(Ordering::Relaxed) < 2 {
+                self.failures = self.failures.saturating_sub(1);
+                return Err(AppError::NoMonitorAvailable);
+            }
+            Ok((vec![200; 32 * 16 * 4], 32, 16))
+        }
+    }
+
+    #[tokio::test]
+    async fn source_is_reopened_after_repeated_failures() {
+        let mut capture = capture(|c| {
+            c.capture.fps = 120;
+            c.capture.mode = CaptureMode::Auto;
+            c.capture.fallback = FallbackFrame::Black;
+            c.capture.reinit_after_failures = 3;
+            c.capture.reinit_interval_ms = 0;
+        });
+        let opens = Arc::new(AtomicUsize::new(0));
+        capture.source = Box::new(ReplugSource {
+            failures: 5,
+            opens: opens.clone(),
+        });
+        let (mut frame_rx, task) = run(capture);
+
+        let mut decoder = FrameDecoder::default();
+        let mut fallback_frames = 0;
+        loop {
+            let message = next_frame(&mut frame_rx).await;
+            // Repeats of the black fallback frame arrive as hold markers
+            if let Some((header, rgba)) = decoder.decode(&message).unwrap() {
+                if header.width == 32 {
+                    assert_eq!(rgba, vec![200; 32 * 16 * 4]);
+                    break;
+                }
+            }
+            fallback_frames += 1;
+            assert!(fallback_frames < 20, "capture never recovered");
+        }
+        task.abort();
+        assert!(fallback_frames >= 5);
+        assert!(opens.load(Ordering::Relaxed) >= 2);
+    }
+
+    /// A window that is closed after `frames` captures
+    struct ClosingWindow {
+        frames: usize,
+    }
+
+    impl FrameSource for ClosingWindow {
+        fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+            if self.frames == 0 {
+                return Err(AppError::CaptureError("Cannot capture the window, was it closed?".to_string()));
+            }
+            self.frames -= 1;
+            Ok((vec![50; 16 * 8 * 4], 16, 8))
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_window_keeps_showing_its_last_frame() {
+        let mut capture = capture(|c| {
+            c.capture.fps = 120;
+            c.capture.mode = CaptureMode::Window;
+            c.capture.fallback = FallbackFrame::LastFrame;
+        });
+        capture.source = Box::new(ClosingWindow { frames: 2 });
+        let metrics = capture.metrics.clone();
+        let (mut frame_rx, task) = run(capture);
+
+        let mut decoder = FrameDecoder::default();
+        for _ in 0..8 {
+            // Repeats of the last frame arrive as hold markers
+            if let Some((header, rgba)) = decoder.decode(&next_frame(&mut frame_rx).await).unwrap() {
+                assert_eq!((header.width, header.height), (16, 8));
+                assert_eq!(rgba, vec![50; 16 * 8 * 4]);
+            }
+        }
+        assert!(!task.is_finished());
+        task.abort();
+        assert_eq!(metrics.get_summary().capture_errors, 0);
+    }
+
+    #[tokio::test]
+    async fn capture_stops_without_viewers() {
+        let mut capture = capture(|c| {
+            c.capture.fps = 120;
+            c.capture.idle_without_viewers = true;
+        });
+        let (source, captures) = MockSource::new(16, 8);
+        capture.source = source;
+        let viewer_joined = Arc::new(Notify::new());
+        capture.idle_without_viewers(viewer_joined.clone());
+        let metrics = capture.metrics.clone();
+
+        let frame_tx = fanout::channel(64, OverflowStrategy::Block);
+        let mut frame_rx = frame_tx.subscribe();
+        let task = tokio::spawn({
+            let frame_tx = frame_tx.clone();
+            async move { capture.start_capture_loop(frame_tx).await }
+        });
+        next_frame(&mut frame_rx).await;
+
+        drop(frame_rx);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while !metrics.is_capture_idle() {
+            assert!(tokio::time::Instant::now() < deadline, "capture never went idle");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (captured, frames) = (captures.load(Ordering::Relaxed), metrics.get_frames_captured());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(captures.load(Ordering::Relaxed), captured);
+        assert_eq!(metrics.get_frames_captured(), frames);
+
+        let mut frame_rx = frame_tx.subscribe();
+        viewer_joined.notify_one();
+        next_frame(&mut frame_rx).await;
+        assert!(!metrics.is_capture_idle());
+        task.abort();
+    }
+
+    /// A source whose first capture takes `stall`, recording when each
+    /// capture started
+    struct StallingSource {
+        stall: Duration,
+        started: Arc<std::sync::Mutex<Vec<std::time::Instant>>>,
+    }
+
+    impl FrameSource for StallingSource {
+        fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+            let mut started = self.started.lock().unwrap();
+            started.push(std::time::Instant::now());
+            if started.len() == 1 {
+                std::thread::sleep(self.stall);
             }
+            Ok((vec![started.len() as u8; 16 * 8 * 4], 16, 8))
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_capture_does_not_cause_a_burst() {
+        let mut capture = capture(|c| {
+            c.capture.fps = 100;
+            c.capture.overrun = OverrunBehavior::Skip;
+        });
+        let started = Arc::new(std::sync::Mutex::new(Vec::new()));
+        capture.source = Box::new(StallingSource {
+            stall: Duration::from_millis(300),
+            started: started.clone(),
+        });
+        let metrics = capture.metrics.clone();
+        let (mut frame_rx, task) = run(capture);
+
+        for _ in 0..8 {
+            next_frame(&mut frame_rx).await;
+        }
+        task.abort();
+
+        // The 30 slots missed during the stall are skipped, so the next
+        // captures keep to the 10ms cadence instead of catching up at once.
+        // The first one may still fall just before the next slot.
+        let started = started.lock().unwrap();
+        let after_stall = started[7] - started[1];
+        assert!(after_stall >= Duration::from_millis(45), "6 captures in {:?}", after_stall);
+        assert!(metrics.get_summary().capture_ticks_missed >= 25);
+    }
+
+    /// Counts how often the display is opened and captured
+    #[derive(Default)]
+    struct CountingSource {
+        opens: Arc<AtomicUsize>,
+        captures: Arc<AtomicUsize>,
+    }
+
+    impl FrameSource for CountingSource {
+        fn open(&mut self) -> AppResult<()> {
+            self.opens.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+            self.captures.fetch_add(1, Ordering::Relaxed);
+            Ok((vec![0; 16 * 8 * 4], 16, 8))
         }
+    }
+
+    #[tokio::test]
+    async fn display_is_left_alone_until_the_first_viewer() {
+        let mut capture = capture(|c| {
+            c.capture.fps = 120;
+            c.capture.idle_without_viewers = true;
+        });
+        let source = CountingSource::default();
+        let (opens, captures) = (source.opens.clone(), source.captures.clone());
+        capture.source = Box::new(source);
+        let viewer_joined = Arc::new(Notify::new());
+        capture.idle_without_viewers(viewer_joined.clone());
+        let metrics = capture.metrics.clone();
+
+        let frame_tx = fanout::channel(64, OverflowStrategy::Block);
+        let task = tokio::spawn({
+            let frame_tx = frame_tx.clone();
+            async move { capture.start_capture_loop(frame_tx).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(opens.load(Ordering::Relaxed), 0);
+        assert_eq!(captures.load(Ordering::Relaxed), 0);
+        assert!(metrics.is_capture_idle());
+
+        let mut frame_rx = frame_tx.subscribe();
+        viewer_joined.notify_one();
+        next_frame(&mut frame_rx).await;
+        task.abort();
+        assert_eq!(opens.load(Ordering::Relaxed), 1);
+        assert!(captures.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[test]
+    fn error_backoff_doubles_up_to_the_max() {
+        let base = Duration::from_millis(10);
+        let max = Duration::from_secs(1);
+        for (failures, nominal) in [(1, 10), (2, 20), (3, 40), (4, 80), (7, 640), (8, 1000), (100, 1000)] {
+            let nominal = Duration::from_millis(nominal);
+            for _ in 0..20 {
+                let delay = error_backoff(failures, base, max);
+                // Jittered down by up to half
+                assert!(delay <= nominal && delay >= nominal / 2, "{} failures: {:?}", failures, delay);
+            }
+        }
+    }
+
+    /// Plays back `script` (true for a successful capture), then succeeds,
+    /// recording when each capture happened
+    struct ScriptedSource {
+        script: std::collections::VecDeque<bool>,
+        started: Arc<std::sync::Mutex<Vec<std::time::Instant>>>,
+    }
+
+    impl FrameSource for ScriptedSource {
+        fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+            self.started.lock().unwrap().push(std::time::Instant::now());
+            match self.script.pop_front().unwrap_or(true) {
+                true => Ok((vec![0; 16 * 8 * 4], 16, 8)),
+                false => Err(AppError::CaptureError("Display went away".to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn error_backoff_grows_and_resets_after_a_capture() {
+        let mut capture = capture(|c| {
+            c.capture.fps = 100;
+            c.capture.mode = CaptureMode::Screen;
+            c.capture.error_backoff_base_ms = 40;
+            c.capture.error_backoff_max_ms = 1000;
+        });
+        let started = Arc::new(std::sync::Mutex::new(Vec::new()));
+        capture.source = Box::new(ScriptedSource {
+            script: [false, false, false, false, true, false].into(),
+            started: started.clone(),
+        });
+        let metrics = capture.metrics.clone();
+        let (mut frame_rx, task) = run(capture);
+
+        next_frame(&mut frame_rx).await;
+        next_frame(&mut frame_rx).await;
+        task.abort();
+        assert_eq!(metrics.get_summary().capture_errors, 5);
+
+        let started = started.lock().unwrap();
+        let after = |capture: usize| started[capture + 1] - started[capture];
+        // Four failures in a row: at least 20, 40, 80 and 160ms
+        for (capture, least) in [(0, 20), (1, 40), (2, 80), (3, 160)] {
+            assert!(after(capture) >= Duration::from_millis(least), "{:?} after capture {}", after(capture), capture);
+        }
+    }
+
+    #[tokio::test]
+    async fn error_count_starts_over_after_a_capture() {
+        let capture = capture(|c| c.capture.error_backoff_base_ms = 1);
+        let mut stats = LoopStats::new(0);
+        let failure = || AppError::CaptureError("Display went away".to_string());
+
+        for failures in 1..=4 {
+            capture.record_error(failure(), &mut stats).await;
+            assert_eq!(stats.consecutive_errors, failures);
+        }
+        stats.record_capture();
+        capture.record_error(failure(), &mut stats).await;
+        // Backs off as after a first failure again
+        assert_eq!(stats.consecutive_errors, 1);
+        assert_eq!(stats.error_count, 5);
+    }
+
+    /// A linear-light ramp from black to 8× SDR white, as an HDR display
+    /// would hand it over
+    fn hdr_ramp() -> DynamicImage {
+        let ramp = image::Rgb32FImage::from_fn(256, 1, |x, _| image::Rgb([x as f32 / 32.0; 3]));
+        DynamicImage::ImageRgb32F(ramp)
+    }
+
+    fn clipped(rgba: &RgbaImage) -> usize {
+        rgba.pixels().filter(|p| p.0[0] == 255).count()
+    }
+
+    #[test]
+    fn tone_mapping_reduces_clipping() {
+        // Everything past SDR white
+        let untouched = clipped(&tonemap_to_srgb(hdr_ramp(), HdrTonemap::Off));
+        assert!(untouched > 200);
+
+        for operator in [HdrTonemap::Reinhard, HdrTonemap::Aces] {
+            let mapped = tonemap_to_srgb(hdr_ramp(), operator);
+            // Only the top of the range, where the curve flattens, rounds to white
+            assert!(clipped(&mapped) < untouched / 4, "{:?} clips {} pixels", operator, clipped(&mapped));
+            // Highlights stay distinguishable and in order
+            let levels: Vec<u8> = mapped.pixels().map(|p| p.0[0]).collect();
+            assert!(levels.windows(2).all(|w| w[0] <= w[1]), "{:?}", operator);
+            assert!(levels[128] < levels[192] && levels[192] < levels[255], "{:?}", operator);
+        }
+    }
+
+    #[test]
+    fn sdr_images_are_not_tone_mapped() {
+        let sdr = RgbaImage::from_fn(16, 16, |x, y| image::Rgba([x as u8 * 16, y as u8 * 16, 255, 255]));
+        let mapped = tonemap_to_srgb(DynamicImage::ImageRgba8(sdr.clone()), HdrTonemap::Reinhard);
+        assert_eq!(mapped, sdr);
+    }
+
+    #[test]
+    fn check_frame_size_rejects_malformed_frames() {
+        assert!(check_frame_size((vec![0; 4 * 3 * 4], 4, 3)).is_ok());
+        for (rgba, width, height) in [
+            // Short by a pixel, and by a row
+            (vec![0; 4 * 3 * 4 - 4], 4, 3),
+            (vec![0; 4 * 2 * 4], 4, 3),
+            // Too long
+            (vec![0; 4 * 3 * 4 + 1], 4, 3),
+            // Empty, with and without dimensions
+            (Vec::new(), 4, 3),
+            (Vec::new(), 0, 0),
+            (vec![0; 16], 0, 4),
+        ] {
+            let len = rgba.len();
+            let result = check_frame_size((rgba, width, height));
+            assert!(
+                matches!(result, Err(AppError::CaptureError(_))),
+                "{}x{} frame of {} bytes",
+                width,
+                height,
+                len
+            );
+        }
+    }
+
+    /// A backend handing over buffers too short for their dimensions
+    struct ShortFrames;
+
+    impl FrameSource for ShortFrames {
+        fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+            Ok((vec![0; 8], 32, 16))
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_fail_the_capture_instead_of_panicking() {
+        let mut capture = capture(|c| {
+            c.capture.fps = 120;
+            c.capture.mode = CaptureMode::Auto;
+            c.capture.init_retries = 0;
+            c.capture.fallback = FallbackFrame::Black;
+        });
+        capture.source = Box::new(ShortFrames);
+        let (mut frame_rx, task) = run(capture);
 
-        rgba_data
+        let (header, rgba) = FrameDecoder::default().decode(&next_frame(&mut frame_rx).await).unwrap().unwrap();
+        assert_eq!((header.width, header.height), (1280, 720));
+        assert_eq!(rgba, testcard::black_frame(1280, 720));
+        next_frame(&mut frame_rx).await;
+        assert!(!task.is_finished());
+        task.abort();
     }
 }
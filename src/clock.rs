@@ -0,0 +1,60 @@
+//! The clock frame timestamps are read from. It starts at the wall-clock
+//! time and then only advances with monotonic time, so timestamps never go
+//! backwards when NTP or a user adjusts the system clock.
+
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Wall-clock time in ms since the Unix epoch, and the `Instant` it was
+/// read at. Read on first use.
+static BASE: OnceLock<(u64, Instant)> = OnceLock::new();
+
+fn base() -> &'static (u64, Instant) {
+    BASE.get_or_init(|| {
+        (ms_since_epoch(SystemTime::now()), Instant::now())
+    })
+}
+
+/// `time` in ms since the Unix epoch, or 0 if it's before then, e.g. on a
+/// device whose clock was never set
+fn ms_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Wall-clock time the clock started at, in ms since the Unix epoch
+pub fn base_ms() -> u64 {
+    base().0
+}
+
+/// Current time in ms since the Unix epoch: `base_ms` plus the monotonic
+/// time since. Drifts from the system clock by however much that has been
+/// adjusted since.
+pub fn now_ms() -> u64 {
+    let (wall, start) = base();
+    wall + start.elapsed().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn now_never_goes_backwards() {
+        let mut last = now_ms();
+        assert!(last >= base_ms());
+        for _ in 0..10_000 {
+            let now = now_ms();
+            assert!(now >= last);
+            last = now;
+        }
+    }
+
+    #[test]
+    fn clock_before_the_epoch_reads_as_zero() {
+        let before = UNIX_EPOCH - std::time::Duration::from_secs(60);
+        assert_eq!(ms_since_epoch(before), 0);
+        assert_eq!(ms_since_epoch(UNIX_EPOCH + std::time::Duration::from_millis(1500)), 1500);
+    }
+}
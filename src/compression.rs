@@ -1,70 +1,1367 @@
-use crate::{config::CompressionConfig, error::{AppError, AppResult}};
-use serde::{Serialize, Deserialize};
+use crate::{
+    clock,
+    config::{ColorFormat, CompressionConfig, CompressionFormat},
+    error::{AppError, AppResult},
+    pool::BufferPool,
+};
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+    },
+    ImageEncoder,
+};
+use std::hash::{DefaultHasher, Hasher};
+use std::sync::{
+    atomic::{AtomicI32, AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+use tracing::debug;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// First bytes of every frame message, so clients can reject anything that
+/// isn't a frame from this server.
+pub const FRAME_MAGIC: [u8; 2] = *b"RS";
+
+/// Wire format version, bumped whenever the message layout or header
+/// fields change incompatibly.
+///
+/// Version 1: `[magic "RS"][version u8][u32 LE header len][JSON FrameHeader][payload]`
+///
+/// Version 2: `[magic "RS"][version u8][FrameHeader, 35 bytes][payload]`
+///
+/// Version 3: as version 2 with a payload checksum appended to the header,
+/// now `FRAME_HEADER_LEN` bytes. See `FrameHeader::to_bytes` for the layout.
+///
+/// Version 4: as version 3 with the source frame rate appended to the
+/// header and a flag for hold markers.
+///
+/// Version 5: as version 4 with the colour format of raw payloads appended
+/// to the header.
+pub const PROTOCOL_VERSION: u8 = 5;
+
+/// A complete frame message. Shared rather than cloned between everything
+/// it's sent to, since a frame can be megabytes.
+pub type FrameMessage = Arc<[u8]>;
+
+/// Bytes before the header: magic and version
+const PREFIX_LEN: usize = FRAME_MAGIC.len() + 1;
+
+/// Size of an encoded `FrameHeader`
+pub const FRAME_HEADER_LEN: usize = 42;
+
+const FLAG_COMPRESSED: u8 = 1 << 0;
+const FLAG_KEYFRAME: u8 = 1 << 1;
+const FLAG_HAS_BASE: u8 = 1 << 2;
+const FLAG_HAS_CHECKSUM: u8 = 1 << 3;
+const FLAG_HOLD: u8 = 1 << 4;
+const FLAG_DICTIONARY: u8 = 1 << 5;
+const FLAG_TILES: u8 = 1 << 6;
+
+/// Encodes averaged before each adaptive level decision
+const ADAPT_WINDOW: u32 = 10;
+
+/// Bits per colour channel of captured frames. xcap only provides 8-bit RGBA.
+pub const CAPTURE_BIT_DEPTH: u8 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FrameHeader {
     pub width: u32,
     pub height: u32,
     pub compressed: bool,
+    /// ms since the Unix epoch by `clock::now_ms`, so it never goes
+    /// backwards with the system clock
     pub timestamp: u64,
     pub frame_id: u64,
+    pub format: CompressionFormat,
+    pub bit_depth: u8,
+    /// False for delta frames, which hold spans of
+    /// `[u32 LE first pixel][u32 LE pixel count][pixels]` to paint
+    /// over the keyframe `base_frame_id`, or tiles with `tiles` set
+    pub is_keyframe: bool,
+    pub base_frame_id: Option<u64>,
+    /// CRC32 of the payload as sent, with `compression.verify_checksums`
+    pub checksum: Option<u32>,
+    /// Frames per second the stream is captured at, which can be below the
+    /// rate a client displays at, e.g. to interpolate between frames
+    pub source_fps: u16,
+    /// A hold marker: no payload, the picture is unchanged since frame
+    /// `base_frame_id`. See `compression.max_hold_frames`.
+    pub is_hold: bool,
+    /// The payload was compressed with the zstd dictionary from
+    /// `compression.dictionary_path`, and needs it to decompress
+    pub dictionary: bool,
+    /// The delta frame holds changed tiles rather than spans, see
+    /// `diff_tiles`
+    pub tiles: bool,
+    /// Layout of the pixels in `zstd` payloads. Always RGBA for the image
+    /// formats.
+    pub color_format: ColorFormat,
+}
+
+impl FrameHeader {
+    /// Pack the header as fixed-width little-endian fields:
+    ///
+    /// | offset | type | field |
+    /// |--------|------|-------|
+    /// | 0  | u32 | width |
+    /// | 4  | u32 | height |
+    /// | 8  | u8  | flags: bit 0 compressed, bit 1 keyframe, bit 2 has base frame, bit 3 has checksum, bit 4 hold, bit 5 dictionary, bit 6 tiles |
+    /// | 9  | u8  | format: 0 zstd, 1 png, 2 jpeg, 3 webp |
+    /// | 10 | u8  | bit depth |
+    /// | 11 | u64 | timestamp (ms since the Unix epoch) |
+    /// | 19 | u64 | frame ID |
+    /// | 27 | u64 | base frame ID, 0 unless the has-base flag is set |
+    /// | 35 | u32 | CRC32 of the payload, 0 unless the has-checksum flag is set |
+    /// | 39 | u16 | source frame rate |
+    /// | 41 | u8  | colour format: 0 RGBA, 1 BGRA, 2 RGB, 3 grayscale |
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_LEN] {
+        let mut flags = 0;
+        if self.compressed {
+            flags |= FLAG_COMPRESSED;
+        }
+        if self.is_keyframe {
+            flags |= FLAG_KEYFRAME;
+        }
+        if self.base_frame_id.is_some() {
+            flags |= FLAG_HAS_BASE;
+        }
+        if self.checksum.is_some() {
+            flags |= FLAG_HAS_CHECKSUM;
+        }
+        if self.is_hold {
+            flags |= FLAG_HOLD;
+        }
+        if self.dictionary {
+            flags |= FLAG_DICTIONARY;
+        }
+        if self.tiles {
+            flags |= FLAG_TILES;
+        }
+        let format = match self.format {
+            CompressionFormat::Zstd => 0,
+            CompressionFormat::Png => 1,
+            CompressionFormat::Jpeg => 2,
+            CompressionFormat::Webp => 3,
+        };
+        let color_format = match self.color_format {
+            ColorFormat::Rgba => 0,
+            ColorFormat::Bgra => 1,
+            ColorFormat::Rgb => 2,
+            ColorFormat::Gray => 3,
+        };
+
+        let mut bytes = [0; FRAME_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.width.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.height.to_le_bytes());
+        bytes[8] = flags;
+        bytes[9] = format;
+        bytes[10] = self.bit_depth;
+        bytes[11..19].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[19..27].copy_from_slice(&self.frame_id.to_le_bytes());
+        bytes[27..35].copy_from_slice(&self.base_frame_id.unwrap_or(0).to_le_bytes());
+        bytes[35..39].copy_from_slice(&self.checksum.unwrap_or(0).to_le_bytes());
+        bytes[39..41].copy_from_slice(&self.source_fps.to_le_bytes());
+        bytes[41] = color_format;
+        bytes
+    }
+
+    /// Unpack a header written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> AppResult<Self> {
+        let bytes: &[u8; FRAME_HEADER_LEN] = bytes
+            .get(..FRAME_HEADER_LEN)
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| AppError::ProtocolError("Truncated frame header".to_string()))?;
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+
+        let flags = bytes[8];
+        let format = match bytes[9] {
+            0 => CompressionFormat::Zstd,
+            1 => CompressionFormat::Png,
+            2 => CompressionFormat::Jpeg,
+            3 => CompressionFormat::Webp,
+            other => {
+                return Err(AppError::ProtocolError(format!("Unknown frame format {}", other)))
+            }
+        };
+        let color_format = match bytes[41] {
+            0 => ColorFormat::Rgba,
+            1 => ColorFormat::Bgra,
+            2 => ColorFormat::Rgb,
+            3 => ColorFormat::Gray,
+            other => {
+                return Err(AppError::ProtocolError(format!("Unknown colour format {}", other)))
+            }
+        };
+
+        Ok(Self {
+            width: u32_at(0),
+            height: u32_at(4),
+            compressed: flags & FLAG_COMPRESSED != 0,
+            timestamp: u64_at(11),
+            frame_id: u64_at(19),
+            format,
+            bit_depth: bytes[10],
+            is_keyframe: flags & FLAG_KEYFRAME != 0,
+            base_frame_id: (flags & FLAG_HAS_BASE != 0).then(|| u64_at(27)),
+            checksum: (flags & FLAG_HAS_CHECKSUM != 0).then(|| u32_at(35)),
+            source_fps: u16::from_le_bytes([bytes[39], bytes[40]]),
+            is_hold: flags & FLAG_HOLD != 0,
+            dictionary: flags & FLAG_DICTIONARY != 0,
+            tiles: flags & FLAG_TILES != 0,
+            color_format,
+        })
+    }
+}
+
+/// A frame later frames are encoded against
+struct ReferenceFrame {
+    rgba: Arc<Vec<u8>>,
+    width: u32,
+    height: u32,
+    frame_id: u64,
 }
 
 pub struct Compressor {
     config: CompressionConfig,
-    frame_counter: std::sync::atomic::AtomicU64,
+    /// JPEG and lossy WebP quality (1-100), from `capture.quality`
+    jpeg_quality: u8,
+    // Shared with the owner of the compressor so frame IDs stay monotonic
+    // for the lifetime of the server, even if the compressor is rebuilt.
+    frame_counter: Arc<AtomicU64>,
+    /// The frame delta frames are encoded against
+    keyframe: Option<ReferenceFrame>,
+    /// Hash of each tile of `keyframe`, with `tile_size` set
+    keyframe_tiles: Vec<u64>,
+    frames_since_keyframe: u64,
+    /// The last frame sent in full, to spot repeats to send as hold
+    /// markers. Only kept with `max_hold_frames` set.
+    previous: Option<ReferenceFrame>,
+    /// Hold markers sent since `previous`
+    holds: u64,
+    /// For `FrameHeader.source_fps`
+    source_fps: u16,
+    /// Timestamp of the last frame, see `prepare_frame`
+    last_timestamp: u64,
+    /// Recycles RGBA frames, delta spans and encoder output, see `BufferPool`
+    pool: Arc<BufferPool>,
+    /// zstd level for the next frame: `config.level`, or tuned by
+    /// `adapt_level` when `min_level`/`max_level` are set
+    level: AtomicI32,
+    /// Encode time and count since the last adaptive level decision
+    recent_encodes: (Duration, u32),
+    /// zstd dictionary from `compression.dictionary_path`
+    dictionary: Option<Arc<[u8]>>,
+    /// Layout of the frames passed to `prepare_frame`
+    color_format: ColorFormat,
 }
 
 impl Compressor {
-    pub fn new(config: CompressionConfig) -> Self {
+    /// `fps` is the rate frames will be captured at, which is only reported
+    /// to clients.
+    pub fn new(config: CompressionConfig, quality: f32, fps: u32, frame_counter: Arc<AtomicU64>) -> Self {
         Self {
+            jpeg_quality: jpeg_quality(quality),
+            frame_counter,
+            keyframe: None,
+            keyframe_tiles: Vec::new(),
+            frames_since_keyframe: 0,
+            previous: None,
+            holds: 0,
+            source_fps: source_fps(fps),
+            last_timestamp: 0,
+            // Each encode in flight holds a payload and an output buffer,
+            // plus the current keyframe and the previous frame
+            pool: Arc::new(BufferPool::new(2 * config.workers + 2)),
+            level: AtomicI32::new(Self::starting_level(&config)),
+            recent_encodes: (Duration::ZERO, 0),
+            dictionary: None,
+            color_format: ColorFormat::Rgba,
             config,
-            frame_counter: std::sync::atomic::AtomicU64::new(0),
         }
     }
 
-    pub fn compress(&self, data: &[u8]) -> AppResult<Vec<u8>> {
-        if !self.config.enabled {
-            return Ok(data.to_vec());
+    /// Compress `zstd` payloads with `dictionary`, see `dictionary::train`.
+    pub fn set_dictionary(&mut self, dictionary: Arc<[u8]>) {
+        self.dictionary = Some(dictionary);
+    }
+
+    /// Take frames laid out as `color_format` rather than RGBA, see
+    /// `convert_color_format`. Only for the `zstd` format.
+    pub fn set_color_format(&mut self, color_format: ColorFormat) {
+        self.color_format = color_format;
+    }
+
+    fn starting_level(config: &CompressionConfig) -> i32 {
+        match (config.min_level, config.max_level) {
+            (Some(min), Some(max)) => config.level.clamp(min, max),
+            _ => config.level,
+        }
+    }
+
+    /// The zstd level frames are currently compressed at
+    pub fn level(&self) -> i32 {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    /// Feed back how long a frame took to encode. Every `ADAPT_WINDOW`
+    /// frames, or sooner if encoding is far behind, steps the level down if
+    /// encodes average more than 80% of `budget`, the time an encode may
+    /// take without falling behind, or up if they average under 40%. Does
+    /// nothing unless `min_level` and `max_level` are set.
+    pub fn adapt_level(&mut self, encode_duration: Duration, budget: Duration) {
+        let (Some(min), Some(max)) = (self.config.min_level, self.config.max_level) else {
+            return;
+        };
+        if self.config.format != CompressionFormat::Zstd || !self.config.enabled {
+            return;
+        }
+
+        let (total, count) = &mut self.recent_encodes;
+        *total += encode_duration;
+        *count += 1;
+        // Decide early when already a window's worth of budget behind, so
+        // a far too slow level doesn't hold up ten more frames
+        if *count < ADAPT_WINDOW && *total < budget * ADAPT_WINDOW {
+            return;
         }
+        let average = *total / *count;
+        self.recent_encodes = (Duration::ZERO, 0);
 
-        let compressed = zstd::encode_all(data, self.config.level)
-            .map_err(|e| AppError::CompressionError(format!("Compression failed: {}", e)))?;
-        
-        Ok(compressed)
+        let level = self.level();
+        let next = if average > budget.mul_f32(0.8) {
+            (level - 1).max(min)
+        } else if average < budget.mul_f32(0.4) {
+            (level + 1).min(max)
+        } else {
+            level
+        };
+        if next != level {
+            debug!(
+                "Compression level {} -> {} (encodes averaging {:?} of a {:?} budget)",
+                level, next, average, budget
+            );
+            self.level.store(next, Ordering::Relaxed);
+        }
     }
 
-    pub fn create_frame_message(&self, data: Vec<u8>, width: u32, height: u32) -> AppResult<Vec<u8>> {
-        let frame_id = self.frame_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        
-        let header = FrameHeader {
+    /// Apply reloaded settings. The next frame is always a keyframe.
+    pub fn reconfigure(&mut self, config: CompressionConfig, quality: f32, fps: u32) {
+        self.jpeg_quality = jpeg_quality(quality);
+        self.source_fps = source_fps(fps);
+        self.level.store(Self::starting_level(&config), Ordering::Relaxed);
+        self.recent_encodes = (Duration::ZERO, 0);
+        self.config = config;
+        if let Some(keyframe) = self.keyframe.take() {
+            self.pool.recycle_shared(keyframe.rgba);
+        }
+        if let Some(previous) = self.previous.take() {
+            self.pool.recycle_shared(previous.rgba);
+        }
+        self.holds = 0;
+    }
+
+    /// The ID of the last frame sent in full if `rgba` is the same picture
+    fn repeat_of(&self, rgba: &[u8], width: u32, height: u32) -> Option<u64> {
+        self.previous
+            .as_ref()
+            .filter(|p| (p.width, p.height) == (width, height) && p.rgba.as_slice() == rgba)
+            .map(|p| p.frame_id)
+    }
+
+    /// Diff against the current keyframe, or `None` if this frame should
+    /// become the next keyframe.
+    fn encode_delta(&self, rgba: &[u8], width: u32, height: u32) -> Option<(Vec<u8>, u64)> {
+        let keyframe = self.keyframe.as_ref().filter(|k| {
+            (k.width, k.height) == (width, height)
+                && self.frames_since_keyframe + 1 < self.config.keyframe_interval
+        })?;
+
+        let mut delta = self.pool.take(0);
+        let bpp = self.color_format.bytes_per_pixel();
+        match self.config.tile_size {
+            0 => diff_spans(&keyframe.rgba, rgba, bpp, &mut delta),
+            size => diff_tiles(&self.keyframe_tiles, rgba, width, height, size, bpp, &mut delta),
+        }
+        let limit = (rgba.len() as f32 * self.config.delta_threshold) as usize;
+        if delta.len() > limit {
+            self.pool.recycle(delta);
+            return None;
+        }
+        Some((delta, keyframe.frame_id))
+    }
+
+    /// Do the part of encoding that depends on earlier frames: assign the
+    /// frame ID, spot repeats and diff against the keyframe. Frames must be
+    /// prepared in capture order, but the returned frames can be encoded in
+    /// parallel.
+    pub fn prepare_frame(&mut self, data: Vec<u8>, width: u32, height: u32) -> PendingFrame {
+        let frame_id = self.frame_counter.fetch_add(1, Ordering::Relaxed);
+        // Strictly increasing, even for frames captured within the same ms
+        let timestamp = clock::now_ms().max(self.last_timestamp + 1);
+        self.last_timestamp = timestamp;
+
+        let start = Instant::now();
+        let repeat_of = self.repeat_of(&data, width, height);
+        if let Some(base) = repeat_of.filter(|_| self.holds < self.config.max_hold_frames) {
+            self.holds += 1;
+            self.pool.recycle(data);
+            let header = self.header(width, height, timestamp, frame_id, Some(base), true);
+            return self.pending(header, Arc::new(Vec::new()), start);
+        }
+        self.holds = 0;
+
+        let rgba = Arc::new(data);
+        let (payload, base_frame_id) = match self.config.format {
+            // A repeat past `max_hold_frames` is always a keyframe, for
+            // clients still waiting for one
+            CompressionFormat::Zstd => match repeat_of
+                .map_or_else(|| self.encode_delta(&rgba, width, height), |_| None)
+            {
+                Some((delta, base)) => {
+                    self.frames_since_keyframe += 1;
+                    (Arc::new(delta), Some(base))
+                }
+                None => {
+                    if self.config.keyframe_interval > 1 {
+                        let previous = self.keyframe.replace(ReferenceFrame {
+                            rgba: rgba.clone(),
+                            width,
+                            height,
+                            frame_id,
+                        });
+                        if let Some(previous) = previous {
+                            self.pool.recycle_shared(previous.rgba);
+                        }
+                        let bpp = self.color_format.bytes_per_pixel();
+                        self.keyframe_tiles = match self.config.tile_size {
+                            0 => Vec::new(),
+                            size => tiles(width, height, size)
+                                .map(|tile| hash_tile(&rgba, width, tile, bpp))
+                                .collect(),
+                        };
+                        self.frames_since_keyframe = 0;
+                    }
+                    (rgba.clone(), None)
+                }
+            },
+            CompressionFormat::Png | CompressionFormat::Jpeg | CompressionFormat::Webp => (rgba.clone(), None),
+        };
+
+        if self.config.max_hold_frames > 0 {
+            let previous = self.previous.replace(ReferenceFrame {
+                rgba,
+                width,
+                height,
+                frame_id,
+            });
+            if let Some(previous) = previous {
+                self.pool.recycle_shared(previous.rgba);
+            }
+        } else {
+            self.pool.recycle_shared(rgba);
+        }
+
+        let header = self.header(width, height, timestamp, frame_id, base_frame_id, false);
+        self.pending(header, payload, start)
+    }
+
+    fn header(
+        &self,
+        width: u32,
+        height: u32,
+        timestamp: u64,
+        frame_id: u64,
+        base_frame_id: Option<u64>,
+        is_hold: bool,
+    ) -> FrameHeader {
+        FrameHeader {
             width,
             height,
-            compressed: self.config.enabled,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
+            compressed: false,
+            timestamp,
             frame_id,
+            format: self.config.format,
+            bit_depth: CAPTURE_BIT_DEPTH,
+            is_keyframe: base_frame_id.is_none(),
+            base_frame_id,
+            checksum: None,
+            source_fps: self.source_fps,
+            is_hold,
+            dictionary: false,
+            tiles: base_frame_id.is_some() && !is_hold && self.config.tile_size > 0,
+            color_format: self.color_format,
+        }
+    }
+
+    fn pending(&self, header: FrameHeader, payload: Arc<Vec<u8>>, start: Instant) -> PendingFrame {
+        PendingFrame {
+            header,
+            payload,
+            checksum: self.config.verify_checksums,
+            zstd_level: self.config.enabled.then(|| self.level()),
+            dictionary: self.dictionary.clone(),
+            jpeg_quality: self.jpeg_quality,
+            webp_lossless: self.config.webp_lossless,
+            prepare_duration: start.elapsed(),
+            pool: self.pool.clone(),
+        }
+    }
+
+    pub fn create_frame_message(&mut self, data: Vec<u8>, width: u32, height: u32) -> AppResult<FrameMessage> {
+        self.prepare_frame(data, width, height)
+            .encode()
+            .map(|frame| frame.message)
+    }
+}
+
+/// A frame that has been through `Compressor::prepare_frame` and only
+/// needs its payload encoded, which doesn't depend on other frames.
+pub struct PendingFrame {
+    /// Complete apart from `compressed`
+    header: FrameHeader,
+    /// Pixels, delta spans for delta frames, or nothing for hold
+    /// markers. Shared with the compressor when this is the current
+    /// keyframe or previous frame.
+    payload: Arc<Vec<u8>>,
+    /// zstd level when `compression.enabled` is set
+    zstd_level: Option<i32>,
+    dictionary: Option<Arc<[u8]>>,
+    /// Fill in `header.checksum`
+    checksum: bool,
+    jpeg_quality: u8,
+    webp_lossless: bool,
+    prepare_duration: Duration,
+    /// Where the payload goes once encoded, unless the compressor still
+    /// holds it as the keyframe
+    pool: Arc<BufferPool>,
+}
+
+/// A finished frame message, see `PROTOCOL_VERSION`
+pub struct EncodedFrame {
+    pub message: FrameMessage,
+    /// Size of the frame as raw RGBA
+    pub raw_len: usize,
+    /// Time spent diffing and encoding the payload, excluding header
+    /// construction
+    pub encode_duration: Duration,
+}
+
+impl PendingFrame {
+    pub fn encode(self) -> AppResult<EncodedFrame> {
+        let mut header = self.header;
+        let (width, height) = (header.width, header.height);
+
+        let start = Instant::now();
+        let mut output = self.pool.take(0);
+        let data: &[u8] = match header.format {
+            _ if header.is_hold => &self.payload,
+            CompressionFormat::Zstd => {
+                match (self.zstd_level, &self.dictionary) {
+                    (Some(level), Some(dictionary)) => {
+                        compress_with_dictionary_into(&self.payload, level, dictionary, &mut output)?
+                    }
+                    (Some(level), None) => compress_into(&self.payload, level, &mut output)?,
+                    (None, _) => {}
+                }
+                // Incompressible frames are sent as they are
+                header.compressed = !output.is_empty() && output.len() < self.payload.len();
+                header.dictionary = header.compressed && self.dictionary.is_some();
+                if header.compressed {
+                    &output
+                } else {
+                    &self.payload
+                }
+            }
+            // Favour speed over size since this runs once per frame
+            CompressionFormat::Png => {
+                write_png(&mut output, &self.payload, width, height, CompressionType::Fast)?;
+                &output
+            }
+            CompressionFormat::Jpeg => {
+                let mut rgb = self.pool.take(self.payload.len() / 4 * 3);
+                let result = write_jpeg(&mut output, &mut rgb, &self.payload, width, height, self.jpeg_quality);
+                self.pool.recycle(rgb);
+                result?;
+                &output
+            }
+            CompressionFormat::Webp => {
+                let quality = (!self.webp_lossless).then_some(self.jpeg_quality);
+                write_webp(&mut output, &self.payload, width, height, quality)?;
+                &output
+            }
         };
+        if self.checksum {
+            header.checksum = Some(crc32fast::hash(data));
+        }
+        let encode_duration = self.prepare_duration + start.elapsed();
+
+        // Message format: see `PROTOCOL_VERSION`. The message is the one
+        // buffer not taken from the pool, since clients keep it.
+        let mut message = Vec::with_capacity(PREFIX_LEN + FRAME_HEADER_LEN + data.len());
+        message.extend_from_slice(&FRAME_MAGIC);
+        message.push(PROTOCOL_VERSION);
+        message.extend_from_slice(&header.to_bytes());
+        message.extend_from_slice(data);
 
-        let header_json = serde_json::to_string(&header)?;
-        let header_bytes = header_json.as_bytes();
-        let header_len = header_bytes.len() as u32;
+        self.pool.recycle(output);
+        self.pool.recycle_shared(self.payload);
 
-        // Message format: [4 bytes header length][header json][frame data]
-        let mut message = Vec::with_capacity(4 + header_bytes.len() + data.len());
-        message.extend_from_slice(&header_len.to_le_bytes());
-        message.extend_from_slice(header_bytes);
-        message.extend_from_slice(&data);
+        Ok(EncodedFrame {
+            message: message.into(),
+            raw_len: width as usize * height as usize * 4,
+            encode_duration,
+        })
+    }
+}
+
+/// zstd-compress `data` at `level`, appending to `out`.
+pub fn compress_into(data: &[u8], level: i32, out: &mut Vec<u8>) -> AppResult<()> {
+    zstd::stream::copy_encode(data, out, level)
+        .map_err(|e| AppError::CompressionError(format!("Compression failed: {}", e)))
+}
+
+/// zstd-compress `data` at `level` with a dictionary written by
+/// `dictionary::train`. Unlike `compress_into` this replaces the contents
+/// of `out`.
+pub fn compress_with_dictionary_into(data: &[u8], level: i32, dictionary: &[u8], out: &mut Vec<u8>) -> AppResult<()> {
+    let error = |e: std::io::Error| AppError::CompressionError(format!("Compression failed: {}", e));
+    out.clear();
+    out.reserve(zstd::zstd_safe::compress_bound(data.len()));
+    zstd::bulk::Compressor::with_dictionary(level, dictionary)
+        .map_err(error)?
+        .compress_to_buffer(data, out)
+        .map_err(error)?;
+    Ok(())
+}
+
+/// Encode an RGBA frame as WebP into `out`: lossy at `quality` (1-100), or
+/// lossless without one.
+fn write_webp(out: &mut Vec<u8>, rgba: &[u8], width: u32, height: u32, quality: Option<u8>) -> AppResult<()> {
+    let encoder = webp::Encoder::from_rgba(rgba, width, height);
+    let webp = match quality {
+        Some(quality) => encoder.encode_simple(false, quality as f32),
+        None => encoder.encode_simple(true, 100.0),
+    }
+    .map_err(|e| AppError::CompressionError(format!("WebP encoding failed: {:?}", e)))?;
+    out.extend_from_slice(&webp);
+    Ok(())
+}
+
+/// Frame rate as sent in `FrameHeader.source_fps`
+fn source_fps(fps: u32) -> u16 {
+    fps.min(u16::MAX as u32) as u16
+}
+
+/// Map `capture.quality` (0.0-1.0) to a JPEG quality (1-100)
+pub fn jpeg_quality(quality: f32) -> u8 {
+    (quality.clamp(0.0, 1.0) * 100.0).round().max(1.0) as u8
+}
+
+/// Encode an RGBA frame as a PNG image.
+pub fn encode_png(rgba: &[u8], width: u32, height: u32, compression: CompressionType) -> AppResult<Vec<u8>> {
+    let mut png = Vec::new();
+    write_png(&mut png, rgba, width, height, compression)?;
+    Ok(png)
+}
 
-        Ok(message)
+fn write_png(
+    out: &mut Vec<u8>,
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    compression: CompressionType,
+) -> AppResult<()> {
+    PngEncoder::new_with_quality(out, compression, FilterType::Sub)
+        .write_image(rgba, width, height, image::ExtendedColorType::Rgba8)
+        .map_err(|e| AppError::CompressionError(format!("PNG encoding failed: {}", e)))
+}
+
+/// Encode an RGBA frame as a baseline JPEG at `quality` (1-100), discarding alpha.
+pub fn encode_jpeg(rgba: &[u8], width: u32, height: u32, quality: u8) -> AppResult<Vec<u8>> {
+    let mut jpeg = Vec::new();
+    write_jpeg(&mut jpeg, &mut Vec::new(), rgba, width, height, quality)?;
+    Ok(jpeg)
+}
+
+/// `encode_jpeg` into `out`, using `rgb` as scratch space for the pixels
+/// without alpha.
+fn write_jpeg(
+    out: &mut Vec<u8>,
+    rgb: &mut Vec<u8>,
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+) -> AppResult<()> {
+    rgb.clear();
+    rgb.extend(rgba.chunks_exact(4).flat_map(|px| [px[0], px[1], px[2]]));
+
+    JpegEncoder::new_with_quality(out, quality)
+        .write_image(rgb, width, height, image::ExtendedColorType::Rgb8)
+        .map_err(|e| AppError::CompressionError(format!("JPEG encoding failed: {}", e)))
+}
+
+/// Encode the `bpp`-byte pixels of `current` that differ from `base` as
+/// spans of `[u32 LE first pixel][u32 LE pixel count][pixels]`. Identical
+/// frames produce an empty delta. The spans are appended to `delta`.
+fn diff_spans(base: &[u8], current: &[u8], bpp: usize, delta: &mut Vec<u8>) {
+    let pixels = current.len() / bpp;
+    let changed = |i: usize| base[i * bpp..(i + 1) * bpp] != current[i * bpp..(i + 1) * bpp];
+    let mut i = 0;
+
+    while i < pixels {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        while i < pixels && changed(i) {
+            i += 1;
+        }
+        delta.extend_from_slice(&(start as u32).to_le_bytes());
+        delta.extend_from_slice(&((i - start) as u32).to_le_bytes());
+        delta.extend_from_slice(&current[start * bpp..i * bpp]);
+    }
+}
+
+/// Tiles of a `width`×`height` frame as `(x, y, width, height)`, row by
+/// row. Tiles on the right and bottom edges can be smaller than `size`.
+fn tiles(width: u32, height: u32, size: u32) -> impl Iterator<Item = (u32, u32, u32, u32)> {
+    (0..height).step_by(size as usize).flat_map(move |y| {
+        (0..width)
+            .step_by(size as usize)
+            .map(move |x| (x, y, size.min(width - x), size.min(height - y)))
+    })
+}
+
+fn hash_tile(pixels: &[u8], width: u32, (x, y, w, h): (u32, u32, u32, u32), bpp: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for row in y..y + h {
+        let start = (row as usize * width as usize + x as usize) * bpp;
+        hasher.write(&pixels[start..start + w as usize * bpp]);
+    }
+    hasher.finish()
+}
+
+/// Encode the `size`-pixel tiles of `current` whose hash differs from
+/// `base_hashes` (see `tiles`) as `[u32 LE tile count]`, then
+/// `[u32 LE x][u32 LE y][u32 LE width][u32 LE height]` per tile, then the
+/// tiles' rows of `bpp`-byte pixels in the same order. Identical frames
+/// produce no tiles. Appended to `delta`.
+fn diff_tiles(
+    base_hashes: &[u64],
+    current: &[u8],
+    width: u32,
+    height: u32,
+    size: u32,
+    bpp: usize,
+    delta: &mut Vec<u8>,
+) {
+    let changed: Vec<_> = tiles(width, height, size)
+        .zip(base_hashes)
+        .filter(|&(tile, &hash)| hash_tile(current, width, tile, bpp) != hash)
+        .map(|(tile, _)| tile)
+        .collect();
+
+    delta.extend_from_slice(&(changed.len() as u32).to_le_bytes());
+    for &(x, y, w, h) in &changed {
+        for value in [x, y, w, h] {
+            delta.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    for &(x, y, w, h) in &changed {
+        for row in y..y + h {
+            let start = (row as usize * width as usize + x as usize) * bpp;
+            delta.extend_from_slice(&current[start..start + w as usize * bpp]);
+        }
+    }
+}
+
+/// Convert captured RGBA pixels to `format` in place, see
+/// `capture.color_format`. Grayscale is BT.601 luma.
+pub fn convert_color_format(rgba: &mut Vec<u8>, format: ColorFormat) {
+    let bpp = format.bytes_per_pixel();
+    match format {
+        ColorFormat::Rgba => return,
+        ColorFormat::Bgra => rgba.chunks_exact_mut(4).for_each(|px| px.swap(0, 2)),
+        // Each pixel moves to an offset at or before its own, so the
+        // pixels still to be read are never overwritten
+        ColorFormat::Rgb => {
+            for i in 0..rgba.len() / 4 {
+                rgba.copy_within(i * 4..i * 4 + 3, i * 3);
+            }
+        }
+        ColorFormat::Gray => {
+            for i in 0..rgba.len() / 4 {
+                let [r, g, b] = [0, 1, 2].map(|c| rgba[i * 4 + c] as u32);
+                // Integer BT.601 luma, as in motion detection
+                rgba[i] = ((r * 299 + g * 587 + b * 114) / 1000) as u8;
+            }
+        }
+    }
+    rgba.truncate(rgba.len() / 4 * bpp);
+}
+
+/// Back to RGBA from `convert_color_format`, with opaque alpha where it was
+/// dropped.
+pub fn to_rgba(mut pixels: Vec<u8>, format: ColorFormat) -> Vec<u8> {
+    match format {
+        ColorFormat::Rgba => pixels,
+        ColorFormat::Bgra => {
+            pixels.chunks_exact_mut(4).for_each(|px| px.swap(0, 2));
+            pixels
+        }
+        ColorFormat::Rgb => pixels.chunks_exact(3).flat_map(|px| [px[0], px[1], px[2], 255]).collect(),
+        ColorFormat::Gray => pixels.iter().flat_map(|&l| [l, l, l, 255]).collect(),
     }
 }
 
-// Decompression function - available for future use
-#[allow(dead_code)]
+/// Turns frame messages back into RGBA pixels, keeping the keyframe that
+/// delta frames are relative to.
+#[derive(Default)]
+pub struct FrameDecoder {
+    keyframe: Option<(u64, Vec<u8>)>,
+    /// For frames with `FrameHeader.dictionary` set
+    dictionary: Option<Arc<[u8]>>,
+}
+
+impl FrameDecoder {
+    /// A decoder for a stream compressed with `dictionary`, if any.
+    pub fn with_dictionary(dictionary: Option<Arc<[u8]>>) -> Self {
+        Self {
+            keyframe: None,
+            dictionary,
+        }
+    }
+
+    /// Decode a message built by `create_frame_message`. Returns `None` for
+    /// delta frames whose keyframe was never seen, e.g. just after connecting,
+    /// and for hold markers, which have no picture of their own.
+    pub fn decode(&mut self, message: &[u8]) -> AppResult<Option<(FrameHeader, Vec<u8>)>> {
+        let (header, payload) = parse_frame_message(message)?;
+        if header.is_hold {
+            return Ok(None);
+        }
+
+        let rgba = match header.format {
+            CompressionFormat::Png => decode_image(payload, image::ImageFormat::Png)?,
+            CompressionFormat::Jpeg => decode_image(payload, image::ImageFormat::Jpeg)?,
+            CompressionFormat::Webp => decode_image(payload, image::ImageFormat::WebP)?,
+            CompressionFormat::Zstd => {
+                let data = match (header.compressed, header.dictionary, &self.dictionary) {
+                    (false, _, _) => payload.to_vec(),
+                    (true, false, _) => decompress(payload)?,
+                    (true, true, Some(dictionary)) => decompress_with_dictionary(payload, dictionary)?,
+                    (true, true, None) => {
+                        return Err(AppError::CompressionError(
+                            "Frame needs a zstd dictionary, but none is loaded".to_string(),
+                        ))
+                    }
+                };
+
+                let bpp = header.color_format.bytes_per_pixel();
+                let pixels = match header.base_frame_id {
+                    None => {
+                        self.keyframe = Some((header.frame_id, data.clone()));
+                        data
+                    }
+                    Some(base) => match &self.keyframe {
+                        Some((id, keyframe)) if *id == base => {
+                            let mut pixels = keyframe.clone();
+                            if header.tiles {
+                                apply_tiles(&mut pixels, header.width, &data, bpp)?;
+                            } else {
+                                apply_delta(&mut pixels, &data, bpp)?;
+                            }
+                            pixels
+                        }
+                        _ => return Ok(None),
+                    },
+                };
+                to_rgba(pixels, header.color_format)
+            }
+        };
+
+        Ok(Some((header, rgba)))
+    }
+}
+
+fn decode_image(data: &[u8], format: image::ImageFormat) -> AppResult<Vec<u8>> {
+    Ok(image::load_from_memory_with_format(data, format)
+        .map_err(|e| AppError::CompressionError(format!("{:?} decoding failed: {}", format, e)))?
+        .into_rgba8()
+        .into_raw())
+}
+
+/// Paint the spans of a delta frame (see `diff_spans`) over its keyframe
+/// of `bpp`-byte pixels.
+pub fn apply_delta(pixels: &mut [u8], mut delta: &[u8], bpp: usize) -> AppResult<()> {
+    let malformed = || AppError::CompressionError("Malformed delta frame".to_string());
+
+    while !delta.is_empty() {
+        let start = delta.get(..4).ok_or_else(malformed)?;
+        let count = delta.get(4..8).ok_or_else(malformed)?;
+        let start = u32::from_le_bytes([start[0], start[1], start[2], start[3]]) as usize * bpp;
+        let len = u32::from_le_bytes([count[0], count[1], count[2], count[3]]) as usize * bpp;
+
+        let span = delta.get(8..8 + len).ok_or_else(malformed)?;
+        pixels
+            .get_mut(start..start + len)
+            .ok_or_else(malformed)?
+            .copy_from_slice(span);
+        delta = &delta[8 + len..];
+    }
+
+    Ok(())
+}
+
+/// Paint the tiles of a delta frame (see `diff_tiles`) over its keyframe
+/// of `bpp`-byte pixels, `width` pixels wide.
+pub fn apply_tiles(pixels: &mut [u8], width: u32, delta: &[u8], bpp: usize) -> AppResult<()> {
+    let malformed = || AppError::CompressionError("Malformed tile delta frame".to_string());
+    let u32_at = |i: usize| -> AppResult<usize> {
+        let bytes = delta.get(i..i + 4).ok_or_else(malformed)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+
+    let count = u32_at(0)?;
+    let mut offset = 4 + count * 16;
+    for tile in 0..count {
+        let [x, y, w, h] = [0, 4, 8, 12].map(|field| u32_at(4 + tile * 16 + field));
+        let (x, y, w, h) = (x?, y?, w?, h?);
+        if x + w > width as usize {
+            return Err(malformed());
+        }
+        for row in y..y + h {
+            let start = (row * width as usize + x) * bpp;
+            let source = delta.get(offset..offset + w * bpp).ok_or_else(malformed)?;
+            pixels
+                .get_mut(start..start + w * bpp)
+                .ok_or_else(malformed)?
+                .copy_from_slice(source);
+            offset += w * bpp;
+        }
+    }
+
+    Ok(())
+}
+
+/// Split a message built by `create_frame_message` into header and payload,
+/// rejecting messages without the magic bytes, from another protocol version
+/// or whose payload doesn't match its checksum.
+pub fn parse_frame_message(message: &[u8]) -> AppResult<(FrameHeader, &[u8])> {
+    let (header, payload) = split_frame_message(message)?;
+    verify_checksum(&header, payload)?;
+    Ok((header, payload))
+}
+
+/// Check `payload` against `header.checksum`. Frames sent without a
+/// checksum always pass.
+pub fn verify_checksum(header: &FrameHeader, payload: &[u8]) -> AppResult<()> {
+    match header.checksum {
+        Some(expected) if crc32fast::hash(payload) != expected => Err(AppError::ProtocolError(format!(
+            "Frame {} failed its checksum, payload corrupted",
+            header.frame_id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// `parse_frame_message` without the checksum check
+fn split_frame_message(message: &[u8]) -> AppResult<(FrameHeader, &[u8])> {
+    let prefix = message
+        .get(..PREFIX_LEN)
+        .ok_or_else(|| AppError::ProtocolError("Frame message too short".to_string()))?;
+    if prefix[..2] != FRAME_MAGIC {
+        return Err(AppError::ProtocolError("Not a frame message (bad magic)".to_string()));
+    }
+    if prefix[2] != PROTOCOL_VERSION {
+        return Err(AppError::ProtocolError(format!(
+            "Unsupported protocol version {} (expected {})",
+            prefix[2], PROTOCOL_VERSION
+        )));
+    }
+
+    let header = FrameHeader::from_bytes(&message[PREFIX_LEN..])?;
+    Ok((header, &message[PREFIX_LEN + FRAME_HEADER_LEN..]))
+}
+
+/// Read the header of a message built by `create_frame_message`, without
+/// verifying the payload.
+pub fn read_frame_header(message: &[u8]) -> AppResult<FrameHeader> {
+    split_frame_message(message).map(|(header, _)| header)
+}
+
 pub fn decompress(data: &[u8]) -> AppResult<Vec<u8>> {
     zstd::decode_all(data)
         .map_err(|e| AppError::CompressionError(format!("Decompression failed: {}", e)))
 }
+
+/// `decompress` for payloads compressed with `dictionary`.
+pub fn decompress_with_dictionary(data: &[u8], dictionary: &[u8]) -> AppResult<Vec<u8>> {
+    let error = |e: std::io::Error| AppError::CompressionError(format!("Decompression failed: {}", e));
+    let mut decoder = zstd::stream::Decoder::with_dictionary(data, dictionary).map_err(error)?;
+    let mut rgba = Vec::new();
+    std::io::Read::read_to_end(&mut decoder, &mut rgba).map_err(error)?;
+    Ok(rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn gradient(width: u32, height: u32) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| [(x * 255 / width) as u8, (y * 255 / height) as u8, 128, 255]))
+            .collect()
+    }
+
+    fn compressor(configure: impl FnOnce(&mut CompressionConfig)) -> Compressor {
+        let mut config = Config::default().compression;
+        configure(&mut config);
+        Compressor::new(config, 0.8, 30, Arc::new(AtomicU64::new(0)))
+    }
+
+    fn frame_id(message: &[u8]) -> u64 {
+        read_frame_header(message).unwrap().frame_id
+    }
+
+    #[test]
+    fn frame_ids_survive_config_reload() {
+        let config = Config::default();
+        let counter = Arc::new(AtomicU64::new(0));
+        let mut compressor = Compressor::new(config.compression.clone(), 0.8, 30, counter.clone());
+        let first = compressor.create_frame_message(vec![0; 16], 2, 2).unwrap();
+
+        let mut reloaded = config.clone();
+        reloaded.compression.level = 9;
+        reloaded.capture.fps = 60;
+        let merged = config.reloaded(&reloaded);
+        compressor.reconfigure(merged.compression.clone(), merged.capture.quality, merged.capture.fps);
+        let second = compressor.create_frame_message(vec![0; 16], 2, 2).unwrap();
+
+        // A restarted capture task builds a new compressor on the same counter
+        let mut rebuilt = Compressor::new(merged.compression, merged.capture.quality, merged.capture.fps, counter);
+        let third = rebuilt.create_frame_message(vec![0; 16], 2, 2).unwrap();
+
+        assert_eq!([frame_id(&first), frame_id(&second), frame_id(&third)], [0, 1, 2]);
+    }
+
+    #[test]
+    fn jpeg_round_trip_keeps_dimensions() {
+        let mut compressor = compressor(|c| c.format = CompressionFormat::Jpeg);
+        let message = compressor.create_frame_message(gradient(64, 48), 64, 48).unwrap();
+
+        let (header, rgba) = FrameDecoder::default().decode(&message).unwrap().unwrap();
+        assert_eq!(header.format, CompressionFormat::Jpeg);
+        assert_eq!((header.width, header.height), (64, 48));
+        assert_eq!(rgba.len(), 64 * 48 * 4);
+    }
+
+    #[test]
+    fn delta_of_identical_frame_is_smaller_than_changed_region() {
+        let mut compressor = compressor(|c| {
+            c.keyframe_interval = 10;
+            c.max_hold_frames = 0;
+        });
+        let frame = gradient(64, 48);
+        let mut changed = frame.clone();
+        for row in 8..24 {
+            changed[row * 64 * 4 + 32..row * 64 * 4 + 96].fill(0);
+        }
+
+        let keyframe = compressor.create_frame_message(frame.clone(), 64, 48).unwrap();
+        let identical = compressor.create_frame_message(frame, 64, 48).unwrap();
+        let region = compressor.create_frame_message(changed.clone(), 64, 48).unwrap();
+
+        let (identical_header, _) = parse_frame_message(&identical).unwrap();
+        let (region_header, _) = parse_frame_message(&region).unwrap();
+        assert!(!identical_header.is_keyframe && !region_header.is_keyframe);
+        assert_eq!(region_header.base_frame_id, Some(frame_id(&keyframe)));
+        assert!(identical.len() < region.len());
+        assert!(region.len() < keyframe.len());
+
+        let mut decoder = FrameDecoder::default();
+        decoder.decode(&keyframe).unwrap();
+        let (_, rgba) = decoder.decode(&region).unwrap().unwrap();
+        assert_eq!(rgba, changed);
+    }
+
+    #[test]
+    fn parses_a_valid_message() {
+        let mut compressor = compressor(|_| {});
+        let message = compressor.create_frame_message(gradient(8, 4), 8, 4).unwrap();
+
+        assert_eq!(message[..2], FRAME_MAGIC);
+        assert_eq!(message[2], PROTOCOL_VERSION);
+        let (header, payload) = parse_frame_message(&message).unwrap();
+        assert_eq!((header.width, header.height), (8, 4));
+        assert_eq!(payload.len(), message.len() - PREFIX_LEN - FRAME_HEADER_LEN);
+    }
+
+    #[test]
+    fn rejects_corrupted_and_foreign_messages() {
+        let mut compressor = compressor(|_| {});
+        let message = compressor.create_frame_message(gradient(8, 4), 8, 4).unwrap().to_vec();
+
+        let mut bad_magic = message.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(parse_frame_message(&bad_magic), Err(AppError::ProtocolError(_))));
+
+        let mut old_version = message.clone();
+        old_version[2] = PROTOCOL_VERSION - 1;
+        assert!(matches!(parse_frame_message(&old_version), Err(AppError::ProtocolError(_))));
+
+        let mut bad_format = message.clone();
+        bad_format[PREFIX_LEN + 9] = 0xff;
+        assert!(matches!(parse_frame_message(&bad_format), Err(AppError::ProtocolError(_))));
+
+        assert!(parse_frame_message(&message[..PREFIX_LEN + FRAME_HEADER_LEN - 1]).is_err());
+        assert!(parse_frame_message(&message[..2]).is_err());
+    }
+
+    #[test]
+    fn header_is_42_bytes_and_round_trips() {
+        let header = FrameHeader {
+            width: 1920,
+            height: 1080,
+            compressed: true,
+            timestamp: 1_700_000_000_123,
+            frame_id: 42,
+            format: CompressionFormat::Zstd,
+            bit_depth: CAPTURE_BIT_DEPTH,
+            is_keyframe: false,
+            base_frame_id: Some(40),
+            checksum: Some(0xdead_beef),
+            source_fps: 60,
+            is_hold: false,
+            dictionary: true,
+            tiles: true,
+            color_format: ColorFormat::Bgra,
+        };
+
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), 42);
+        assert_eq!(bytes[..4], 1920u32.to_le_bytes());
+        assert_eq!(bytes[19..27], 42u64.to_le_bytes());
+        assert_eq!(FrameHeader::from_bytes(&bytes).unwrap(), header);
+        assert!(FrameHeader::from_bytes(&bytes[..41]).is_err());
+    }
+
+    #[test]
+    fn compressible_frame_gets_a_smaller_payload() {
+        let raw = [40, 80, 120, 255].repeat(64 * 48);
+        let message = compressor(|_| {}).create_frame_message(raw.clone(), 64, 48).unwrap();
+        let (header, payload) = parse_frame_message(&message).unwrap();
+        assert!(header.compressed);
+        assert!(payload.len() < raw.len() / 10, "{} of {} bytes", payload.len(), raw.len());
+        assert_eq!(decompress(payload).unwrap(), raw);
+
+        let message = compressor(|c| c.enabled = false).create_frame_message(raw.clone(), 64, 48).unwrap();
+        let (header, payload) = parse_frame_message(&message).unwrap();
+        assert!(!header.compressed);
+        assert_eq!(payload, raw);
+    }
+
+    #[test]
+    fn flipped_payload_byte_fails_the_checksum() {
+        let mut checked = compressor(|c| c.verify_checksums = true);
+        let message = checked.create_frame_message(gradient(8, 4), 8, 4).unwrap().to_vec();
+        let (header, _) = parse_frame_message(&message).unwrap();
+        assert!(header.checksum.is_some());
+
+        let mut corrupted = message.clone();
+        *corrupted.last_mut().unwrap() ^= 0x01;
+        assert!(matches!(parse_frame_message(&corrupted), Err(AppError::ProtocolError(_))));
+
+        // Without checksums the same corruption goes unnoticed
+        let mut plain = compressor(|c| c.verify_checksums = false);
+        let mut unchecked = plain.create_frame_message(gradient(8, 4), 8, 4).unwrap().to_vec();
+        *unchecked.last_mut().unwrap() ^= 0x01;
+        assert!(parse_frame_message(&unchecked).is_ok());
+    }
+
+    #[test]
+    fn adapt_level_follows_encode_times() {
+        let mut compressor = compressor(|c| {
+            c.level = 5;
+            (c.min_level, c.max_level) = (Some(4), Some(6));
+        });
+        let budget = Duration::from_millis(10);
+        let feed = |compressor: &mut Compressor, millis: u64| {
+            for _ in 0..ADAPT_WINDOW {
+                compressor.adapt_level(Duration::from_millis(millis), budget);
+            }
+            compressor.level()
+        };
+
+        assert_eq!(feed(&mut compressor, 9), 4, "slow encodes lower the level");
+        assert_eq!(feed(&mut compressor, 9), 4, "but not below min_level");
+        assert_eq!(feed(&mut compressor, 6), 4, "between 40% and 80% stays put");
+        assert_eq!(feed(&mut compressor, 1), 5, "fast encodes raise the level");
+        assert_eq!(feed(&mut compressor, 1), 6);
+        assert_eq!(feed(&mut compressor, 1), 6, "but not above max_level");
+
+        // One encode a whole window over budget doesn't wait for the window
+        compressor.adapt_level(budget * ADAPT_WINDOW, budget);
+        assert_eq!(compressor.level(), 5);
+    }
+
+    #[test]
+    fn level_is_fixed_without_min_and_max() {
+        let mut compressor = compressor(|c| c.level = 5);
+        for _ in 0..ADAPT_WINDOW {
+            compressor.adapt_level(Duration::from_secs(1), Duration::from_millis(10));
+        }
+        assert_eq!(compressor.level(), 5);
+    }
+
+    #[test]
+    fn identical_frames_become_hold_markers() {
+        let mut compressor = compressor(|c| c.max_hold_frames = 2);
+        let mut send = |rgba: Vec<u8>| {
+            let message = compressor.create_frame_message(rgba, 8, 4).unwrap();
+            let (header, payload) = parse_frame_message(&message).unwrap();
+            (header, payload.is_empty())
+        };
+
+        let (first, _) = send(gradient(8, 4));
+        assert!(!first.is_hold);
+        for _ in 0..2 {
+            let (hold, empty) = send(gradient(8, 4));
+            assert!(hold.is_hold && empty);
+            assert_eq!(hold.base_frame_id, Some(first.frame_id));
+            assert_eq!(hold.source_fps, 30);
+        }
+
+        // Past max_hold_frames the picture goes out again, as a keyframe
+        let (repeat, _) = send(gradient(8, 4));
+        assert!(!repeat.is_hold && repeat.is_keyframe);
+
+        let (changed, _) = send(vec![0; 8 * 4 * 4]);
+        assert!(!changed.is_hold);
+    }
+
+    #[test]
+    fn no_hold_markers_when_disabled() {
+        let mut compressor = compressor(|c| c.max_hold_frames = 0);
+        for _ in 0..3 {
+            let message = compressor.create_frame_message(gradient(8, 4), 8, 4).unwrap();
+            assert!(!read_frame_header(&message).unwrap().is_hold);
+        }
+    }
+
+    #[test]
+    fn timestamps_increase_across_a_clock_jump() {
+        let mut compressor = compressor(|c| c.max_hold_frames = 0);
+        // As if the previous frame was stamped before the clock went back
+        // an hour
+        compressor.last_timestamp = clock::now_ms() + 3_600_000;
+        let mut last = compressor.last_timestamp;
+        for _ in 0..5 {
+            let header = read_frame_header(&compressor.create_frame_message(gradient(8, 4), 8, 4).unwrap()).unwrap();
+            assert!(header.timestamp > last);
+            last = header.timestamp;
+        }
+    }
+
+    #[test]
+    fn webp_payload_is_a_riff_webp_file() {
+        for lossless in [false, true] {
+            let mut compressor = compressor(|c| {
+                c.format = CompressionFormat::Webp;
+                c.webp_lossless = lossless;
+            });
+            let message = compressor.create_frame_message(gradient(32, 16), 32, 16).unwrap();
+            let (header, payload) = parse_frame_message(&message).unwrap();
+            assert_eq!(header.format, CompressionFormat::Webp);
+            assert_eq!(&payload[..4], b"RIFF");
+            assert_eq!(&payload[8..12], b"WEBP");
+
+            let (_, rgba) = FrameDecoder::default().decode(&message).unwrap().unwrap();
+            assert_eq!(rgba.len(), 32 * 16 * 4);
+            if lossless {
+                assert_eq!(rgba, gradient(32, 16));
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_changed_tile_is_emitted() {
+        let base = gradient(32, 16);
+        let hashes: Vec<u64> = tiles(32, 16, 8).map(|tile| hash_tile(&base, 32, tile, 4)).collect();
+        let mut current = base.clone();
+        // Pixel (17, 9), in the tile at (16, 8)
+        current[(9 * 32 + 17) * 4] ^= 0xff;
+
+        let mut delta = Vec::new();
+        diff_tiles(&hashes, &current, 32, 16, 8, 4, &mut delta);
+        let words: Vec<u32> = delta[..20].chunks_exact(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect();
+        assert_eq!(words, [1, 16, 8, 8, 8]);
+        let pixels = &delta[20..];
+        assert_eq!(pixels.len(), 8 * 8 * 4);
+        for (row, pixels) in pixels.chunks_exact(8 * 4).enumerate() {
+            let start = ((8 + row) * 32 + 16) * 4;
+            assert_eq!(pixels, &current[start..start + 8 * 4]);
+        }
+
+        let mut unchanged = Vec::new();
+        diff_tiles(&hashes, &base, 32, 16, 8, 4, &mut unchanged);
+        assert_eq!(unchanged, 0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn tiled_deltas_decode_to_the_frame() {
+        let mut compressor = compressor(|c| {
+            c.keyframe_interval = 10;
+            c.tile_size = 8;
+        });
+        let mut decoder = FrameDecoder::default();
+        let base = gradient(32, 16);
+        decoder.decode(&compressor.create_frame_message(base.clone(), 32, 16).unwrap()).unwrap();
+
+        let mut current = base;
+        current[(9 * 32 + 17) * 4] ^= 0xff;
+        let message = compressor.create_frame_message(current.clone(), 32, 16).unwrap();
+        let (header, rgba) = decoder.decode(&message).unwrap().unwrap();
+        assert!(header.tiles && !header.is_keyframe);
+        assert_eq!(rgba, current);
+    }
+
+    #[test]
+    fn color_formats_have_the_expected_layout() {
+        let rgba = vec![10, 20, 30, 40, 200, 100, 50, 255];
+        let convert = |format: ColorFormat| {
+            let mut pixels = rgba.clone();
+            convert_color_format(&mut pixels, format);
+            assert_eq!(pixels.len(), 2 * format.bytes_per_pixel());
+            pixels
+        };
+
+        assert_eq!(convert(ColorFormat::Rgba), rgba);
+        assert_eq!(convert(ColorFormat::Bgra), [30, 20, 10, 40, 50, 100, 200, 255]);
+        assert_eq!(convert(ColorFormat::Rgb), [10, 20, 30, 200, 100, 50]);
+        // (r * 299 + g * 587 + b * 114) / 1000
+        assert_eq!(convert(ColorFormat::Gray), [18, 124]);
+    }
+
+    #[test]
+    fn color_formats_convert_back_to_rgba() {
+        let rgba = vec![10, 20, 30, 40, 200, 100, 50, 255];
+        for (format, expected) in [
+            (ColorFormat::Rgba, rgba.clone()),
+            (ColorFormat::Bgra, rgba.clone()),
+            // Alpha comes back opaque
+            (ColorFormat::Rgb, vec![10, 20, 30, 255, 200, 100, 50, 255]),
+            (ColorFormat::Gray, vec![18, 18, 18, 255, 124, 124, 124, 255]),
+        ] {
+            let mut pixels = rgba.clone();
+            convert_color_format(&mut pixels, format);
+            assert_eq!(to_rgba(pixels, format), expected, "{:?}", format);
+        }
+    }
+}
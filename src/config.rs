@@ -2,8 +2,14 @@ use clap::Parser;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use anyhow::Result;
+use tracing::{info, warn};
 
-#[derive(Parser)]
+use crate::{
+    error::{AppError, AppResult},
+    preset::{self, Preset},
+};
+
+#[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// Configuration file path
@@ -21,35 +27,656 @@ pub struct Args {
     /// Compression level (0-22)
     #[arg(short = 'z', long)]
     pub compression: Option<i32>,
+
+    /// Write every captured frame as a numbered PNG into this directory
+    #[arg(long, value_name = "DIR")]
+    pub dump_frames: Option<PathBuf>,
+
+    /// Maximum number of frames to write with --dump-frames
+    #[arg(long, default_value_t = 1000)]
+    pub dump_count: u64,
+
+    /// Record every frame sent to clients into this file
+    #[arg(long, value_name = "PATH", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Stream frames from a file written by --record instead of capturing
+    /// the screen, looping at the end
+    #[arg(long, value_name = "PATH")]
+    pub replay: Option<PathBuf>,
+
+    /// Apply a named preset (from the presets file or built-in:
+    /// low-bandwidth, lossless, retro-demo) before command line overrides
+    #[arg(long, value_name = "NAME")]
+    pub preset: Option<String>,
+
+    /// Save the resolved capture and compression settings as a named preset
+    #[arg(long, value_name = "NAME")]
+    pub save_preset: Option<String>,
+
+    /// Presets file path
+    #[arg(long, default_value = "presets.toml")]
+    pub presets_file: PathBuf,
+
+    /// Log as human-readable text or as one JSON object per line
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Train a zstd dictionary for compression.dictionary_path on the
+    /// images in SAMPLES_DIR, e.g. from --dump-frames, and exit
+    #[arg(long, num_args = 2, value_names = ["SAMPLES_DIR", "OUT_FILE"])]
+    pub train_dict: Option<Vec<PathBuf>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Environment variables named `RETROSTREAM_<SECTION>__<KEY>` override
+/// config values, e.g. `RETROSTREAM_SERVER__PORT=9000` for `server.port` or
+/// `RETROSTREAM_BUFFER_SIZE=4` for the top-level `buffer_size`
+const ENV_PREFIX: &str = "RETROSTREAM";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub capture: CaptureConfig,
     pub compression: CompressionConfig,
     pub buffer_size: usize,
+    /// Warn once the slowest client is this many frames behind, before
+    /// frames start being dropped. Defaults to 3/4 of `buffer_size`.
+    #[serde(default)]
+    pub queue_high_water_mark: Option<usize>,
+    /// What happens to a client's `buffer_size` queue once it is full
+    #[serde(default)]
+    pub overflow_strategy: OverflowStrategy,
+    #[serde(default)]
+    pub motion: MotionConfig,
+    #[serde(default)]
+    pub log_format: LogFormat,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What to do with a new frame when a client's queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowStrategy {
+    /// Drop the client's oldest queued frame, so it stays close to live
+    #[default]
+    DropOldest,
+    /// Drop the new frame, so the client finishes what it has queued
+    DropNewest,
+    /// Hold capture until every client has room, pacing it to the slowest
+    Block,
+}
+
+/// How log lines are written to stdout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable, one line per event
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregators
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub max_connections: usize,
+    /// Largest WebSocket message or frame accepted from a client, in bytes.
+    /// Outbound video frames are sent as a single unfragmented WebSocket
+    /// frame and are not subject to this limit, but clients should use a
+    /// matching limit since high resolutions with compression off can exceed
+    /// 30 MB per frame.
+    #[serde(default = "default_max_ws_message_bytes")]
+    pub max_ws_message_bytes: usize,
+    /// When at `max_connections`, hold new clients in a FIFO queue instead
+    /// of rejecting them
+    #[serde(default)]
+    pub queue_when_full: bool,
+    /// Clients beyond this many queued are rejected
+    #[serde(default = "default_max_queue_length")]
+    pub max_queue_length: usize,
+    /// Push the most recent frame to a client as soon as it connects rather
+    /// than waiting for the next capture
+    #[serde(default = "default_true")]
+    pub send_latest_on_connect: bool,
+    /// Drop frames older than this (capture time to send) instead of
+    /// delivering stale content. Keyframes are sent anyway, since the
+    /// deltas after them need them. Zero disables the deadline.
+    #[serde(default)]
+    pub max_frame_age_ms: u64,
+    /// How long to wait on Ctrl+C for clients to acknowledge the close
+    /// frame before exiting anyway
+    #[serde(default = "default_shutdown_grace_ms")]
+    pub shutdown_grace_ms: u64,
+    /// Release frames to each client on a steady cadence at the capture
+    /// rate instead of as soon as they arrive, smoothing out bursts.
+    /// Frames superseded while waiting are dropped.
+    #[serde(default)]
+    pub pacing: bool,
+    /// How long after a WebSocket connection drops the client can reconnect
+    /// with `?session=` and count as the same viewer
+    #[serde(default = "default_session_ttl_ms")]
+    pub session_ttl_ms: u64,
+    /// Before each send, skip ahead to the newest queued frame so slow
+    /// clients always show current content instead of working through a
+    /// backlog. The keyframe a newer delta frame depends on is still sent.
+    #[serde(default)]
+    pub latest_frame_only: bool,
+    /// Cap on what each WebSocket client is sent, in kilobits per second
+    /// over a sliding one-second window. Frames over budget are skipped
+    /// whole. Zero means unlimited.
+    #[serde(default)]
+    pub max_bitrate_kbps: u64,
+    /// Require this token on `/stream`, `/mjpeg`, `/metrics`,
+    /// `/metrics/stream`, `/monitors`, `/windows`, `/snapshot` and `/config`, as
+    /// `?token=` or `Authorization: Bearer`. Unset leaves them open.
+    /// Masked in `/config`.
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    /// Token `set_redactions` commands must carry. Unset refuses them, so
+    /// only the config file controls redactions. Masked in `/config`.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// PEM certificate chain and private key. With both set the server
+    /// speaks HTTPS, so clients connect with `wss://`.
+    #[serde(default)]
+    pub tls_cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key_path: Option<PathBuf>,
+    /// Distinct `set_quality` profiles encoded at once. Each costs an extra
+    /// JPEG encode per frame, so this bounds the CPU clients can demand.
+    #[serde(default = "default_max_client_profiles")]
+    pub max_client_profiles: usize,
+    /// How often each WebSocket client is pinged
+    #[serde(default = "default_ping_interval_ms")]
+    pub ping_interval_ms: u64,
+    /// Disconnect a WebSocket client that hasn't answered a ping for this
+    /// long. Checked on each ping, so it takes effect up to one
+    /// `ping_interval_ms` late. Zero never disconnects.
+    #[serde(default = "default_pong_timeout_ms")]
+    pub pong_timeout_ms: u64,
+    /// How often `/metrics/stream` sends a metrics event
+    #[serde(default = "default_metrics_stream_interval_ms")]
+    pub metrics_stream_interval_ms: u64,
+}
+
+fn default_ping_interval_ms() -> u64 {
+    30_000
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+fn default_pong_timeout_ms() -> u64 {
+    90_000
+}
+
+fn default_metrics_stream_interval_ms() -> u64 {
+    1000
+}
+
+fn default_session_ttl_ms() -> u64 {
+    30_000
+}
+
+fn default_max_client_profiles() -> usize {
+    4
+}
+
+fn default_shutdown_grace_ms() -> u64 {
+    5000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_queue_length() -> usize {
+    10
+}
+
+fn default_max_ws_message_bytes() -> usize {
+    // Matches tungstenite's default message limit
+    64 << 20
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CaptureConfig {
     pub fps: u32,
+    /// Frame size for the `demo` source
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub quality: f32,
+    /// Where frames come from, see `CaptureMode`
+    #[serde(default)]
+    pub mode: CaptureMode,
+    /// Image streamed in `file` mode
+    #[serde(default)]
+    pub source_file: Option<PathBuf>,
+    /// Window captured in `window` mode, as listed by `GET /windows`. Takes
+    /// precedence over `window_title`.
+    #[serde(default)]
+    pub window_id: Option<u32>,
+    /// In `window` mode, capture the first window whose title contains this
+    #[serde(default)]
+    pub window_title: Option<String>,
+    /// Monitor to capture, as listed by `GET /monitors`. Defaults to the
+    /// primary monitor, which is also used if the index is out of range.
+    #[serde(default)]
+    pub monitor_index: Option<usize>,
+    /// Capture every monitor into one frame laid out as on the virtual
+    /// desktop, with black wherever no monitor covers it. Replaces
+    /// `monitor_index` in the `auto` and `screen` modes.
+    #[serde(default)]
+    pub capture_all_monitors: bool,
+    /// Stream only this rectangle of the monitor, clamped to its edges
+    #[serde(default)]
+    pub region: Option<CaptureRegion>,
+    /// Downscale frames larger than this, keeping the aspect ratio
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    /// Resampling filter used when downscaling
+    #[serde(default)]
+    pub scale_filter: ScaleFilter,
+    /// Restart the capture task if no frame is produced for this many
+    /// seconds. Zero disables the watchdog.
+    #[serde(default = "default_watchdog_timeout")]
+    pub watchdog_timeout: u64,
+    /// What to show viewers when screen capture fails in `auto` mode
+    #[serde(default)]
+    pub fallback: FallbackFrame,
+    /// Extra attempts to find a monitor at startup, for when the server
+    /// starts before the display subsystem is ready
+    #[serde(default = "default_init_retries")]
+    pub init_retries: u32,
+    /// Delay before the first retry; doubles on each subsequent attempt
+    #[serde(default = "default_init_retry_delay_ms")]
+    pub init_retry_delay_ms: u64,
+    /// Re-initialize the capture source (e.g. find the monitor again) after
+    /// this many failed captures in a row, so a display that was unplugged
+    /// and plugged back in is picked up. Zero never re-initializes.
+    #[serde(default = "default_reinit_after_failures")]
+    pub reinit_after_failures: u32,
+    /// Minimum time between re-initialization attempts
+    #[serde(default = "default_reinit_interval_ms")]
+    pub reinit_interval_ms: u64,
+    /// Pause after a failed capture, doubling with each failure in a row
+    /// up to `error_backoff_max_ms`, with random jitter. Resets once a
+    /// capture succeeds.
+    #[serde(default = "default_error_backoff_base_ms")]
+    pub error_backoff_base_ms: u64,
+    #[serde(default = "default_error_backoff_max_ms")]
+    pub error_backoff_max_ms: u64,
+    /// Request 10/16-bit-per-channel capture. The xcap backend only
+    /// delivers 8-bit RGBA, so this currently falls back to 8-bit.
+    #[serde(default)]
+    pub high_bit_depth: bool,
+    /// Tone map sources with more than 8 bits per channel, e.g. an HDR
+    /// image in `file` mode, down to 8-bit sRGB instead of clipping them.
+    /// 8-bit sources, which includes everything xcap captures, are left
+    /// as they are.
+    #[serde(default)]
+    pub hdr_tonemap: HdrTonemap,
+    /// Pixel layout of frames sent as raw pixels, i.e. the `zstd` format.
+    /// The image formats always encode RGBA.
+    #[serde(default)]
+    pub color_format: ColorFormat,
+    /// Windows whose title contains any of these strings are blacked out of
+    /// the captured image, e.g. the browser tab showing the stream itself
+    #[serde(default)]
+    pub exclude_windows: Vec<String>,
+    /// Rectangles hidden from every frame, in source pixels, before
+    /// anything else sees it. Clients with `server.admin_token` can switch
+    /// them off and on with `set_redactions`.
+    #[serde(default)]
+    pub redactions: Vec<Redaction>,
+    /// Effects applied to every frame, in this order, after cropping and
+    /// scaling and before encoding
+    #[serde(default)]
+    pub processors: Vec<ProcessorConfig>,
+    /// Skip captures while no client could take another frame: nobody is
+    /// connected, or the slowest client is at `queue_high_water_mark`.
+    /// Saves CPU, but `/snapshot`, motion detection and `--record` also go
+    /// quiet while nobody is watching.
+    #[serde(default)]
+    pub throttle_on_backpressure: bool,
+    /// Stop capturing while no client is connected and start again as soon
+    /// as one connects, to save power. `/snapshot` then serves the last
+    /// frame from before capture stopped. Capture also starts idle, without
+    /// opening the display until the first client connects. Never idles
+    /// while recording, dumping frames or posting motion events to a
+    /// webhook.
+    #[serde(default = "default_true")]
+    pub idle_without_viewers: bool,
+    /// What the capture loop does when a frame takes longer than its slot
+    #[serde(default)]
+    pub overrun: OverrunBehavior,
+    /// Also stream audio from `audio_device`. Only in builds with the
+    /// `audio` feature.
+    #[serde(default)]
+    pub audio: bool,
+    /// Audio input device whose name contains this, e.g. a "Monitor of"
+    /// device for system audio. Defaults to the default input, usually the
+    /// microphone.
+    #[serde(default)]
+    pub audio_device: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScaleFilter {
+    /// Bilinear, fast enough for every frame
+    #[default]
+    Triangle,
+    /// Sharper, especially for text, but several times slower
+    Lanczos3,
+}
+
+/// Pixel layout of raw frames, reported to clients in
+/// `FrameHeader.color_format`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorFormat {
+    /// As captured
+    #[default]
+    Rgba,
+    /// Red and blue swapped, the layout many GPU texture uploads expect
+    Bgra,
+    /// Alpha dropped, a quarter fewer bytes before compression
+    Rgb,
+    /// One byte of BT.601 luma per pixel, for terminals and other mostly
+    /// monochrome content
+    Gray,
+}
+
+impl ColorFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            ColorFormat::Rgba | ColorFormat::Bgra => 4,
+            ColorFormat::Rgb => 3,
+            ColorFormat::Gray => 1,
+        }
+    }
+}
+
+/// Tone mapping operator for `capture.hdr_tonemap`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HdrTonemap {
+    /// Clip to 8 bits, losing anything brighter than SDR white
+    #[default]
+    Off,
+    /// Extended Reinhard, with the brightest pixel as white. Keeps hues
+    /// and rolls highlights off gently without clipping any.
+    Reinhard,
+    /// The ACES filmic curve per channel, with more contrast and slightly
+    /// desaturated highlights
+    Aces,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// One entry of `capture.processors`, picked by its `type`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProcessorConfig {
+    /// Darken every other row, like an old CRT
+    Scanlines {
+        /// How much darker the dark rows are, 0.0-1.0
+        #[serde(default = "default_scanline_intensity")]
+        intensity: f32,
+    },
+    /// Drop all color, keeping brightness
+    Grayscale,
+    /// Gaussian blur over one rectangle of the frame, clamped to its edges,
+    /// e.g. to keep a chat window unreadable
+    BlurRegion {
+        #[serde(flatten)]
+        region: CaptureRegion,
+        /// Blur radius (the Gaussian's sigma) in pixels
+        #[serde(default = "default_blur_sigma")]
+        sigma: f32,
+    },
+}
+
+/// One entry of `capture.redactions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redaction {
+    #[serde(flatten)]
+    pub region: CaptureRegion,
+    #[serde(default)]
+    pub style: RedactionStyle,
+}
+
+/// How a redacted rectangle is hidden
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactionStyle {
+    /// Solid black, leaving nothing to recover
+    #[default]
+    Fill,
+    /// A blur heavy enough to make text unreadable, while still showing
+    /// that something is there
+    Blur,
+}
+
+fn default_scanline_intensity() -> f32 {
+    0.3
+}
+
+fn default_blur_sigma() -> f32 {
+    12.0
+}
+
+/// A rectangle in monitor-local pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+fn default_init_retries() -> u32 {
+    5
+}
+
+fn default_init_retry_delay_ms() -> u64 {
+    500
+}
+
+fn default_reinit_after_failures() -> u32 {
+    10
+}
+
+fn default_reinit_interval_ms() -> u64 {
+    5000
+}
+
+fn default_error_backoff_base_ms() -> u64 {
+    100
+}
+
+fn default_error_backoff_max_ms() -> u64 {
+    5000
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureMode {
+    /// The screen, showing `fallback` frames whenever it can't be captured
+    #[default]
+    Auto,
+    /// The screen only. Startup fails if no monitor turns up within
+    /// `init_retries`, and later capture failures are counted as errors.
+    Screen,
+    /// The animated demo pattern, without ever touching the display
+    Demo,
+    /// The still image at `source_file`
+    File,
+    /// A single window, picked by `window_id` or `window_title`. Shows
+    /// `fallback` frames while it's minimized or closed.
+    Window,
+}
+
+impl CaptureMode {
+    /// Whether failed captures are papered over with `fallback` frames
+    /// rather than treated as errors
+    pub fn uses_fallback(self) -> bool {
+        matches!(self, CaptureMode::Auto | CaptureMode::Window)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackFrame {
+    /// Animated retro demo pattern
+    #[default]
+    Demo,
+    /// Colour-bar test card with a "NO SIGNAL" banner and timestamp
+    NoSignal,
+    /// Repeat the last successfully captured frame
+    LastFrame,
+    /// Solid black
+    Black,
+}
+
+/// How the capture loop catches up after a capture or encode overran its
+/// frame interval. Missed slots are counted in `capture_ticks_missed_total`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrunBehavior {
+    /// Drop the missed slots and carry on at the next one on the original
+    /// schedule, keeping a steady cadence
+    #[default]
+    Skip,
+    /// Drop the missed slots and restart the schedule from now, so the next
+    /// frame is a full interval after the late one
+    Delay,
+    /// Capture the missed slots back to back to keep the average rate,
+    /// at the cost of a CPU spike and uneven frame spacing
+    Burst,
+}
+
+fn default_watchdog_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CompressionConfig {
     pub level: i32,
     pub enabled: bool,
+    #[serde(default)]
+    pub format: CompressionFormat,
+    /// Send a full keyframe every N frames and only the pixels that changed
+    /// since it in between. Zero or one disables delta frames. Only applies
+    /// to the `zstd` format.
+    #[serde(default)]
+    pub keyframe_interval: u64,
+    /// Send a keyframe early when a delta would exceed this fraction of
+    /// the full frame size
+    #[serde(default = "default_delta_threshold")]
+    pub delta_threshold: f32,
+    /// Delta frames send the whole `tile_size`×`tile_size` pixel tiles
+    /// that changed since the keyframe instead of changed-pixel spans.
+    /// Cheaper to composite for clients. Zero keeps spans.
+    #[serde(default)]
+    pub tile_size: u32,
+    /// Frames encoded in parallel on worker threads, so encoding can keep
+    /// up at high resolutions and frame rates. Frames are still sent in
+    /// capture order. Each extra worker adds up to a frame of latency.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Put a CRC32 of each payload in the frame header, so clients can
+    /// detect frames corrupted in transit. Costs a pass over every payload.
+    #[serde(default)]
+    pub verify_checksums: bool,
+    /// With both set, tune the zstd level between these bounds as frames
+    /// are encoded, starting from `level`: lower it when encoding can't keep
+    /// up with the frame rate, raise it when there's time to spare.
+    #[serde(default)]
+    pub min_level: Option<i32>,
+    #[serde(default)]
+    pub max_level: Option<i32>,
+    /// A frame identical to the one before it is sent as a hold marker, a
+    /// header without payload, instead of in full. After this many holds in
+    /// a row a full keyframe is sent anyway, so clients that skipped frames
+    /// catch up. Zero disables hold markers.
+    #[serde(default = "default_max_hold_frames")]
+    pub max_hold_frames: u64,
+    /// zstd dictionary written by `--train-dict`, to compress frames of
+    /// repetitive content better. Clients fetch it from `GET /dictionary`.
+    #[serde(default)]
+    pub dictionary_path: Option<PathBuf>,
+    /// Encode `webp` frames losslessly instead of at `capture.quality`
+    #[serde(default)]
+    pub webp_lossless: bool,
+}
+
+fn default_delta_threshold() -> f32 {
+    0.5
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_max_hold_frames() -> u64 {
+    30
+}
+
+/// Payload encoding of each frame, reported to clients in `FrameHeader.format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionFormat {
+    /// Raw RGBA, zstd-compressed when `enabled` is set. With a
+    /// `keyframe_interval`, non-keyframes carry changed-pixel spans or tiles
+    /// instead.
+    #[default]
+    Zstd,
+    /// Self-contained PNG image. Browsers can decode it directly with
+    /// `createImageBitmap(new Blob([payload]))`, so no custom decoder is needed.
+    Png,
+    /// Baseline JPEG at `capture.quality`, alpha dropped. Much smaller than
+    /// the lossless formats for photographic content.
+    Jpeg,
+    /// WebP, lossy at `capture.quality` or lossless with `webp_lossless`.
+    /// Better quality per byte than JPEG on screen content, but not every
+    /// client can decode it.
+    Webp,
+}
+
+impl CompressionFormat {
+    /// Every format compiled into this binary
+    pub const ALL: &'static [CompressionFormat] = &[
+        CompressionFormat::Zstd,
+        CompressionFormat::Png,
+        CompressionFormat::Jpeg,
+        CompressionFormat::Webp,
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionConfig {
+    pub enabled: bool,
+    /// Fraction of sampled pixels (0.0-1.0) that must change to count as motion
+    pub sensitivity: f32,
+    /// Per-pixel luma difference (0-255) below which a change is ignored as noise
+    pub pixel_threshold: u8,
+    /// Minimum time between events, so one burst of motion is a single event
+    pub debounce_ms: u64,
+    /// Optional `http://` endpoint that receives each event as a JSON POST
+    pub webhook_url: Option<String>,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sensitivity: 0.01,
+            pixel_threshold: 24,
+            debounce_ms: 2000,
+            webhook_url: None,
+        }
+    }
 }
 
 impl Default for Config {
@@ -59,31 +686,121 @@ impl Default for Config {
                 host: "0.0.0.0".to_string(),
                 port: 8080,
                 max_connections: 10,
+                max_ws_message_bytes: default_max_ws_message_bytes(),
+                queue_when_full: false,
+                max_queue_length: default_max_queue_length(),
+                send_latest_on_connect: true,
+                max_frame_age_ms: 0,
+                shutdown_grace_ms: default_shutdown_grace_ms(),
+                pacing: false,
+                session_ttl_ms: default_session_ttl_ms(),
+                latest_frame_only: false,
+                max_bitrate_kbps: 0,
+                auth_token: None,
+                admin_token: None,
+                tls_cert_path: None,
+                tls_key_path: None,
+                max_client_profiles: default_max_client_profiles(),
+                ping_interval_ms: default_ping_interval_ms(),
+                pong_timeout_ms: default_pong_timeout_ms(),
+                metrics_stream_interval_ms: default_metrics_stream_interval_ms(),
             },
             capture: CaptureConfig {
                 fps: 30,
                 width: None,
                 height: None,
                 quality: 0.8,
+                mode: CaptureMode::default(),
+                window_id: None,
+                window_title: None,
+                source_file: None,
+                monitor_index: None,
+                capture_all_monitors: false,
+                region: None,
+                max_width: None,
+                max_height: None,
+                scale_filter: ScaleFilter::default(),
+                watchdog_timeout: default_watchdog_timeout(),
+                fallback: FallbackFrame::default(),
+                init_retries: default_init_retries(),
+                init_retry_delay_ms: default_init_retry_delay_ms(),
+                reinit_after_failures: default_reinit_after_failures(),
+                reinit_interval_ms: default_reinit_interval_ms(),
+                error_backoff_base_ms: default_error_backoff_base_ms(),
+                error_backoff_max_ms: default_error_backoff_max_ms(),
+                high_bit_depth: false,
+                hdr_tonemap: HdrTonemap::default(),
+                color_format: ColorFormat::default(),
+                exclude_windows: Vec::new(),
+                redactions: Vec::new(),
+                processors: Vec::new(),
+                throttle_on_backpressure: false,
+                idle_without_viewers: true,
+                overrun: OverrunBehavior::default(),
+                audio: false,
+                audio_device: None,
             },
             compression: CompressionConfig {
                 level: 3,
                 enabled: true,
+                format: CompressionFormat::default(),
+                keyframe_interval: 0,
+                delta_threshold: default_delta_threshold(),
+                tile_size: 0,
+                workers: default_workers(),
+                verify_checksums: false,
+                min_level: None,
+                max_level: None,
+                max_hold_frames: default_max_hold_frames(),
+                dictionary_path: None,
+                webp_lossless: false,
             },
             buffer_size: 10,
+            queue_high_water_mark: None,
+            overflow_strategy: OverflowStrategy::default(),
+            motion: MotionConfig::default(),
+            log_format: LogFormat::default(),
         }
     }
 }
 
 impl Config {
+    /// Resolve the config from, lowest precedence first: defaults, the
+    /// config file, environment variables (see `ENV_PREFIX`), `--preset`
+    /// and the other command line flags.
     pub fn load(args: &Args) -> Result<Self> {
-        let mut config = if args.config.exists() {
-            let content = std::fs::read_to_string(&args.config)?;
-            toml::from_str(&content)?
+        let base = if args.config.exists() {
+            info!("Loading config from {}", args.config.display());
+            let file = config::File::from(args.config.as_path()).format(config::FileFormat::Toml);
+            config::Config::builder().add_source(file)
         } else {
-            Self::default()
+            info!("{} not found, using default config", args.config.display());
+            config::Config::builder().add_source(config::Config::try_from(&Self::default())?)
         };
 
+        let mut from_env: Vec<String> = std::env::vars()
+            .map(|(name, _)| name)
+            .filter(|name| name.starts_with(&format!("{}_", ENV_PREFIX)))
+            .collect();
+        from_env.sort();
+        if !from_env.is_empty() {
+            info!("Config overridden from environment: {}", from_env.join(", "));
+        }
+
+        let mut config: Config = base
+            .add_source(
+                config::Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?
+            .try_deserialize()?;
+
+        if let Some(name) = &args.preset {
+            preset::load(&args.presets_file, name)?.apply(&mut config);
+            info!("Applied preset '{}'", name);
+        }
+
         // Override with command line arguments
         if let Some(port) = args.port {
             config.server.port = port;
@@ -94,11 +811,330 @@ impl Config {
         if let Some(compression) = args.compression {
             config.compression.level = compression;
         }
+        if let Some(log_format) = args.log_format {
+            config.log_format = log_format;
+        }
+        let from_args: Vec<&str> = [
+            ("--port", args.port.is_some()),
+            ("--fps", args.fps.is_some()),
+            ("--compression", args.compression.is_some()),
+            ("--log-format", args.log_format.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(flag, set)| set.then_some(flag))
+        .collect();
+        if !from_args.is_empty() {
+            info!("Config overridden from command line: {}", from_args.join(", "));
+        }
+
+        config.validate()?;
+
+        if let Some(name) = &args.save_preset {
+            preset::save(&args.presets_file, name, Preset::from_config(&config))?;
+            info!("Saved preset '{}' to {}", name, args.presets_file.display());
+        }
 
         Ok(config)
     }
 
-    pub fn frame_interval_ms(&self) -> u64 {
-        1000 / self.capture.fps as u64
+    /// Reject values that would misbehave at runtime, naming the field.
+    pub fn validate(&self) -> AppResult<()> {
+        let invalid = |field: &str, value: &dyn std::fmt::Display, expected: &str| {
+            Err(AppError::ConfigError(format!(
+                "{} = {} is invalid, expected {}",
+                field, value, expected
+            )))
+        };
+
+        if !(1..=240).contains(&self.capture.fps) {
+            return invalid("capture.fps", &self.capture.fps, "1-240");
+        }
+        if !(0.0..=1.0).contains(&self.capture.quality) {
+            return invalid("capture.quality", &self.capture.quality, "0.0-1.0");
+        }
+        let levels = zstd::compression_level_range();
+        if !levels.contains(&self.compression.level) {
+            let expected = format!("{}-{}", levels.start(), levels.end());
+            return invalid("compression.level", &self.compression.level, &expected);
+        }
+        match (self.compression.min_level, self.compression.max_level) {
+            (Some(min), Some(max)) => {
+                let expected = format!("{}-{}", levels.start(), levels.end());
+                if !levels.contains(&min) {
+                    return invalid("compression.min_level", &min, &expected);
+                }
+                if !levels.contains(&max) {
+                    return invalid("compression.max_level", &max, &expected);
+                }
+                if min > max {
+                    return invalid("compression.min_level", &min, "at most compression.max_level");
+                }
+            }
+            (None, None) => {}
+            _ => {
+                return Err(AppError::ConfigError(
+                    "compression.min_level and compression.max_level must be set together".to_string(),
+                ))
+            }
+        }
+        if self.capture.mode == CaptureMode::File && self.capture.source_file.is_none() {
+            return Err(AppError::ConfigError(
+                "capture.mode = \"file\" needs capture.source_file".to_string(),
+            ));
+        }
+        if self.capture.mode == CaptureMode::Window
+            && self.capture.window_id.is_none()
+            && self.capture.window_title.is_none()
+        {
+            return Err(AppError::ConfigError(
+                "capture.mode = \"window\" needs capture.window_id or capture.window_title".to_string(),
+            ));
+        }
+        if self.capture.color_format != ColorFormat::Rgba && self.compression.format != CompressionFormat::Zstd {
+            return Err(AppError::ConfigError(
+                "capture.color_format only applies to compression.format = \"zstd\", leave it at \"rgba\""
+                    .to_string(),
+            ));
+        }
+        if self.capture.error_backoff_max_ms < self.capture.error_backoff_base_ms {
+            let value = self.capture.error_backoff_max_ms;
+            return invalid("capture.error_backoff_max_ms", &value, "at least capture.error_backoff_base_ms");
+        }
+        for processor in &self.capture.processors {
+            match *processor {
+                ProcessorConfig::Scanlines { intensity } if !(0.0..=1.0).contains(&intensity) => {
+                    return invalid("capture.processors scanlines intensity", &intensity, "0.0-1.0");
+                }
+                ProcessorConfig::BlurRegion { sigma, .. } if sigma <= 0.0 => {
+                    return invalid("capture.processors blur_region sigma", &sigma, "more than 0");
+                }
+                _ => {}
+            }
+        }
+        if self.compression.workers == 0 {
+            return invalid("compression.workers", &self.compression.workers, "at least 1");
+        }
+        if self.server.ping_interval_ms == 0 {
+            return invalid("server.ping_interval_ms", &self.server.ping_interval_ms, "at least 1");
+        }
+        if self.server.metrics_stream_interval_ms == 0 {
+            let value = self.server.metrics_stream_interval_ms;
+            return invalid("server.metrics_stream_interval_ms", &value, "at least 1");
+        }
+        if self.server.tls_cert_path.is_some() != self.server.tls_key_path.is_some() {
+            return Err(AppError::ConfigError(
+                "server.tls_cert_path and server.tls_key_path must be set together".to_string(),
+            ));
+        }
+        if self.buffer_size == 0 {
+            return invalid("buffer_size", &self.buffer_size, "at least 1");
+        }
+
+        Ok(())
+    }
+
+    /// Merge a freshly loaded config into the running one. Only
+    /// `capture.fps`, `capture.quality`, `compression.level` and
+    /// `compression.enabled` can change while running; anything else is
+    /// logged and ignored until restart.
+    pub fn reloaded(&self, new: &Config) -> Config {
+        let mut merged = self.clone();
+        merged.capture.fps = new.capture.fps;
+        merged.capture.quality = new.capture.quality;
+        merged.compression.level = new.compression.level;
+        merged.compression.enabled = new.compression.enabled;
+
+        let ignored: Vec<&str> = [
+            ("server", merged.server != new.server),
+            ("capture", merged.capture != new.capture),
+            ("compression", merged.compression != new.compression),
+            ("motion", merged.motion != new.motion),
+            ("buffer_size", merged.buffer_size != new.buffer_size),
+            ("queue_high_water_mark", merged.queue_high_water_mark != new.queue_high_water_mark),
+            ("overflow_strategy", merged.overflow_strategy != new.overflow_strategy),
+            ("log_format", merged.log_format != new.log_format),
+        ]
+        .into_iter()
+        .filter_map(|(section, changed)| changed.then_some(section))
+        .collect();
+        if !ignored.is_empty() {
+            warn!(
+                "Changes to {} require a restart and were ignored",
+                ignored.join(", ")
+            );
+        }
+
+        merged
+    }
+
+    /// A copy safe to show to clients, with secrets masked
+    pub fn redacted(&self) -> Config {
+        let mut redacted = self.clone();
+        if redacted.server.auth_token.is_some() {
+            redacted.server.auth_token = Some("***".to_string());
+        }
+        if redacted.server.admin_token.is_some() {
+            redacted.server.admin_token = Some("***".to_string());
+        }
+        redacted
+    }
+
+    /// Time between captures, exact to the nanosecond so rates like 60 or
+    /// 24 FPS don't drift
+    pub fn frame_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(1.0 / self.capture.fps as f64)
+    }
+
+    pub fn queue_high_water_mark(&self) -> usize {
+        self.queue_high_water_mark
+            .unwrap_or(self.buffer_size * 3 / 4)
+            .max(1)
+    }
+
+    pub fn watchdog_timeout(&self) -> Option<std::time::Duration> {
+        (self.capture.watchdog_timeout > 0)
+            .then(|| std::time::Duration::from_secs(self.capture.watchdog_timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(configure: impl FnOnce(&mut Config)) -> AppResult<()> {
+        let mut config = Config::default();
+        configure(&mut config);
+        config.validate()
+    }
+
+    /// Assert the config is rejected with an error naming `field`
+    fn assert_rejected(field: &str, configure: impl FnOnce(&mut Config)) {
+        match validate(configure) {
+            Err(AppError::ConfigError(message)) => {
+                assert!(message.contains(field), "{:?} doesn't name {}", message, field)
+            }
+            other => panic!("expected {} to be rejected, got {:?}", field, other),
+        }
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn fps_bounds() {
+        assert_rejected("capture.fps", |c| c.capture.fps = 0);
+        assert!(validate(|c| c.capture.fps = 1).is_ok());
+        assert!(validate(|c| c.capture.fps = 240).is_ok());
+        assert_rejected("capture.fps", |c| c.capture.fps = 241);
+    }
+
+    #[test]
+    fn quality_bounds() {
+        assert_rejected("capture.quality", |c| c.capture.quality = -0.01);
+        assert!(validate(|c| c.capture.quality = 0.0).is_ok());
+        assert!(validate(|c| c.capture.quality = 1.0).is_ok());
+        assert_rejected("capture.quality", |c| c.capture.quality = 1.01);
+        assert_rejected("capture.quality", |c| c.capture.quality = f32::NAN);
+    }
+
+    #[test]
+    fn compression_level_bounds() {
+        let levels = zstd::compression_level_range();
+        assert!(validate(|c| c.compression.level = *levels.start()).is_ok());
+        assert!(validate(|c| c.compression.level = *levels.end()).is_ok());
+        assert_rejected("compression.level", |c| c.compression.level = levels.start() - 1);
+        assert_rejected("compression.level", |c| c.compression.level = levels.end() + 1);
+    }
+
+    #[test]
+    fn adaptive_level_bounds() {
+        assert!(validate(|c| (c.compression.min_level, c.compression.max_level) = (Some(1), Some(1))).is_ok());
+        assert_rejected("compression.min_level", |c| {
+            (c.compression.min_level, c.compression.max_level) = (Some(5), Some(4))
+        });
+        assert_rejected("compression.max_level", |c| c.compression.min_level = Some(1));
+        assert_rejected("compression.max_level", |c| {
+            (c.compression.min_level, c.compression.max_level) = (Some(1), Some(99))
+        });
+    }
+
+    #[test]
+    fn buffer_size_bounds() {
+        assert_rejected("buffer_size", |c| c.buffer_size = 0);
+        assert!(validate(|c| c.buffer_size = 1).is_ok());
+    }
+
+    #[test]
+    fn other_lower_bounds() {
+        assert_rejected("compression.workers", |c| c.compression.workers = 0);
+        assert_rejected("server.ping_interval_ms", |c| c.server.ping_interval_ms = 0);
+        assert_rejected("server.metrics_stream_interval_ms", |c| c.server.metrics_stream_interval_ms = 0);
+        assert_rejected("capture.error_backoff_max_ms", |c| {
+            c.capture.error_backoff_max_ms = c.capture.error_backoff_base_ms - 1
+        });
+        assert!(validate(|c| c.capture.error_backoff_max_ms = c.capture.error_backoff_base_ms).is_ok());
+    }
+
+    #[test]
+    fn processor_bounds() {
+        assert!(validate(|c| c.capture.processors = vec![ProcessorConfig::Scanlines { intensity: 1.0 }]).is_ok());
+        assert_rejected("scanlines intensity", |c| {
+            c.capture.processors = vec![ProcessorConfig::Scanlines { intensity: 1.5 }]
+        });
+        let region = CaptureRegion { x: 0, y: 0, width: 10, height: 10 };
+        assert_rejected("blur_region sigma", |c| {
+            c.capture.processors = vec![ProcessorConfig::BlurRegion { region, sigma: 0.0 }]
+        });
+    }
+
+    #[test]
+    fn settings_that_need_each_other() {
+        assert_rejected("capture.source_file", |c| c.capture.mode = CaptureMode::File);
+        assert_rejected("capture.window_id", |c| c.capture.mode = CaptureMode::Window);
+        assert!(validate(|c| {
+            c.capture.mode = CaptureMode::Window;
+            c.capture.window_title = Some("Terminal".to_string());
+        })
+        .is_ok());
+        assert_rejected("capture.color_format", |c| {
+            c.capture.color_format = ColorFormat::Gray;
+            c.compression.format = CompressionFormat::Png;
+        });
+        assert_rejected("server.tls_key_path", |c| c.server.tls_cert_path = Some("cert.pem".into()));
+    }
+
+    #[test]
+    fn frame_interval_is_exact_at_60_fps() {
+        let mut config = Config::default();
+        config.capture.fps = 60;
+        let interval = config.frame_interval();
+        let expected = std::time::Duration::from_nanos(16_666_667);
+        assert!(interval.abs_diff(expected) < std::time::Duration::from_micros(1), "{:?}", interval);
+    }
+
+    #[test]
+    fn env_overrides_the_file_but_not_the_command_line() {
+        let mut file_config = Config::default();
+        file_config.server.port = 8100;
+        file_config.capture.fps = 10;
+        file_config.compression.level = 7;
+        let path = std::env::temp_dir().join(format!("retrostream-{}-env.toml", std::process::id()));
+        std::fs::write(&path, toml::to_string(&file_config).unwrap()).unwrap();
+
+        // The only test touching RETROSTREAM_* variables, so it can't race
+        std::env::set_var("RETROSTREAM_SERVER__PORT", "9100");
+        std::env::set_var("RETROSTREAM_CAPTURE__FPS", "20");
+        let args = Args::parse_from(["retrostream", "--config", path.to_str().unwrap(), "--fps", "30"]);
+        let loaded = Config::load(&args);
+        std::env::remove_var("RETROSTREAM_SERVER__PORT");
+        std::env::remove_var("RETROSTREAM_CAPTURE__FPS");
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.server.port, 9100);
+        assert_eq!(loaded.capture.fps, 30);
+        assert_eq!(loaded.compression.level, 7);
     }
 }
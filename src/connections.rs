@@ -0,0 +1,187 @@
+use crate::clock;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+/// Open WebSocket connections, for `GET /connections`
+#[derive(Default)]
+pub struct Connections {
+    open: Mutex<HashMap<SocketAddr, Arc<Connection>>>,
+}
+
+/// One open connection, kept up to date by its `handle_client`
+pub struct Connection {
+    remote_addr: SocketAddr,
+    /// ms since the Unix epoch, by `clock::now_ms`
+    connected_at: u64,
+    frames_delivered: AtomicU64,
+    bytes_sent: AtomicU64,
+    paused: AtomicBool,
+    /// Connected with `?pull=true`, only sent frames on `grab`
+    pull: bool,
+}
+
+/// A connection as listed by `GET /connections`. The session id is left
+/// out, since it's what lets a client resume the session.
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    pub connected_at: u64,
+    pub frames_delivered: u64,
+    /// Frame and audio bytes written to the socket
+    pub bytes_sent: u64,
+    pub state: ConnectionState,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Active,
+    /// Sent `pause`
+    Paused,
+    /// Only sent frames it asks for with `grab`
+    Pull,
+}
+
+impl Connections {
+    /// Register a connection from `remote_addr` until the returned guard
+    /// is dropped.
+    pub fn open(self: &Arc<Self>, remote_addr: SocketAddr, pull: bool) -> Registered {
+        let connection = Arc::new(Connection {
+            remote_addr,
+            connected_at: clock::now_ms(),
+            frames_delivered: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            pull,
+        });
+        self.open.lock().unwrap().insert(remote_addr, connection.clone());
+        Registered {
+            connections: self.clone(),
+            connection,
+        }
+    }
+
+    /// Open connections, oldest first
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut list: Vec<ConnectionInfo> = self
+            .open
+            .lock()
+            .unwrap()
+            .values()
+            .map(|connection| ConnectionInfo {
+                remote_addr: connection.remote_addr,
+                connected_at: connection.connected_at,
+                frames_delivered: connection.frames_delivered.load(Ordering::Relaxed),
+                bytes_sent: connection.bytes_sent.load(Ordering::Relaxed),
+                state: if connection.paused.load(Ordering::Relaxed) {
+                    ConnectionState::Paused
+                } else if connection.pull {
+                    ConnectionState::Pull
+                } else {
+                    ConnectionState::Active
+                },
+            })
+            .collect();
+        list.sort_by_key(|info| info.connected_at);
+        list
+    }
+}
+
+impl Connection {
+    pub fn record_frame_delivered(&self) {
+        self.frames_delivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Also count them in `Metrics::add_bytes_sent`
+    pub fn record_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_pull(&self) -> bool {
+        self.pull
+    }
+}
+
+/// Keeps a connection in `Connections` for as long as it's held, however
+/// the connection ends
+pub struct Registered {
+    connections: Arc<Connections>,
+    connection: Arc<Connection>,
+}
+
+impl Registered {
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.connections
+            .open
+            .lock()
+            .unwrap()
+            .remove(&self.connection.remote_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compression::Compressor, testing};
+    use std::time::Duration;
+
+    async fn list(state: &crate::AppState) -> Vec<serde_json::Value> {
+        let body = testing::body(testing::get(state, "/connections").await).await;
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connection_is_listed_until_it_closes() {
+        let state = testing::state(|_| {});
+        assert!(list(&state).await.is_empty());
+
+        let mut client = testing::connect(testing::serve(&state).await, "").await;
+        testing::next_json(&mut client).await;
+        while state.frame_tx.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        let mut compression = crate::config::Config::default().compression;
+        compression.enabled = false;
+        let frame = Compressor::new(compression, 0.8, 30, Arc::new(AtomicU64::new(0)))
+            .create_frame_message(vec![0; 8 * 8 * 4], 8, 8)
+            .unwrap();
+        state.frame_tx.send(frame.clone()).await.unwrap();
+        testing::next_message(&mut client).await;
+
+        let connections = list(&state).await;
+        assert_eq!(connections.len(), 1);
+        let connection = &connections[0];
+        let local_addr = match client.get_ref() {
+            tokio_tungstenite::MaybeTlsStream::Plain(stream) => stream.local_addr().unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(connection["remote_addr"], local_addr.to_string());
+        assert_eq!(connection["frames_delivered"], 1);
+        assert_eq!(connection["bytes_sent"], frame.len());
+        assert_eq!(connection["state"], "active");
+        assert!(connection["connected_at"].as_u64().unwrap() <= clock::now_ms());
+        assert!(connection.get("session").is_none());
+
+        client.close(None).await.unwrap();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while !list(&state).await.is_empty() {
+            assert!(tokio::time::Instant::now() < deadline, "connection still listed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
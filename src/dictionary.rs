@@ -0,0 +1,125 @@
+use crate::error::{AppError, AppResult};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Size of dictionaries written by `train`, zstd's own default
+pub const DICTIONARY_SIZE: usize = 112_640;
+
+/// Frames are cut into samples of this size, one zstd block, since zstd
+/// trains on many small samples rather than a few frame-sized ones
+const SAMPLE_LEN: usize = 128 * 1024;
+
+/// Train a zstd dictionary for `compression.dictionary_path` on the images
+/// in `samples_dir`, e.g. frames written by `--dump-frames`. Training runs
+/// on their raw RGBA, as the `zstd` format compresses it.
+pub fn train(samples_dir: &Path) -> AppResult<Vec<u8>> {
+    let mut paths = std::fs::read_dir(samples_dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    let mut frames = Vec::new();
+    for path in paths.iter().filter(|p| p.is_file()) {
+        match image::open(path) {
+            Ok(image) => frames.push(image.into_rgba8().into_raw()),
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    if frames.is_empty() {
+        return Err(AppError::ConfigError(format!(
+            "No sample frames found in {}",
+            samples_dir.display()
+        )));
+    }
+
+    let samples: Vec<&[u8]> = frames.iter().flat_map(|f| f.chunks(SAMPLE_LEN)).collect();
+    info!(
+        "Training a dictionary on {} frames ({} samples)",
+        frames.len(),
+        samples.len()
+    );
+    zstd::dict::from_samples(&samples, DICTIONARY_SIZE)
+        .map_err(|e| AppError::CompressionError(format!("Dictionary training failed: {}", e)))
+}
+
+/// Read a dictionary written by `train`.
+pub fn load(path: &Path) -> AppResult<Arc<[u8]>> {
+    let dictionary = std::fs::read(path).map_err(|e| {
+        AppError::ConfigError(format!("Failed to read dictionary {}: {}", path.display(), e))
+    })?;
+    info!("Loaded {} byte zstd dictionary from {}", dictionary.len(), path.display());
+    Ok(dictionary.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{compress_into, compress_with_dictionary_into, decompress_with_dictionary};
+
+    const SIZE: u32 = 32;
+
+    /// Like a terminal: 8x8 glyphs out of a set of 64, arranged differently
+    /// in every frame, so a frame alone has little to reuse but frames
+    /// share a lot
+    fn frame(seed: u64) -> Vec<u8> {
+        // xorshift64
+        fn mix(mut state: u64) -> u64 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }
+        let mut state = mix(seed + 1);
+        let glyphs: Vec<u64> = (0..(SIZE / 8) * (SIZE / 8))
+            .map(|_| {
+                state = mix(state);
+                state % 64 + 1
+            })
+            .collect();
+
+        let mut rgba = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let glyph = glyphs[((y / 8) * (SIZE / 8) + x / 8) as usize];
+                let [r, g, b, ..] = mix(glyph * 64 + (y % 8 * 8 + x % 8) as u64).to_le_bytes();
+                rgba.extend_from_slice(&[r, g, b, 255]);
+            }
+        }
+        rgba
+    }
+
+    #[test]
+    fn trained_dictionary_beats_no_dictionary() {
+        let dir = std::env::temp_dir().join(format!("retrostream-{}-dictionary", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for seed in 0..200 {
+            let image = image::RgbaImage::from_raw(SIZE, SIZE, frame(seed)).unwrap();
+            image.save(dir.join(format!("frame-{:04}.png", seed))).unwrap();
+        }
+        let dictionary = train(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let dictionary = dictionary.unwrap();
+
+        let unseen = frame(1000);
+        let (mut plain, mut with_dictionary) = (Vec::new(), Vec::new());
+        compress_into(&unseen, 3, &mut plain).unwrap();
+        compress_with_dictionary_into(&unseen, 3, &dictionary, &mut with_dictionary).unwrap();
+        assert!(
+            with_dictionary.len() < plain.len() * 3 / 4,
+            "{} bytes with the dictionary, {} without",
+            with_dictionary.len(),
+            plain.len()
+        );
+        assert_eq!(decompress_with_dictionary(&with_dictionary, &dictionary).unwrap(), unseen);
+    }
+
+    #[test]
+    fn training_needs_samples() {
+        let dir = std::env::temp_dir().join(format!("retrostream-{}-no-samples", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let result = train(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(AppError::ConfigError(_))));
+    }
+}
@@ -0,0 +1,46 @@
+use crate::error::{AppError, AppResult};
+use std::path::PathBuf;
+use tracing::info;
+
+/// Writes captured frames to disk as a numbered PNG sequence
+/// (`frame_000001.png`, `frame_000002.png`, ...).
+pub struct FrameDumper {
+    dir: PathBuf,
+    limit: u64,
+    written: u64,
+}
+
+impl FrameDumper {
+    pub fn new(dir: PathBuf, limit: u64) -> AppResult<Self> {
+        std::fs::create_dir_all(&dir)?;
+        info!("Dumping up to {} frames to {}", limit, dir.display());
+
+        Ok(Self {
+            dir,
+            limit,
+            written: 0,
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.written >= self.limit
+    }
+
+    pub fn write(&mut self, rgba: &[u8], width: u32, height: u32) -> AppResult<()> {
+        if self.is_done() {
+            return Ok(());
+        }
+
+        let path = self.dir.join(format!("frame_{:06}.png", self.written + 1));
+        image::save_buffer(&path, rgba, width, height, image::ColorType::Rgba8).map_err(|e| {
+            AppError::CaptureError(format!("Failed to write {}: {}", path.display(), e))
+        })?;
+
+        self.written += 1;
+        if self.is_done() {
+            info!("Frame dump complete: {} frames written to {}", self.written, self.dir.display());
+        }
+
+        Ok(())
+    }
+}
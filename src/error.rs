@@ -3,18 +3,18 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Screen capture error: {0}")]
-    #[allow(dead_code)]
     CaptureError(String),
     
+    #[error("No monitor available for capture")]
+    NoMonitorAvailable,
+    
     #[error("Compression error: {0}")]
     CompressionError(String),
     
-    #[error("WebSocket error: {0}")]
-    #[allow(dead_code)]
-    WebSocketError(String),
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
     
     #[error("Configuration error: {0}")]
-    #[allow(dead_code)]
     ConfigError(String),
     
     #[error("IO error: {0}")]
@@ -25,3 +25,32 @@ pub enum AppError {
 }
 
 pub type AppResult<T> = Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_names_the_cause() {
+        let cases = [
+            (AppError::CaptureError("display gone".to_string()), "Screen capture error: display gone"),
+            (AppError::NoMonitorAvailable, "No monitor available for capture"),
+            (AppError::CompressionError("bad level".to_string()), "Compression error: bad level"),
+            (AppError::ProtocolError("bad magic".to_string()), "Protocol error: bad magic"),
+            (AppError::ConfigError("capture.fps = 0".to_string()), "Configuration error: capture.fps = 0"),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(error.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn wrapped_errors_keep_their_message() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "config.toml");
+        assert_eq!(AppError::from(io).to_string(), "IO error: config.toml");
+
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let message = json.to_string();
+        assert_eq!(AppError::from(json).to_string(), format!("Serialization error: {}", message));
+    }
+}
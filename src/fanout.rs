@@ -0,0 +1,343 @@
+use crate::config::OverflowStrategy;
+use std::collections::VecDeque;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::Notify;
+
+/// A channel delivering every message to every receiver, like
+/// `tokio::sync::broadcast`, but with a choice of what happens when a
+/// receiver's queue is full, see `OverflowStrategy`. Receivers are added
+/// with `Sender::subscribe`.
+pub fn channel<T: Clone>(capacity: usize, strategy: OverflowStrategy) -> Sender<T> {
+    Sender {
+        shared: Arc::new(Shared {
+            capacity: capacity.max(1),
+            strategy,
+            inboxes: Mutex::new(Vec::new()),
+            senders: AtomicUsize::new(1),
+            space: Notify::new(),
+        }),
+    }
+}
+
+struct Shared<T> {
+    capacity: usize,
+    strategy: OverflowStrategy,
+    inboxes: Mutex<Vec<Arc<Inbox<T>>>>,
+    senders: AtomicUsize,
+    /// Woken whenever a receiver takes a message or goes away, for `Block`
+    space: Notify,
+}
+
+/// One receiver's queue
+struct Inbox<T> {
+    queue: Mutex<Queue<T>>,
+    /// Woken when a message arrives or the last sender goes away
+    ready: Notify,
+}
+
+struct Queue<T> {
+    entries: VecDeque<Entry<T>>,
+    /// Messages in `entries`, not counting gaps
+    len: usize,
+    closed: bool,
+}
+
+enum Entry<T> {
+    Message(T),
+    /// This many messages were dropped here, reported as `Lagged`
+    Gap(u64),
+}
+
+impl<T> Queue<T> {
+    /// Drop the message about to arrive, for `DropNewest`
+    fn drop_newest(&mut self) {
+        match self.entries.back_mut() {
+            Some(Entry::Gap(skipped)) => *skipped += 1,
+            _ => self.entries.push_back(Entry::Gap(1)),
+        }
+    }
+
+    /// Drop the oldest queued message, for `DropOldest`, merged with the
+    /// gaps on either side so the receiver sees a single `Lagged`
+    fn drop_oldest(&mut self) {
+        let mut skipped = 1;
+        if let Some(Entry::Gap(n)) = self.entries.front() {
+            skipped += n;
+            self.entries.pop_front();
+        }
+        self.entries.pop_front();
+        self.len -= 1;
+        if let Some(Entry::Gap(n)) = self.entries.front() {
+            skipped += n;
+            self.entries.pop_front();
+        }
+        self.entries.push_front(Entry::Gap(skipped));
+    }
+}
+
+/// Sends to every receiver. Cloning adds another sender; receivers see
+/// `Closed` once all are dropped.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The message could not be sent because there are no receivers.
+#[derive(Debug)]
+pub struct SendError<T>(pub T);
+
+impl<T: Clone> Sender<T> {
+    /// Queue `message` for every receiver, returning how many there are.
+    /// With `Block` this waits until every receiver has room.
+    pub async fn send(&self, message: T) -> Result<usize, SendError<T>> {
+        loop {
+            // Registered before checking for room, so a receiver making
+            // room in between still wakes us
+            let space = self.shared.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            let inboxes = self.shared.inboxes.lock().unwrap().clone();
+            if inboxes.is_empty() {
+                return Err(SendError(message));
+            }
+            let full = |inbox: &Arc<Inbox<T>>| inbox.queue.lock().unwrap().len >= self.shared.capacity;
+            if self.shared.strategy == OverflowStrategy::Block && inboxes.iter().any(full) {
+                space.await;
+                continue;
+            }
+
+            for inbox in &inboxes {
+                let mut queue = inbox.queue.lock().unwrap();
+                if queue.len >= self.shared.capacity {
+                    match self.shared.strategy {
+                        OverflowStrategy::DropNewest => {
+                            queue.drop_newest();
+                            continue;
+                        }
+                        // With `Block`, only another sender can have
+                        // filled the queue since the check above
+                        OverflowStrategy::DropOldest | OverflowStrategy::Block => queue.drop_oldest(),
+                    }
+                }
+                queue.entries.push_back(Entry::Message(message.clone()));
+                queue.len += 1;
+                drop(queue);
+                inbox.ready.notify_one();
+            }
+            return Ok(inboxes.len());
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    pub fn subscribe(&self) -> Receiver<T> {
+        let inbox = Arc::new(Inbox {
+            queue: Mutex::new(Queue {
+                entries: VecDeque::new(),
+                len: 0,
+                closed: false,
+            }),
+            ready: Notify::new(),
+        });
+        self.shared.inboxes.lock().unwrap().push(inbox.clone());
+        Receiver {
+            shared: self.shared.clone(),
+            inbox,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.inboxes.lock().unwrap().len()
+    }
+
+    /// Messages queued for the slowest receiver
+    pub fn len(&self) -> usize {
+        let inboxes = self.shared.inboxes.lock().unwrap();
+        inboxes
+            .iter()
+            .map(|inbox| inbox.queue.lock().unwrap().len)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+        for inbox in self.shared.inboxes.lock().unwrap().iter() {
+            inbox.queue.lock().unwrap().closed = true;
+            inbox.ready.notify_one();
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// This many messages were dropped before the next one
+    Lagged(u64),
+    /// Every sender is gone
+    Closed,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Lagged(u64),
+    Closed,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    inbox: Arc<Inbox<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The next message, or where messages were dropped. Cancel safe.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        let inbox = self.inbox.clone();
+        loop {
+            // A message sent after the check below leaves a permit behind
+            let ready = inbox.ready.notified();
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Lagged(skipped)) => return Err(RecvError::Lagged(skipped)),
+                Err(TryRecvError::Closed) => return Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => ready.await,
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut queue = self.inbox.queue.lock().unwrap();
+        let result = match queue.entries.pop_front() {
+            Some(Entry::Message(message)) => {
+                queue.len -= 1;
+                Ok(message)
+            }
+            Some(Entry::Gap(skipped)) => Err(TryRecvError::Lagged(skipped)),
+            None if queue.closed => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        };
+        drop(queue);
+
+        if result.is_ok() && self.shared.strategy == OverflowStrategy::Block {
+            self.shared.space.notify_waiters();
+        }
+        result
+    }
+
+    /// Whether no message is queued
+    pub fn is_empty(&self) -> bool {
+        self.inbox.queue.lock().unwrap().len == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared
+            .inboxes
+            .lock()
+            .unwrap()
+            .retain(|inbox| !Arc::ptr_eq(inbox, &self.inbox));
+        // A sender blocked on this receiver can go on without it
+        self.shared.space.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::FrameMessage;
+
+    #[tokio::test]
+    async fn subscribers_share_one_frame() {
+        let tx = channel::<FrameMessage>(4, OverflowStrategy::DropOldest);
+        let mut receivers: Vec<_> = (0..3).map(|_| tx.subscribe()).collect();
+
+        let frame: FrameMessage = vec![7; 1 << 20].into();
+        assert_eq!(tx.send(frame.clone()).await.unwrap(), 3);
+
+        for rx in &mut receivers {
+            let received = rx.recv().await.unwrap();
+            assert!(Arc::ptr_eq(&received, &frame));
+        }
+        // The test's handle, plus nothing left queued
+        assert_eq!(Arc::strong_count(&frame), 1);
+    }
+
+    /// A receiver kept up to date, and one that reads nothing until the
+    /// sender is done, on a channel with room for two messages
+    async fn slow_consumer(strategy: OverflowStrategy, messages: u32) -> (Sender<u32>, Receiver<u32>, Receiver<u32>) {
+        let tx = channel(2, strategy);
+        let mut fast = tx.subscribe();
+        let slow = tx.subscribe();
+        for n in 1..=messages {
+            tx.send(n).await.unwrap();
+            assert_eq!(fast.recv().await, Ok(n));
+        }
+        (tx, fast, slow)
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_messages() {
+        let (_tx, _fast, mut slow) = slow_consumer(OverflowStrategy::DropOldest, 5).await;
+        assert_eq!(slow.recv().await, Err(RecvError::Lagged(3)));
+        assert_eq!(slow.recv().await, Ok(4));
+        assert_eq!(slow.recv().await, Ok(5));
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_queued_messages() {
+        let (tx, _fast, mut slow) = slow_consumer(OverflowStrategy::DropNewest, 5).await;
+        assert_eq!(slow.recv().await, Ok(1));
+        assert_eq!(slow.recv().await, Ok(2));
+        assert_eq!(slow.recv().await, Err(RecvError::Lagged(3)));
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Empty));
+
+        // Room again for what comes next
+        tx.send(6).await.unwrap();
+        assert_eq!(slow.recv().await, Ok(6));
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_the_slowest_receiver() {
+        let (tx, _fast, mut slow) = slow_consumer(OverflowStrategy::Block, 2).await;
+        let send = tx.send(3);
+        tokio::pin!(send);
+        let blocked = tokio::time::timeout(std::time::Duration::from_millis(50), send.as_mut()).await;
+        assert!(blocked.is_err(), "send went through with a full receiver");
+
+        assert_eq!(slow.recv().await, Ok(1));
+        assert_eq!(send.await.unwrap(), 2);
+        assert_eq!(slow.recv().await, Ok(2));
+        assert_eq!(slow.recv().await, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn block_goes_on_without_a_dropped_receiver() {
+        let (tx, mut fast, slow) = slow_consumer(OverflowStrategy::Block, 2).await;
+        let send = tx.send(3);
+        tokio::pin!(send);
+        let blocked = tokio::time::timeout(std::time::Duration::from_millis(50), send.as_mut()).await;
+        assert!(blocked.is_err(), "send went through with a full receiver");
+
+        drop(slow);
+        assert_eq!(send.await.unwrap(), 1);
+        assert_eq!(fast.recv().await, Ok(3));
+    }
+}
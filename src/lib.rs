@@ -0,0 +1,11 @@
+//! The frame pipeline without the server around it: config, encoding and
+//! test patterns. Split out of the binary so benches can reach it.
+
+pub mod clock;
+pub mod compression;
+pub mod config;
+pub mod dictionary;
+pub mod error;
+mod pool;
+pub mod preset;
+pub mod testcard;
@@ -0,0 +1,114 @@
+use crate::config::LogFormat;
+use std::fmt;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, MakeWriter},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter,
+};
+
+/// `service` field of JSON log lines
+const SERVICE_NAME: &str = "retrostream";
+
+/// A subscriber logging to stdout in `format`, filtered by `RUST_LOG` in
+/// both formats.
+pub fn subscriber(format: LogFormat) -> Box<dyn Subscriber + Send + Sync> {
+    subscriber_to(format, std::io::stdout)
+}
+
+/// `subscriber`, writing to `writer` instead of stdout
+fn subscriber_to<W>(format: LogFormat, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(writer);
+
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .event_format(WithService(tracing_subscriber::fmt::format().json()))
+                .finish(),
+        ),
+    }
+}
+
+/// Install `subscriber(format)` for the rest of the process.
+pub fn init(format: LogFormat) {
+    subscriber(format).init();
+}
+
+/// Prefixes each JSON line with `service` and `version` fields, so lines
+/// from several services can be told apart in one aggregator.
+struct WithService<F>(F);
+
+impl<S, N, F> FormatEvent<S, N> for WithService<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut line = String::new();
+        self.0.format_event(ctx, Writer::new(&mut line), event)?;
+        let fields = line.strip_prefix('{').ok_or(fmt::Error)?;
+        write!(
+            writer,
+            "{{\"service\":\"{}\",\"version\":\"{}\",{}",
+            SERVICE_NAME,
+            env!("CARGO_PKG_VERSION"),
+            fields
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects everything logged, for inspection
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_format_writes_one_object_per_line() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = subscriber_to(LogFormat::Json, move || writer.clone());
+        // Errors pass the default filter without RUST_LOG
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!(client = "10.0.0.1:5000", "Capture error #{}", 1);
+            tracing::error!("Line with \"quotes\"\nand a newline");
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["service"], SERVICE_NAME);
+        assert_eq!(lines[0]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(lines[0]["level"], "ERROR");
+        assert_eq!(lines[0]["fields"]["message"], "Capture error #1");
+        assert_eq!(lines[0]["fields"]["client"], "10.0.0.1:5000");
+        assert_eq!(lines[1]["fields"]["message"], "Line with \"quotes\"\nand a newline");
+    }
+}
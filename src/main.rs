@@ -1,113 +1,657 @@
-mod config;
-mod error;
+mod admission;
+#[cfg(feature = "audio")]
+mod audio;
+mod auth;
+mod capabilities;
 mod capture;
-mod compression;
+mod connections;
+mod dump;
+mod fanout;
+mod logging;
 mod websocket;
 mod metrics;
+mod mjpeg;
+mod motion;
+mod processor;
+mod profile;
+mod recording;
+mod shutdown;
+mod snapshot;
+mod session;
+mod source;
+#[cfg(test)]
+mod testing;
+
+// Shared with the benches through the library target
+use screen_stream_backend::{clock, compression, config, dictionary, error, testcard};
 
 use anyhow::Result;
+use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
-use std::sync::Arc;
-use tokio::sync::broadcast;
-use tracing::{info, warn};
-use tower_http::cors::CorsLayer;
+use futures_util::Stream;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::{atomic::AtomicU64, Arc};
+use tokio::{sync::{broadcast, watch, Notify}, task::JoinHandle};
+use tracing::{error, info, warn};
+use tower_http::{compression::CompressionLayer, cors::CorsLayer};
 use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
     routing::get,
-    Router,
+    Json, Router,
 };
 
 use crate::{
-    config::{Config, Args},
-    capture::ScreenCapture,
+    capabilities::Capabilities,
+    config::{Config, Args, ServerConfig},
+    capture::{LatestFrame, MonitorInfo, ScreenCapture, WindowInfo},
+    compression::FrameMessage,
+    error::{AppError, AppResult},
     websocket::ws_handler,
     metrics::setup_metrics,
+    shutdown::ShutdownReason,
 };
 
 #[derive(Clone)]
 pub struct AppState {
-    pub frame_tx: broadcast::Sender<Vec<u8>>,
+    pub frame_tx: fanout::Sender<FrameMessage>,
     pub config: Arc<Config>,
     pub metrics: Arc<metrics::Metrics>,
+    pub admission: Arc<admission::Admission>,
+    pub motion_tx: broadcast::Sender<motion::MotionEvent>,
+    /// Audio messages with `capture.audio`, see `audio::packet`
+    pub audio_tx: broadcast::Sender<FrameMessage>,
+    pub latest_frame: LatestFrame,
+    /// Monitor index chosen at runtime by clients, followed by capture
+    pub monitor_select: watch::Sender<Option<usize>>,
+    /// Whether `capture.redactions` apply, switched by clients with
+    /// `set_redactions`, followed by capture
+    pub redactions_enabled: watch::Sender<bool>,
+    /// Set on Ctrl+C to close client connections and stop capture
+    pub shutdown: watch::Sender<bool>,
+    /// The config as last reloaded on SIGHUP, followed by capture
+    pub live_config: watch::Sender<Arc<Config>>,
+    pub profiles: Arc<profile::Profiles>,
+    /// Width and height of the last captured frame, published by capture
+    pub resolution: watch::Sender<Option<(u32, u32)>>,
+    pub sessions: Arc<session::Sessions>,
+    /// Open WebSocket connections, for `GET /connections`
+    pub connections: Arc<connections::Connections>,
+    /// Notified whenever a client subscribes to `frame_tx`, to wake capture
+    /// from `capture.idle_without_viewers`
+    pub viewer_joined: Arc<Notify>,
+    /// zstd dictionary from `compression.dictionary_path`, loaded once at
+    /// startup
+    pub dictionary: Option<Arc<[u8]>>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+impl AppState {
+    pub fn new(config: Arc<Config>, metrics: Arc<metrics::Metrics>, frame_counter: Arc<AtomicU64>) -> AppResult<Self> {
+        Ok(Self {
+            // Fan frames out to clients, each with a queue of buffer_size
+            frame_tx: fanout::channel(config.buffer_size, config.overflow_strategy),
+            admission: Arc::new(admission::Admission::new(&config.server)),
+            motion_tx: broadcast::channel(16).0,
+            // A second of audio packets can queue per client
+            audio_tx: broadcast::channel(50).0,
+            latest_frame: LatestFrame::default(),
+            monitor_select: watch::Sender::new(config.capture.monitor_index),
+            redactions_enabled: watch::Sender::new(true),
+            shutdown: watch::Sender::new(false),
+            live_config: watch::Sender::new(config.clone()),
+            profiles: Arc::new(profile::Profiles::new(
+                config.compression.clone(),
+                config.server.max_client_profiles,
+                config.buffer_size,
+                config.overflow_strategy,
+                frame_counter,
+            )),
+            resolution: watch::Sender::new(None),
+            sessions: Arc::new(session::Sessions::new(std::time::Duration::from_millis(
+                config.server.session_ttl_ms,
+            ))),
+            connections: Arc::default(),
+            viewer_joined: Arc::new(Notify::new()),
+            dictionary: config
+                .compression
+                .dictionary_path
+                .as_deref()
+                .map(dictionary::load)
+                .transpose()?,
+            config,
+            metrics,
+        })
+    }
+}
 
-    // Parse arguments and load configuration
+/// Every route, with `auth_token` required on all but the probes and
+/// `/capabilities`
+fn router(state: AppState) -> Router {
+    let protected = Router::new()
+        .route("/stream", get(ws_handler))
+        .route("/mjpeg", get(mjpeg::mjpeg_handler))
+        // gzip or brotli per Accept-Encoding. Images are already compressed
+        // and pass through as they are.
+        .route("/metrics", get(metrics_handler).layer(CompressionLayer::new()))
+        .route("/metrics/stream", get(metrics_stream_handler))
+        .route("/monitors", get(monitors_handler))
+        .route("/windows", get(windows_handler))
+        .route("/snapshot", get(snapshot::snapshot_handler).layer(CompressionLayer::new()))
+        .route("/config", get(config_handler))
+        .route("/connections", get(connections_handler))
+        .route("/dictionary", get(dictionary_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_token));
+    Router::new()
+        .merge(protected)
+        .route("/health", get(health_check))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/capabilities", get(capabilities_handler))
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    // Parse arguments and load configuration. Its own log lines can only
+    // follow --log-format, since the config's log_format isn't known yet.
     let args = Args::parse();
-    let config = Arc::new(Config::load(&args)?);
+    if let Some([samples_dir, out_file]) = args.train_dict.as_deref() {
+        logging::init(args.log_format.unwrap_or_default());
+        std::fs::write(out_file, dictionary::train(samples_dir)?)?;
+        info!("Dictionary written to {}", out_file.display());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let config = tracing::subscriber::with_default(
+        logging::subscriber(args.log_format.unwrap_or_default()),
+        || Config::load(&args),
+    )?;
+    let config = Arc::new(config);
+    logging::init(config.log_format);
     
     info!("Starting Screen Stream Backend v{}", env!("CARGO_PKG_VERSION"));
     info!("Configuration: {:?}", config);
+    info!("Capabilities: {:?}", Capabilities::detect());
 
     // Setup metrics
     let metrics = Arc::new(setup_metrics()?);
 
-    // Create broadcast channel for frames
-    let (frame_tx, _) = broadcast::channel(config.buffer_size);
-    
-    let state = AppState {
-        frame_tx: frame_tx.clone(),
-        config: config.clone(),
-        metrics: metrics.clone(),
-    };
+    // Frame IDs are owned here rather than by the compressor so they stay
+    // monotonic for the server's lifetime, regardless of encoder rebuilds.
+    let frame_counter = Arc::new(AtomicU64::new(0));
+    let state = AppState::new(config.clone(), metrics.clone(), frame_counter.clone())?;
+
+    // Motion events fan out to WebSocket clients and the optional webhook
+    if let (true, Some(url)) = (config.motion.enabled, config.motion.webhook_url.clone()) {
+        tokio::spawn(motion::run_webhook(url, state.motion_tx.subscribe()));
+    }
+
+    // Audio is paced by the audio device, independently of capture
+    if config.capture.audio {
+        #[cfg(feature = "audio")]
+        audio::spawn(config.capture.audio_device.clone(), state.audio_tx.clone())?;
+        #[cfg(not(feature = "audio"))]
+        warn!("capture.audio is set, but this build has no audio support (the audio feature)");
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(args.clone(), state.live_config.clone()));
 
-    // Create screen capture
-    let mut capture = ScreenCapture::new(config.clone(), metrics.clone())?;
-    
     // Start screen capture task
-    let capture_task = tokio::spawn(async move {
-        capture.start_capture_loop(frame_tx).await
-    });
+    let mut capture_task = spawn_capture(&state, frame_counter.clone(), &args, true)?;
 
-    // Setup web server with CORS
-    let app = Router::new()
-        .route("/stream", get(ws_handler))
-        .route("/health", get(health_check))
-        .route("/metrics", get(metrics_handler))
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+    let app = router(state.clone());
 
     // Start server
     let addr = format!("{}:{}", config.server.host, config.server.port);
     info!("Server starting on {}", addr);
     
     let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
     
     // Graceful shutdown handling
-    let server_task = tokio::spawn(async move {
-        if let Err(e) = axum::serve(listener, app).await {
-            warn!("Server error: {}", e);
+    let server_task = match load_tls(&config.server).await? {
+        Some(tls) => {
+            info!("TLS enabled, clients connect with wss://");
+            let server = axum_server::from_tcp_rustls(listener.into_std()?, tls);
+            tokio::spawn(async move { server.serve(service).await })
         }
-    });
+        None => tokio::spawn(async move { axum::serve(listener, service).await }),
+    };
+
+    // Watchdog: restart the capture task if it stops producing frames
+    let watchdog_timeout = config.watchdog_timeout();
+    let mut watchdog = tokio::time::interval(
+        watchdog_timeout.map_or(std::time::Duration::from_secs(1), |t| t / 2),
+    );
+    tokio::pin!(server_task);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
 
     // Wait for tasks
-    tokio::select! {
-        result = capture_task => {
-            if let Err(e) = result? {
-                warn!("Capture task error: {}", e);
+    let reason = loop {
+        tokio::select! {
+            result = &mut capture_task => {
+                break match result {
+                    Ok(Ok(())) => {
+                        error!("Capture task exited unexpectedly");
+                        ShutdownReason::CaptureFailed
+                    }
+                    Ok(Err(e)) => {
+                        error!("Capture task failed: {}", e);
+                        ShutdownReason::CaptureFailed
+                    }
+                    Err(e) => {
+                        error!("Capture task panicked: {}", e);
+                        ShutdownReason::CapturePanicked
+                    }
+                };
+            }
+            result = &mut server_task => {
+                break match result {
+                    Ok(Ok(())) => {
+                        error!("Server stopped unexpectedly");
+                        ShutdownReason::ServerStopped
+                    }
+                    Ok(Err(e)) => {
+                        error!("Server failed: {}", e);
+                        ShutdownReason::ServerFailed
+                    }
+                    Err(e) => {
+                        error!("Server task panicked: {}", e);
+                        ShutdownReason::ServerFailed
+                    }
+                };
+            }
+            _ = &mut ctrl_c => {
+                info!("Received Ctrl+C, shutting down...");
+                break ShutdownReason::Signal;
+            }
+            _ = watchdog.tick(), if watchdog_timeout.is_some() => {
+                let stalled_for = metrics.time_since_last_frame();
+                // No frames are expected while nobody is watching
+                if !metrics.is_capture_idle()
+                    && watchdog_timeout.is_some_and(|timeout| stalled_for > timeout)
+                {
+                    warn!(
+                        "No frame captured for {:.1}s, restarting capture task",
+                        stalled_for.as_secs_f32()
+                    );
+                    capture_task.abort();
+                    metrics.increment_capture_restarts();
+                    // Give the new task a full timeout before checking again
+                    metrics.record_frame_heartbeat();
+                    capture_task = spawn_capture(&state, frame_counter.clone(), &args, false)?;
+                }
             }
         }
-        _ = server_task => {
-            info!("Server task completed");
+    };
+
+    if reason == ShutdownReason::Signal {
+        drain_clients(&state).await;
+    }
+
+    info!("Shutdown complete ({:?})", reason);
+    Ok(reason.exit_code())
+}
+
+/// Load the certificate and key from `tls_cert_path` and `tls_key_path`,
+/// or `None` to serve plain HTTP.
+async fn load_tls(server: &ServerConfig) -> AppResult<Option<RustlsConfig>> {
+    let (Some(cert), Some(key)) = (&server.tls_cert_path, &server.tls_key_path) else {
+        return Ok(None);
+    };
+
+    RustlsConfig::from_pem_file(cert, key).await.map(Some).map_err(|e| {
+        AppError::ConfigError(format!(
+            "Cannot load TLS certificate {} and key {}: {}",
+            cert.display(),
+            key.display(),
+            e
+        ))
+    })
+}
+
+/// Re-read the config file and command line on every SIGHUP and publish the
+/// result to `live_config`.
+#[cfg(unix)]
+async fn reload_on_sighup(mut args: Args, live_config: watch::Sender<Arc<Config>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Config reload on SIGHUP unavailable: {}", e);
+            return;
         }
-        _ = tokio::signal::ctrl_c() => {
-            info!("Received Ctrl+C, shutting down...");
+    };
+    // Only save the preset once, at startup
+    args.save_preset = None;
+
+    while hangup.recv().await.is_some() {
+        match Config::load(&args) {
+            Ok(new) => {
+                let merged = live_config.borrow().reloaded(&new);
+                info!("Reloaded configuration from {}", args.config.display());
+                live_config.send_replace(Arc::new(merged));
+            }
+            Err(e) => error!("Config reload failed, keeping current settings: {}", e),
         }
     }
+}
+
+/// Ask every client to close and wait up to `shutdown_grace_ms` for them
+/// to disconnect before the process exits.
+async fn drain_clients(state: &AppState) {
+    let clients = state.metrics.get_active_connections();
+    state.shutdown.send_replace(true);
+    if clients == 0 {
+        return;
+    }
 
-    info!("Shutdown complete");
-    Ok(())
+    info!("Closing {} client connections", clients);
+    let deadline = tokio::time::Instant::now()
+        + std::time::Duration::from_millis(state.config.server.shutdown_grace_ms);
+    while state.metrics.get_active_connections() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    let dropped = state.metrics.get_active_connections();
+    info!(
+        "Drained {} clients, {} forcibly dropped",
+        clients.saturating_sub(dropped),
+        dropped
+    );
 }
 
-async fn health_check() -> &'static str {
+/// Frame dumping and recording only apply to the first capture task
+/// (`first_run`) so a watchdog restart doesn't overwrite earlier frames.
+fn spawn_capture(
+    state: &AppState,
+    frame_counter: Arc<AtomicU64>,
+    args: &Args,
+    first_run: bool,
+) -> Result<JoinHandle<AppResult<()>>> {
+    // A restarted capture task starts from the latest reloaded config
+    let config = state.live_config.borrow().clone();
+    let mut capture = ScreenCapture::new(config.clone(), state.metrics.clone(), frame_counter)?;
+    capture.follow_config_reloads(state.live_config.subscribe());
+    capture.follow_monitor_selection(state.monitor_select.subscribe());
+    capture.follow_redaction_toggle(state.redactions_enabled.subscribe());
+    capture.stop_on_shutdown(state.shutdown.subscribe());
+    capture.idle_without_viewers(state.viewer_joined.clone());
+    if let Some(dictionary) = &state.dictionary {
+        capture.use_dictionary(dictionary.clone());
+    }
+    capture.publish_raw_frames(state.profiles.raw_sender());
+    capture.publish_resolution(state.resolution.clone());
+    capture.cache_latest_frame(state.latest_frame.clone());
+    if config.motion.enabled {
+        capture.enable_motion_detection(state.motion_tx.clone());
+    }
+    let frame_tx = state.frame_tx.clone();
+    if let Some(path) = &args.replay {
+        capture.replay_from(path)?;
+    }
+    if first_run {
+        if let Some(dir) = args.dump_frames.clone() {
+            capture.enable_frame_dump(dir, args.dump_count)?;
+        }
+        if let Some(path) = args.record.clone() {
+            capture.enable_recording(path)?;
+        }
+    }
+
+    Ok(tokio::spawn(async move {
+        capture.start_capture_loop(frame_tx).await
+    }))
+}
+
+/// `/health` reports capture stalled once the source has gone this long
+/// without delivering a frame
+const CAPTURE_STALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// 200 while the capture source is delivering frames, or capture is idle
+/// for lack of viewers. 503 when it isn't, e.g. while clients only get
+/// fallback frames, so load balancers can route around this instance.
+async fn health_check(State(state): State<AppState>) -> (StatusCode, String) {
+    let since_capture = state.metrics.time_since_last_capture();
+    if state.metrics.is_capture_idle() || since_capture <= CAPTURE_STALL_TIMEOUT {
+        return (StatusCode::OK, "OK".to_string());
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        format!("capture stalled: no frame captured for {:.1}s", since_capture.as_secs_f32()),
+    )
+}
+
+/// Kubernetes liveness probe: answered whenever the runtime is serving
+/// requests at all
+async fn livez() -> &'static str {
     "OK"
 }
 
-async fn metrics_handler() -> String {
-    "metrics endpoint - implement prometheus export here".to_string()
+/// Kubernetes readiness probe: 503 until the capture source has opened,
+/// e.g. while waiting for the display or serving fallback frames. Being
+/// answered at all means the listener is bound.
+async fn readyz(State(state): State<AppState>) -> (StatusCode, &'static str) {
+    if state.metrics.is_source_opened() {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready: capture source not open")
+    }
+}
+
+async fn capabilities_handler() -> Json<Capabilities> {
+    Json(Capabilities::detect())
+}
+
+async fn monitors_handler() -> Result<Json<Vec<MonitorInfo>>, (StatusCode, String)> {
+    capture::list_monitors()
+        .map(Json)
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))
+}
+
+/// The config the server started with, after CLI overrides
+async fn config_handler(State(state): State<AppState>) -> Json<Config> {
+    Json(state.config.redacted())
+}
+
+async fn connections_handler(State(state): State<AppState>) -> Json<Vec<connections::ConnectionInfo>> {
+    Json(state.connections.list())
+}
+
+/// The zstd dictionary frames with `FrameHeader.dictionary` need to
+/// decompress, or 404 without `compression.dictionary_path`
+async fn dictionary_handler(State(state): State<AppState>) -> Result<impl IntoResponse, StatusCode> {
+    let dictionary = state.dictionary.clone().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], dictionary.to_vec()))
+}
+
+async fn windows_handler() -> Result<Json<Vec<WindowInfo>>, (StatusCode, String)> {
+    capture::list_windows()
+        .map(Json)
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.get_summary().to_prometheus(),
+    )
+}
+
+/// Push a JSON `MetricsSummary` as a server-sent event every
+/// `metrics_stream_interval_ms`. The stream owns its interval, so nothing
+/// outlives the client.
+async fn metrics_stream_handler(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let period = std::time::Duration::from_millis(state.config.server.metrics_stream_interval_ms);
+    let interval = tokio::time::interval(period);
+
+    let events = futures_util::stream::unfold(interval, move |mut interval| {
+        let metrics = state.metrics.clone();
+        async move {
+            interval.tick().await;
+            let event = Event::default().json_data(metrics.get_summary());
+            Some((event, interval))
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn metrics_stream_sends_summaries() {
+        let state = testing::state(|c| c.server.metrics_stream_interval_ms = 10);
+        let response = testing::get(&state, "/metrics/stream").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        let mut body = response.into_body().into_data_stream();
+        let mut text = String::new();
+        while text.matches("\n\n").count() < 2 {
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), body.next())
+                .await
+                .expect("timed out waiting for an event")
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        let events: Vec<&str> = text.split_terminator("\n\n").take(2).collect();
+        for event in events {
+            let json = event.strip_prefix("data: ").expect(event);
+            let summary: serde_json::Value = serde_json::from_str(json).unwrap();
+            assert_eq!(summary["active_connections"], 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_capture_is_unhealthy() {
+        let mut state = testing::state(|_| {});
+        state.metrics = Arc::new(metrics::Metrics::started_ago(CAPTURE_STALL_TIMEOUT * 2));
+        let response = testing::get(&state, "/health").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(testing::body(response).await.starts_with(b"capture stalled"));
+
+        state.metrics.record_source_capture();
+        assert_eq!(testing::get(&state, "/health").await.status(), StatusCode::OK);
+
+        state.metrics = Arc::new(metrics::Metrics::started_ago(CAPTURE_STALL_TIMEOUT * 2));
+        state.metrics.start_capture_idle();
+        assert_eq!(testing::get(&state, "/health").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn ready_once_the_source_opens() {
+        let state = testing::state(|_| {});
+        assert_eq!(testing::get(&state, "/readyz").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Idling doesn't tell whether the display can be captured
+        state.metrics.start_capture_idle();
+        assert_eq!(testing::get(&state, "/readyz").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        state.metrics.record_source_opened();
+        assert_eq!(testing::get(&state, "/readyz").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_gzipped_when_accepted() {
+        let state = testing::state(|_| {});
+        let request = axum::http::Request::get("/metrics")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = testing::send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(testing::body(response).await[..2], [0x1f, 0x8b]);
+
+        let plain = testing::get(&state, "/metrics").await;
+        assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+        assert!(testing::body(plain).await.starts_with(b"# HELP"));
+    }
+
+    #[tokio::test]
+    async fn serves_tls_with_a_self_signed_certificate() {
+        let state = testing::state(|c| {
+            c.server.tls_cert_path = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/tls-cert.pem").into());
+            c.server.tls_key_path = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/tls-key.pem").into());
+        });
+        let tls = load_tls(&state.config.server).await.unwrap().expect("TLS configured");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(axum_server::from_tcp_rustls(listener, tls).serve(service));
+
+        let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &include_bytes!("testdata/tls-cert.pem")[..]) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client = tokio_rustls::rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+            .connect("localhost".try_into().unwrap(), tcp)
+            .await
+            .unwrap();
+
+        stream
+            .write_all(b"GET /livez HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn unreadable_certificate_is_a_config_error() {
+        let state = testing::state(|c| {
+            c.server.tls_cert_path = Some("/nonexistent/cert.pem".into());
+            c.server.tls_key_path = Some("/nonexistent/key.pem".into());
+        });
+        assert!(matches!(load_tls(&state.config.server).await, Err(AppError::ConfigError(_))));
+    }
+
+    #[tokio::test]
+    async fn plain_http_without_certificate() {
+        assert!(load_tls(&Config::default().server).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn config_endpoint_masks_the_token() {
+        let state = testing::state(|c| {
+            c.capture.fps = 24;
+            c.server.auth_token = Some("hunter2".to_string());
+        });
+        let request = axum::http::Request::get("/config")
+            .header(header::AUTHORIZATION, "Bearer hunter2")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = testing::send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = testing::body(response).await;
+        let config: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(config["capture"]["fps"], 24);
+        assert_eq!(config["server"]["auth_token"], "***");
+        assert!(!String::from_utf8(body).unwrap().contains("hunter2"));
+    }
 }
@@ -1,52 +1,227 @@
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Duration;
+use serde::Serialize;
+use std::fmt::{Display, Write};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex,
+};
+use std::time::{Duration, Instant};
+
+/// Fixed-point scale of the stored compression ratio. Parts per million,
+/// since deltas of a static screen compress to a tiny fraction of a frame.
+const RATIO_SCALE: u64 = 1_000_000;
+
+/// Upper bounds of the frame timing histogram buckets, in milliseconds
+const DURATION_BUCKETS_MS: [u64; 8] = [1, 2, 5, 10, 20, 50, 100, 200];
+
+/// Upper bounds of the connection duration histogram buckets, in
+/// milliseconds: from a second, e.g. a flapping client, to an hour
+const CONNECTION_BUCKETS_MS: [u64; 8] = [1_000, 10_000, 30_000, 60_000, 300_000, 600_000, 1_800_000, 3_600_000];
+
+/// Prometheus-style histogram of durations over fixed bucket bounds, so
+/// tail latencies can be read off instead of averaged away.
+pub struct DurationHistogram {
+    /// Upper bucket bounds in milliseconds, ascending
+    bounds_ms: &'static [u64],
+    /// Per-bucket counts, not cumulative. The last one is `+Inf`.
+    buckets: Box<[AtomicU64]>,
+    sum_us: AtomicU64,
+    count: AtomicU64,
+}
+
+impl DurationHistogram {
+    fn new(bounds_ms: &'static [u64]) -> Self {
+        Self {
+            bounds_ms,
+            buckets: (0..=bounds_ms.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_us: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let us = duration.as_micros() as u64;
+        let bucket = self
+            .bounds_ms
+            .iter()
+            .position(|&bound| us <= bound * 1000)
+            .unwrap_or(self.bounds_ms.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                cumulative
+            })
+            .collect();
+
+        HistogramSnapshot {
+            bounds_ms: self.bounds_ms,
+            buckets,
+            sum_ms: self.sum_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a `DurationHistogram`
+#[derive(Debug, Serialize)]
+pub struct HistogramSnapshot {
+    #[serde(skip)]
+    pub bounds_ms: &'static [u64],
+    /// Cumulative counts per bound of `bounds_ms`, then `+Inf`
+    pub buckets: Vec<u64>,
+    pub sum_ms: f64,
+    pub count: u64,
+}
 
 pub struct Metrics {
     // Connection metrics
     active_connections: AtomicU64,
+    /// Connected clients that sent `pause`
+    paused_connections: AtomicU64,
     total_connections: AtomicU64,
+    /// Reconnections that resumed a session, not counted in `total_connections`
+    sessions_resumed: AtomicU64,
+    client_ips: Mutex<HashSet<IpAddr>>,
+    bytes_sent: AtomicU64,
     
     // Frame metrics
     frames_captured: AtomicU64,
     frames_sent: AtomicU64,
     frames_delivered: AtomicU64,
     frames_dropped: AtomicU64,
+    frame_queue_depth: AtomicU64,
+    frames_expired: AtomicU64,
+    frames_superseded: AtomicU64,
+    frames_throttled: AtomicU64,
+    captures_skipped: AtomicU64,
+    capture_ticks_missed: AtomicU64,
     
     // Error metrics
     capture_errors: AtomicU64,
+    capture_restarts: AtomicU64,
+    
+    // Capture heartbeat, in ms since `started_at`
+    started_at: Instant,
+    last_frame_ms: AtomicU64,
+    /// Last frame from the source itself rather than a fallback frame
+    last_capture_ms: AtomicU64,
+    /// Whether the capture source has been opened, see `/readyz`
+    source_opened: AtomicBool,
+    /// When capture went idle for lack of viewers, in ms since `started_at`
+    idle_since_ms: Mutex<Option<u64>>,
+    /// Idle time of earlier idle periods
+    capture_idle_ms: AtomicU64,
     
     // Performance metrics
-    avg_capture_duration_ms: AtomicU64,
-    avg_compression_duration_ms: AtomicU64,
-    compression_ratio: AtomicU64, // * 1000 for precision
+    capture_duration: DurationHistogram,
+    compression_duration: DurationHistogram,
+    /// How long WebSocket connections stayed open, observed on disconnect
+    connection_duration: DurationHistogram,
+    compression_ratio: AtomicU64, // * RATIO_SCALE for precision
+    /// Set by the first measured ratio, which replaces the 1.0 default
+    compression_ratio_seeded: AtomicBool,
+    avg_frame_jitter_us: AtomicU64,
 }
 
 impl Metrics {
     pub fn new() -> Self {
         Self {
             active_connections: AtomicU64::new(0),
+            paused_connections: AtomicU64::new(0),
             total_connections: AtomicU64::new(0),
+            sessions_resumed: AtomicU64::new(0),
+            client_ips: Mutex::new(HashSet::new()),
+            bytes_sent: AtomicU64::new(0),
             frames_captured: AtomicU64::new(0),
             frames_sent: AtomicU64::new(0),
             frames_delivered: AtomicU64::new(0),
             frames_dropped: AtomicU64::new(0),
+            frame_queue_depth: AtomicU64::new(0),
+            frames_expired: AtomicU64::new(0),
+            frames_superseded: AtomicU64::new(0),
+            frames_throttled: AtomicU64::new(0),
+            captures_skipped: AtomicU64::new(0),
+            capture_ticks_missed: AtomicU64::new(0),
             capture_errors: AtomicU64::new(0),
-            avg_capture_duration_ms: AtomicU64::new(0),
-            avg_compression_duration_ms: AtomicU64::new(0),
-            compression_ratio: AtomicU64::new(1000), // 1.0 * 1000
+            capture_restarts: AtomicU64::new(0),
+            started_at: Instant::now(),
+            last_frame_ms: AtomicU64::new(0),
+            last_capture_ms: AtomicU64::new(0),
+            source_opened: AtomicBool::new(false),
+            idle_since_ms: Mutex::new(None),
+            capture_idle_ms: AtomicU64::new(0),
+            capture_duration: DurationHistogram::new(&DURATION_BUCKETS_MS),
+            compression_duration: DurationHistogram::new(&DURATION_BUCKETS_MS),
+            connection_duration: DurationHistogram::new(&CONNECTION_BUCKETS_MS),
+            compression_ratio: AtomicU64::new(RATIO_SCALE), // 1.0
+            compression_ratio_seeded: AtomicBool::new(false),
+            avg_frame_jitter_us: AtomicU64::new(0),
         }
     }
+
+    /// Metrics of a server that started `ago`, so the heartbeats look that
+    /// old until recorded
+    #[cfg(test)]
+    pub fn started_ago(ago: Duration) -> Self {
+        Self { started_at: Instant::now() - ago, ..Self::new() }
+    }
     
     // Connection metrics
+    /// A connection opened. It is counted in `total_connections` by
+    /// `count_new_viewer` once it's known not to resume a session.
     pub fn increment_connections(&self) {
         self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_new_viewer(&self) {
         self.total_connections.fetch_add(1, Ordering::Relaxed);
     }
     
+    /// A reconnecting client resumed its session: active again, but not
+    /// another viewer
+    pub fn record_session_resumed(&self) {
+        self.sessions_resumed.fetch_add(1, Ordering::Relaxed);
+    }
+    
     pub fn decrement_connections(&self) {
         self.active_connections.fetch_sub(1, Ordering::Relaxed);
     }
     
+    /// A connection closed after being open for `duration`
+    pub fn observe_connection_duration(&self, duration: Duration) {
+        self.connection_duration.observe(duration);
+    }
+
+    /// Remember a client address for the unique client count
+    pub fn record_client_ip(&self, ip: IpAddr) {
+        self.client_ips.lock().unwrap().insert(ip);
+    }
+    
+    /// Count bytes written to a client's socket. Per connection counts are
+    /// kept by `Connection` instead, so the exposition doesn't grow a series
+    /// for every client that ever connected.
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    
+    pub fn increment_paused_connections(&self) {
+        self.paused_connections.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    pub fn decrement_paused_connections(&self) {
+        self.paused_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+    
     pub fn get_active_connections(&self) -> u64 {
         self.active_connections.load(Ordering::Relaxed)
     }
@@ -56,6 +231,10 @@ impl Metrics {
         self.frames_captured.fetch_add(1, Ordering::Relaxed);
     }
     
+    pub fn get_frames_captured(&self) -> u64 {
+        self.frames_captured.load(Ordering::Relaxed)
+    }
+    
     pub fn increment_frames_sent(&self) {
         self.frames_sent.fetch_add(1, Ordering::Relaxed);
     }
@@ -68,66 +247,431 @@ impl Metrics {
         self.frames_dropped.fetch_add(1, Ordering::Relaxed);
     }
     
+    /// Frames dropped as stale: past their send deadline or superseded while paced
+    pub fn increment_expired_frames(&self) {
+        self.frames_expired.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Frames skipped by `latest_frame_only` because a newer one was queued
+    pub fn add_superseded_frames(&self, count: u64) {
+        self.frames_superseded.fetch_add(count, Ordering::Relaxed);
+    }
+    
+    /// Frames skipped because a client was over `max_bitrate_kbps`
+    pub fn increment_throttled_frames(&self) {
+        self.frames_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Captures skipped by `throttle_on_backpressure`
+    pub fn increment_captures_skipped(&self) {
+        self.captures_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Count frame slots that came due while a capture or encode was still
+    /// running and were left out. Stays at zero with `capture.overrun` set
+    /// to `burst`, which captures them back to back instead.
+    pub fn add_capture_ticks_missed(&self, count: u64) {
+        self.capture_ticks_missed.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Frames queued in the broadcast channel for the slowest client
+    pub fn set_frame_queue_depth(&self, depth: usize) {
+        self.frame_queue_depth.store(depth as u64, Ordering::Relaxed);
+    }
+    
     pub fn increment_capture_errors(&self) {
         self.capture_errors.fetch_add(1, Ordering::Relaxed);
     }
     
+    pub fn increment_capture_restarts(&self) {
+        self.capture_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    // Capture heartbeat
+    pub fn record_frame_heartbeat(&self) {
+        let ms = self.started_at.elapsed().as_millis() as u64;
+        self.last_frame_ms.store(ms, Ordering::Relaxed);
+    }
+    
+    pub fn time_since_last_frame(&self) -> Duration {
+        let last = Duration::from_millis(self.last_frame_ms.load(Ordering::Relaxed));
+        self.started_at.elapsed().saturating_sub(last)
+    }
+    
+    /// The capture source delivered a frame. Unlike the heartbeat, not
+    /// recorded for fallback frames.
+    pub fn record_source_capture(&self) {
+        let ms = self.started_at.elapsed().as_millis() as u64;
+        self.last_capture_ms.store(ms, Ordering::Relaxed);
+    }
+    
+    /// Time since `record_source_capture`, or since startup before the
+    /// first capture
+    pub fn time_since_last_capture(&self) -> Duration {
+        let last = Duration::from_millis(self.last_capture_ms.load(Ordering::Relaxed));
+        self.started_at.elapsed().saturating_sub(last)
+    }
+    
+    /// The capture source opened, so frames can be served
+    pub fn record_source_opened(&self) {
+        self.source_opened.store(true, Ordering::Relaxed);
+    }
+    
+    pub fn is_source_opened(&self) -> bool {
+        self.source_opened.load(Ordering::Relaxed)
+    }
+    
+    /// Capture stopped because nobody is watching, see
+    /// `capture.idle_without_viewers`
+    pub fn start_capture_idle(&self) {
+        let ms = self.started_at.elapsed().as_millis() as u64;
+        self.idle_since_ms.lock().unwrap().get_or_insert(ms);
+    }
+    
+    pub fn end_capture_idle(&self) {
+        if let Some(since) = self.idle_since_ms.lock().unwrap().take() {
+            let ms = self.started_at.elapsed().as_millis() as u64;
+            self.capture_idle_ms.fetch_add(ms.saturating_sub(since), Ordering::Relaxed);
+        }
+    }
+    
+    /// Whether capture is idle, in which case no frames are expected
+    pub fn is_capture_idle(&self) -> bool {
+        self.idle_since_ms.lock().unwrap().is_some()
+    }
+    
+    /// Total time spent idle, including the current idle period
+    fn capture_idle_time(&self) -> Duration {
+        let current = self.idle_since_ms.lock().unwrap().map_or(0, |since| {
+            (self.started_at.elapsed().as_millis() as u64).saturating_sub(since)
+        });
+        Duration::from_millis(self.capture_idle_ms.load(Ordering::Relaxed) + current)
+    }
+    
     // Performance metrics
-    pub fn record_capture_duration(&self, duration: Duration) {
-        let ms = duration.as_millis() as u64;
-        // Simple exponential moving average
-        let current = self.avg_capture_duration_ms.load(Ordering::Relaxed);
-        let new_avg = if current == 0 { ms } else { (current * 7 + ms) / 8 };
-        self.avg_capture_duration_ms.store(new_avg, Ordering::Relaxed);
+    pub fn observe_capture_duration(&self, duration: Duration) {
+        self.capture_duration.observe(duration);
     }
     
-    pub fn record_compression_duration(&self, duration: Duration) {
-        let ms = duration.as_millis() as u64;
-        let current = self.avg_compression_duration_ms.load(Ordering::Relaxed);
-        let new_avg = if current == 0 { ms } else { (current * 7 + ms) / 8 };
-        self.avg_compression_duration_ms.store(new_avg, Ordering::Relaxed);
+    pub fn observe_compression_duration(&self, duration: Duration) {
+        self.compression_duration.observe(duration);
     }
     
     pub fn record_compression_ratio(&self, original_size: usize, compressed_size: usize) {
-        if original_size > 0 {
-            let ratio = ((compressed_size * 1000) / original_size) as u64; // * 1000 for precision
+        if let Some(ratio) = (compressed_size as u64 * RATIO_SCALE).checked_div(original_size as u64) {
             let current = self.compression_ratio.load(Ordering::Relaxed);
-            let new_avg = if current == 1000 { ratio } else { (current * 7 + ratio) / 8 };
+            // Checked with a flag rather than the value, since a measured
+            // ratio can be exactly 1.0 too
+            let seeded = self.compression_ratio_seeded.swap(true, Ordering::Relaxed);
+            let new_avg = if seeded { (current * 7 + ratio) / 8 } else { ratio };
             self.compression_ratio.store(new_avg, Ordering::Relaxed);
         }
     }
     
+    /// Deviation of the gap between two sends to a client from the frame interval
+    pub fn record_frame_jitter(&self, jitter: Duration) {
+        let us = jitter.as_micros() as u64;
+        let current = self.avg_frame_jitter_us.load(Ordering::Relaxed);
+        let new_avg = if current == 0 { us } else { (current * 7 + us) / 8 };
+        self.avg_frame_jitter_us.store(new_avg, Ordering::Relaxed);
+    }
+    
     // Get summary
     pub fn get_summary(&self) -> MetricsSummary {
+        let active_connections = self.active_connections.load(Ordering::Relaxed);
+        let frames_sent = self.frames_sent.load(Ordering::Relaxed);
+        let frames_delivered = self.frames_delivered.load(Ordering::Relaxed);
+        let frames_dropped = self.frames_dropped.load(Ordering::Relaxed);
+        let connection_duration = self.connection_duration.snapshot();
+
         MetricsSummary {
-            active_connections: self.active_connections.load(Ordering::Relaxed),
+            active_connections,
+            paused_connections: self.paused_connections.load(Ordering::Relaxed),
             total_connections: self.total_connections.load(Ordering::Relaxed),
+            sessions_resumed: self.sessions_resumed.load(Ordering::Relaxed),
+            unique_client_ips: self.client_ips.lock().unwrap().len() as u64,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
             frames_captured: self.frames_captured.load(Ordering::Relaxed),
-            frames_sent: self.frames_sent.load(Ordering::Relaxed),
-            frames_delivered: self.frames_delivered.load(Ordering::Relaxed),
-            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            frames_sent,
+            frames_delivered,
+            frames_dropped,
+            // Approximate, since clients that came and went are counted
+            // against frames sent before or after they were connected
+            delivery_ratio: ratio(frames_delivered, frames_sent * active_connections),
+            drop_rate: ratio(frames_dropped, frames_delivered + frames_dropped),
+            frame_queue_depth: self.frame_queue_depth.load(Ordering::Relaxed),
+            frames_expired: self.frames_expired.load(Ordering::Relaxed),
+            frames_superseded: self.frames_superseded.load(Ordering::Relaxed),
+            frames_throttled: self.frames_throttled.load(Ordering::Relaxed),
+            captures_skipped: self.captures_skipped.load(Ordering::Relaxed),
+            capture_ticks_missed: self.capture_ticks_missed.load(Ordering::Relaxed),
             capture_errors: self.capture_errors.load(Ordering::Relaxed),
-            avg_capture_duration_ms: self.avg_capture_duration_ms.load(Ordering::Relaxed),
-            avg_compression_duration_ms: self.avg_compression_duration_ms.load(Ordering::Relaxed),
-            compression_ratio: self.compression_ratio.load(Ordering::Relaxed) as f64 / 1000.0,
+            capture_restarts: self.capture_restarts.load(Ordering::Relaxed),
+            capture_idle: self.is_capture_idle() as u64,
+            capture_idle_seconds: self.capture_idle_time().as_secs_f64(),
+            capture_duration: self.capture_duration.snapshot(),
+            compression_duration: self.compression_duration.snapshot(),
+            avg_session_seconds: if connection_duration.count == 0 {
+                0.0
+            } else {
+                connection_duration.sum_ms / 1000.0 / connection_duration.count as f64
+            },
+            connection_duration,
+            compression_ratio: self.compression_ratio.load(Ordering::Relaxed) as f64 / RATIO_SCALE as f64,
+            avg_frame_jitter_ms: self.avg_frame_jitter_us.load(Ordering::Relaxed) as f64 / 1000.0,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct MetricsSummary {
     pub active_connections: u64,
+    pub paused_connections: u64,
     pub total_connections: u64,
+    pub sessions_resumed: u64,
+    pub unique_client_ips: u64,
+    pub bytes_sent: u64,
     pub frames_captured: u64,
     pub frames_sent: u64,
     pub frames_delivered: u64,
     pub frames_dropped: u64,
+    /// `frames_delivered` over `frames_sent` times the clients connected
+    /// now; 1.0 when every client got every frame
+    pub delivery_ratio: f64,
+    /// Share of `frames_dropped` lag events among frames delivered or dropped
+    pub drop_rate: f64,
+    pub frame_queue_depth: u64,
+    pub frames_expired: u64,
+    pub frames_superseded: u64,
+    pub frames_throttled: u64,
+    pub captures_skipped: u64,
+    pub capture_ticks_missed: u64,
     pub capture_errors: u64,
-    pub avg_capture_duration_ms: u64,
-    pub avg_compression_duration_ms: u64,
+    pub capture_restarts: u64,
+    /// 1 while capture is stopped for lack of viewers
+    pub capture_idle: u64,
+    pub capture_idle_seconds: f64,
+    pub capture_duration: HistogramSnapshot,
+    pub compression_duration: HistogramSnapshot,
+    /// Of closed connections only
+    pub connection_duration: HistogramSnapshot,
+    /// Mean of `connection_duration`, 0 before any connection closed
+    pub avg_session_seconds: f64,
     pub compression_ratio: f64,
+    pub avg_frame_jitter_ms: f64,
+}
+
+impl MetricsSummary {
+    /// Render in the Prometheus text exposition format (version 0.0.4).
+    pub fn to_prometheus(&self) -> String {
+        let metrics: &[(&str, &str, &str, &dyn Display)] = &[
+            ("up", "gauge", "Always 1 while the server is running, to detect restarts and outages", &1),
+            ("active_connections", "gauge", "Currently connected WebSocket clients", &self.active_connections),
+            ("paused_connections", "gauge", "Connected WebSocket clients that paused delivery", &self.paused_connections),
+            ("connections_total", "counter", "WebSocket connections accepted since startup", &self.total_connections),
+            ("sessions_resumed_total", "counter", "WebSocket reconnections that resumed an earlier session", &self.sessions_resumed),
+            ("unique_client_ips", "gauge", "Distinct client IP addresses seen since startup", &self.unique_client_ips),
+            ("bytes_sent_total", "counter", "Frame bytes written to WebSocket clients", &self.bytes_sent),
+            ("frames_captured_total", "counter", "Frames produced by the capture loop", &self.frames_captured),
+            ("frames_sent_total", "counter", "Frames broadcast to connected clients", &self.frames_sent),
+            ("frames_delivered_total", "counter", "Frames written to client sockets", &self.frames_delivered),
+            ("frames_dropped_total", "counter", "Lag events where a client missed broadcast frames", &self.frames_dropped),
+            ("delivery_ratio", "gauge", "Frames delivered over frames sent per connected client", &self.delivery_ratio),
+            ("drop_rate", "gauge", "Lag events over frames delivered plus lag events", &self.drop_rate),
+            ("frames_expired_total", "counter", "Frames dropped as stale before sending", &self.frames_expired),
+            ("frames_superseded_total", "counter", "Queued frames skipped in favour of a newer one (latest_frame_only)", &self.frames_superseded),
+            ("frames_throttled_total", "counter", "Frames skipped to keep a client under max_bitrate_kbps", &self.frames_throttled),
+            ("captures_skipped_total", "counter", "Captures skipped because no client could take another frame (throttle_on_backpressure)", &self.captures_skipped),
+            ("capture_ticks_missed_total", "counter", "Frame slots skipped because a capture or encode overran the frame interval", &self.capture_ticks_missed),
+            ("frame_queue_depth", "gauge", "Frames queued for the slowest client", &self.frame_queue_depth),
+            ("capture_errors_total", "counter", "Failed screen captures", &self.capture_errors),
+            ("capture_restarts_total", "counter", "Capture task restarts by the watchdog", &self.capture_restarts),
+            ("capture_idle", "gauge", "Whether capture is stopped because nobody is watching", &self.capture_idle),
+            ("capture_idle_seconds_total", "counter", "Time capture spent stopped because nobody was watching", &self.capture_idle_seconds),
+            ("avg_session_seconds", "gauge", "Mean time closed WebSocket connections stayed open", &self.avg_session_seconds),
+            ("compression_ratio", "gauge", "Moving average of compressed over original size", &self.compression_ratio),
+            ("frame_jitter_ms", "gauge", "Moving average of send interval deviation from the frame interval", &self.avg_frame_jitter_ms),
+        ];
+
+        let mut out = String::new();
+        write_build_info(&mut out);
+        for &(name, kind, help, value) in metrics {
+            write_metric(&mut out, name, kind, help, value);
+        }
+        write_histogram(&mut out, "capture_duration_ms", "Screen capture time in milliseconds", &self.capture_duration);
+        write_histogram(&mut out, "compression_duration_ms", "Frame encoding time in milliseconds", &self.compression_duration);
+        write_histogram(&mut out, "connection_duration_ms", "Time closed WebSocket connections stayed open, in milliseconds", &self.connection_duration);
+        out
+    }
+}
+
+/// `numerator / denominator`, or 0 when there's nothing to divide by
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: &dyn Display) {
+    // Writing to a String cannot fail
+    let _ = writeln!(out, "# HELP retrostream_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE retrostream_{} {}", name, kind);
+    let _ = writeln!(out, "retrostream_{} {}", name, value);
+}
+
+/// Constant 1, with the running version as a label
+fn write_build_info(out: &mut String) {
+    let _ = writeln!(out, "# HELP retrostream_build_info Version of the running server");
+    let _ = writeln!(out, "# TYPE retrostream_build_info gauge");
+    let _ = writeln!(
+        out,
+        "retrostream_build_info{{version=\"{}\"}} 1",
+        label_value(env!("CARGO_PKG_VERSION"))
+    );
+}
+
+/// Escape a label value for the text format, where backslash, double
+/// quote and line feed must be written as `\\`, `\"` and `\n`
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &HistogramSnapshot) {
+    let _ = writeln!(out, "# HELP retrostream_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE retrostream_{} histogram", name);
+    let bounds = histogram.bounds_ms.iter().map(|b| b.to_string()).chain(["+Inf".to_string()]);
+    for (bound, count) in bounds.zip(&histogram.buckets) {
+        let _ = writeln!(out, "retrostream_{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+    }
+    let _ = writeln!(out, "retrostream_{}_sum {}", name, histogram.sum_ms);
+    let _ = writeln!(out, "retrostream_{}_count {}", name, histogram.count);
 }
 
 pub fn setup_metrics() -> anyhow::Result<Metrics> {
     Ok(Metrics::new())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_counts_durations_into_cumulative_buckets() {
+        let histogram = DurationHistogram::new(&DURATION_BUCKETS_MS);
+        for ms in [0, 1, 3, 3, 15, 150, 500] {
+            histogram.observe(Duration::from_millis(ms));
+        }
+        // A duration exactly on a bound falls in that bound's bucket
+        histogram.observe(Duration::from_micros(2_001));
+
+        let snapshot = histogram.snapshot();
+        // le 1, 2, 5, 10, 20, 50, 100, 200, +Inf
+        assert_eq!(snapshot.buckets, [2, 2, 5, 5, 6, 6, 6, 7, 8]);
+        assert_eq!(snapshot.count, 8);
+        assert_eq!(snapshot.sum_ms, 674.001);
+    }
+
+    #[test]
+    fn histogram_is_exported_with_sum_and_count() {
+        let metrics = Metrics::new();
+        metrics.observe_capture_duration(Duration::from_millis(4));
+        let text = metrics.get_summary().to_prometheus();
+        assert!(text.contains("retrostream_capture_duration_ms_bucket{le=\"2\"} 0\n"));
+        assert!(text.contains("retrostream_capture_duration_ms_bucket{le=\"5\"} 1\n"));
+        assert!(text.contains("retrostream_capture_duration_ms_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("retrostream_capture_duration_ms_sum 4\n"));
+        assert!(text.contains("retrostream_capture_duration_ms_count 1\n"));
+    }
+
+    #[test]
+    fn delivery_ratio_and_drop_rate_from_counts() {
+        let metrics = Metrics::new();
+        metrics.increment_connections();
+        metrics.increment_connections();
+        for _ in 0..10 {
+            metrics.increment_frames_sent();
+        }
+        for _ in 0..15 {
+            metrics.increment_frames_delivered();
+        }
+        for _ in 0..5 {
+            metrics.increment_dropped_frames();
+        }
+
+        let summary = metrics.get_summary();
+        assert_eq!(summary.delivery_ratio, 0.75);
+        assert_eq!(summary.drop_rate, 0.25);
+        let text = summary.to_prometheus();
+        assert!(text.contains("retrostream_delivery_ratio 0.75\n"));
+        assert!(text.contains("retrostream_drop_rate 0.25\n"));
+    }
+
+    #[test]
+    fn ratios_are_zero_without_connections_or_frames() {
+        let metrics = Metrics::new();
+        for _ in 0..10 {
+            metrics.increment_frames_sent();
+        }
+        let summary = metrics.get_summary();
+        assert_eq!(summary.delivery_ratio, 0.0);
+        assert_eq!(summary.drop_rate, 0.0);
+
+        assert_eq!(ratio(3, 0), 0.0);
+        assert_eq!(ratio(3, 4), 0.75);
+    }
+
+    #[test]
+    fn measured_ratio_of_one_is_averaged_not_reseeded() {
+        let metrics = Metrics::new();
+        metrics.record_compression_ratio(100, 100);
+        metrics.record_compression_ratio(100, 100);
+        assert_eq!(metrics.get_summary().compression_ratio, 1.0);
+
+        // Blends into the 1.0 average instead of replacing it
+        metrics.record_compression_ratio(100, 50);
+        assert_eq!(metrics.get_summary().compression_ratio, 0.9375);
+    }
+
+    #[test]
+    fn first_ratio_seeds_the_average() {
+        let metrics = Metrics::new();
+        metrics.record_compression_ratio(100, 25);
+        assert_eq!(metrics.get_summary().compression_ratio, 0.25);
+        // Empty frames are skipped
+        metrics.record_compression_ratio(0, 0);
+        assert_eq!(metrics.get_summary().compression_ratio, 0.25);
+    }
+
+    #[test]
+    fn exposition_has_up_and_build_info() {
+        let text = Metrics::new().get_summary().to_prometheus();
+        assert!(text.contains("retrostream_up 1\n"));
+        let build_info = format!("retrostream_build_info{{version=\"{}\"}} 1\n", env!("CARGO_PKG_VERSION"));
+        assert!(text.contains(&build_info), "{}", text);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(label_value(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(label_value("a\nb"), "a\\nb");
+    }
+
+    #[test]
+    fn connection_durations_land_in_their_buckets() {
+        let metrics = Metrics::new();
+        // 0.5s, 10s exactly, 45s, 20min and 2h
+        for secs in [0.5, 10.0, 45.0, 1200.0, 7200.0] {
+            metrics.observe_connection_duration(Duration::from_secs_f64(secs));
+        }
+
+        let summary = metrics.get_summary();
+        // le 1s, 10s, 30s, 1m, 5m, 10m, 30m, 1h, +Inf
+        assert_eq!(summary.connection_duration.buckets, [1, 2, 2, 3, 3, 3, 4, 4, 5]);
+        assert_eq!(summary.avg_session_seconds, 1691.1);
+        let text = summary.to_prometheus();
+        assert!(text.contains("retrostream_connection_duration_ms_bucket{le=\"10000\"} 2\n"));
+        assert!(text.contains("retrostream_connection_duration_ms_bucket{le=\"+Inf\"} 5\n"));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{
+    compression::{encode_jpeg, jpeg_quality, parse_frame_message, FrameDecoder, FrameMessage},
+    config::CompressionFormat,
+    error::AppResult,
+    fanout::{self, RecvError},
+    AppState,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::header,
+    response::IntoResponse,
+};
+use std::convert::Infallible;
+use std::sync::PoisonError;
+use tracing::{debug, info, warn};
+
+const BOUNDARY: &str = "retrostream-frame";
+
+/// Convert a frame message to a JPEG image, passing JPEG payloads through.
+/// Returns `None` for delta frames `decoder` can't reconstruct yet and for
+/// hold markers, where the last part sent stays on screen.
+fn decode_jpeg(decoder: &mut FrameDecoder, message: &[u8], quality: u8) -> AppResult<Option<Vec<u8>>> {
+    let (header, payload) = parse_frame_message(message)?;
+    if header.is_hold {
+        return Ok(None);
+    }
+    if header.format == CompressionFormat::Jpeg {
+        return Ok(Some(payload.to_vec()));
+    }
+
+    match decoder.decode(message)? {
+        Some((header, rgba)) => encode_jpeg(&rgba, header.width, header.height, quality).map(Some),
+        None => Ok(None),
+    }
+}
+
+struct MjpegStream {
+    frames: fanout::Receiver<FrameMessage>,
+    decoder: FrameDecoder,
+    state: AppState,
+    /// Sent before anything from `frames`
+    pending: Option<FrameMessage>,
+}
+
+impl MjpegStream {
+    /// The next multipart body part, or `None` once capture has stopped.
+    async fn next_part(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let message = match self.pending.take() {
+                Some(message) => message,
+                None => match self.frames.recv().await {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("MJPEG client lagging, skipped {} frames", skipped);
+                        self.state.metrics.increment_dropped_frames();
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+            };
+
+            let quality = jpeg_quality(self.state.live_config.borrow().capture.quality);
+            match decode_jpeg(&mut self.decoder, &message, quality) {
+                Ok(Some(jpeg)) => {
+                    self.state.metrics.increment_frames_delivered();
+                    return Some(multipart_part(&jpeg));
+                }
+                Ok(None) => continue,
+                Err(e) => warn!("Skipping frame for MJPEG client: {}", e),
+            }
+        }
+    }
+}
+
+fn multipart_part(jpeg: &[u8]) -> Vec<u8> {
+    let mut part = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        jpeg.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+/// Serve the stream as `multipart/x-mixed-replace` JPEG for clients that
+/// can't speak the WebSocket protocol, such as VLC or an `<img>` tag.
+/// Frames are re-encoded from the shared frame stream at `capture.quality`.
+pub async fn mjpeg_handler(State(state): State<AppState>) -> impl IntoResponse {
+    info!("MJPEG client connected");
+
+    let pending = if state.config.server.send_latest_on_connect {
+        state.latest_frame.read().unwrap_or_else(PoisonError::into_inner).clone()
+    } else {
+        None
+    };
+    let stream = MjpegStream {
+        frames: state.frame_tx.subscribe(),
+        decoder: FrameDecoder::with_dictionary(state.dictionary.clone()),
+        state,
+        pending,
+    };
+    stream.state.viewer_joined.notify_one();
+
+    // The stream is dropped when the client disconnects, ending the body
+    let parts = futures_util::stream::unfold(stream, |mut stream| async move {
+        let part = stream.next_part().await?;
+        Some((Ok::<_, Infallible>(part), stream))
+    });
+
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                format!("multipart/x-mixed-replace; boundary={}", BOUNDARY),
+            ),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        Body::from_stream(parts),
+    )
+}
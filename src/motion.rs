@@ -0,0 +1,178 @@
+use crate::config::MotionConfig;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::broadcast,
+};
+use tracing::{debug, info, warn};
+
+// Compare every Nth pixel in each direction; motion regions are far larger
+// than this so the full-resolution diff isn't worth its cost.
+const SAMPLE_STRIDE: u32 = 8;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MotionEvent {
+    /// Bounding box of the changed area, in frame pixels
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Fraction of sampled pixels that changed (0.0-1.0)
+    pub magnitude: f32,
+    pub timestamp: u64,
+}
+
+pub struct MotionDetector {
+    config: MotionConfig,
+    events: broadcast::Sender<MotionEvent>,
+    previous: Vec<u8>,
+    previous_size: (u32, u32),
+    last_event: Option<Instant>,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig, events: broadcast::Sender<MotionEvent>) -> Self {
+        Self {
+            config,
+            events,
+            previous: Vec::new(),
+            previous_size: (0, 0),
+            last_event: None,
+        }
+    }
+
+    /// Diff the frame against the previous one and publish an event if
+    /// enough of it changed, at most once per debounce period.
+    pub fn process(&mut self, rgba: &[u8], width: u32, height: u32) {
+        let luma = sample_luma(rgba, width, height);
+
+        // Nothing to compare against on the first frame or a resolution change
+        if self.previous_size != (width, height) {
+            self.previous = luma;
+            self.previous_size = (width, height);
+            return;
+        }
+
+        let cols = width.div_ceil(SAMPLE_STRIDE);
+        let mut changed = 0usize;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+
+        for (i, (&now, &before)) in luma.iter().zip(&self.previous).enumerate() {
+            if now.abs_diff(before) > self.config.pixel_threshold {
+                changed += 1;
+                let x = (i as u32 % cols) * SAMPLE_STRIDE;
+                let y = (i as u32 / cols) * SAMPLE_STRIDE;
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+            }
+        }
+
+        self.previous = luma;
+
+        let magnitude = changed as f32 / self.previous.len().max(1) as f32;
+        if changed == 0 || magnitude < self.config.sensitivity {
+            return;
+        }
+
+        let debounce = Duration::from_millis(self.config.debounce_ms);
+        if self.last_event.is_some_and(|t| t.elapsed() < debounce) {
+            return;
+        }
+        self.last_event = Some(Instant::now());
+
+        let event = MotionEvent {
+            x: min_x,
+            y: min_y,
+            width: (max_x + SAMPLE_STRIDE).min(width) - min_x,
+            height: (max_y + SAMPLE_STRIDE).min(height) - min_y,
+            magnitude,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+        };
+
+        debug!("Motion detected: {:?}", event);
+        // No subscribers just means nobody is listening right now
+        let _ = self.events.send(event);
+    }
+}
+
+fn sample_luma(rgba: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let mut luma = Vec::with_capacity(
+        (width.div_ceil(SAMPLE_STRIDE) * height.div_ceil(SAMPLE_STRIDE)) as usize,
+    );
+
+    for y in (0..height).step_by(SAMPLE_STRIDE as usize) {
+        for x in (0..width).step_by(SAMPLE_STRIDE as usize) {
+            let i = ((y * width + x) * 4) as usize;
+            let (r, g, b) = (rgba[i] as u32, rgba[i + 1] as u32, rgba[i + 2] as u32);
+            // Integer BT.601 luma
+            luma.push(((r * 299 + g * 587 + b * 114) / 1000) as u8);
+        }
+    }
+
+    luma
+}
+
+/// Forward motion events to an HTTP webhook as JSON `POST` requests.
+/// Only plain `http://` URLs are supported.
+pub async fn run_webhook(url: String, mut events: broadcast::Receiver<MotionEvent>) {
+    let Some((host, path)) = parse_http_url(&url) else {
+        warn!("Motion webhook URL must start with http://, webhook disabled: {}", url);
+        return;
+    };
+
+    info!("Sending motion events to {}", url);
+
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if let Err(e) = post_json(host, path, &event).await {
+                    warn!("Motion webhook failed: {}", e);
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Motion webhook fell behind, skipped {} events", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Split an `http://host[:port]/path` URL into host and path.
+fn parse_http_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    match rest.find('/') {
+        Some(i) => Some((&rest[..i], &rest[i..])),
+        None => Some((rest, "/")),
+    }
+}
+
+async fn post_json(host: &str, path: &str, event: &MotionEvent) -> anyhow::Result<()> {
+    let body = serde_json::to_string(event)?;
+    let addr = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+
+    let mut stream = tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(&addr)).await??;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut status_line = [0u8; 12];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut status_line)).await??;
+    let status = String::from_utf8_lossy(&status_line[9..12]).to_string();
+    if !status.starts_with('2') {
+        anyhow::bail!("webhook returned HTTP {}", status);
+    }
+
+    Ok(())
+}
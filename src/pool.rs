@@ -0,0 +1,53 @@
+use std::sync::{Arc, Mutex};
+
+/// Frame-sized byte buffers recycled between frames, so steady-state capture
+/// doesn't allocate a fresh buffer for every intermediate step.
+///
+/// A pooled buffer's life:
+/// 1. `take` hands out an empty buffer, reusing an idle one when there is one.
+/// 2. Its holder fills and uses it like any other `Vec`, and may pass it on
+///    (e.g. from the compressor to an encode worker inside an `Arc`).
+/// 3. Whoever is done with it last hands it back with `recycle`, or
+///    `recycle_shared` if it's behind an `Arc`. A buffer that is never handed
+///    back, like a frame message sent to clients, is simply freed.
+///
+/// At most `max_idle` buffers are kept; any more are freed on `recycle`.
+pub struct BufferPool {
+    idle: Mutex<Vec<Vec<u8>>>,
+    max_idle: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_idle: usize) -> Self {
+        Self {
+            idle: Mutex::new(Vec::with_capacity(max_idle)),
+            max_idle,
+        }
+    }
+
+    /// An empty buffer with room for at least `capacity` bytes
+    pub fn take(&self, capacity: usize) -> Vec<u8> {
+        let mut buffer = self.idle.lock().unwrap().pop().unwrap_or_default();
+        buffer.clear();
+        buffer.reserve(capacity);
+        buffer
+    }
+
+    pub fn recycle(&self, buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(buffer);
+        }
+    }
+
+    /// Recycle `buffer` if this is the last reference to it, otherwise just
+    /// drop this reference and leave recycling to the other holder.
+    pub fn recycle_shared(&self, buffer: Arc<Vec<u8>>) {
+        if let Ok(buffer) = Arc::try_unwrap(buffer) {
+            self.recycle(buffer);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{
+    config::{CaptureConfig, CompressionConfig, CompressionFormat, Config, FallbackFrame},
+    error::{AppError, AppResult},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A named bundle of pipeline settings that replaces the `[capture]` and
+/// `[compression]` sections of the config file when selected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub capture: CaptureConfig,
+    pub compression: CompressionConfig,
+}
+
+impl Preset {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            capture: config.capture.clone(),
+            compression: config.compression.clone(),
+        }
+    }
+
+    pub fn apply(self, config: &mut Config) {
+        config.capture = self.capture;
+        config.compression = self.compression;
+    }
+}
+
+/// Presets available without a presets file. User presets with the same
+/// name take precedence.
+pub fn builtin(name: &str) -> Option<Preset> {
+    let Config {
+        mut capture,
+        mut compression,
+        ..
+    } = Config::default();
+
+    match name {
+        "low-bandwidth" => {
+            capture.fps = 10;
+            capture.quality = 0.5;
+            compression.enabled = true;
+            compression.level = 9;
+        }
+        "lossless" => {
+            capture.fps = 15;
+            capture.quality = 1.0;
+            compression.format = CompressionFormat::Png;
+        }
+        "retro-demo" => {
+            capture.fps = 30;
+            capture.fallback = FallbackFrame::Demo;
+            compression.level = 3;
+        }
+        _ => return None,
+    }
+
+    Some(Preset {
+        capture,
+        compression,
+    })
+}
+
+fn read_presets(path: &Path) -> AppResult<BTreeMap<String, Preset>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let content = std::fs::read_to_string(path)?;
+    toml::from_str(&content).map_err(|e| {
+        AppError::ConfigError(format!("Invalid presets file {}: {}", path.display(), e))
+    })
+}
+
+/// Look up a preset in the presets file, then among the built-ins.
+pub fn load(path: &Path, name: &str) -> AppResult<Preset> {
+    read_presets(path)?
+        .remove(name)
+        .or_else(|| builtin(name))
+        .ok_or_else(|| {
+            AppError::ConfigError(format!(
+                "Unknown preset '{}' (not in {} or built-in)",
+                name,
+                path.display()
+            ))
+        })
+}
+
+/// Add or replace a preset in the presets file, keeping the others.
+pub fn save(path: &Path, name: &str, preset: Preset) -> AppResult<()> {
+    let mut presets = read_presets(path)?;
+    presets.insert(name.to_string(), preset);
+
+    let content = toml::to_string_pretty(&presets)
+        .map_err(|e| AppError::ConfigError(format!("Failed to serialize presets: {}", e)))?;
+    std::fs::write(path, content)?;
+
+    Ok(())
+}
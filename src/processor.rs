@@ -0,0 +1,206 @@
+use crate::config::{CaptureRegion, ProcessorConfig, Redaction, RedactionStyle};
+use image::{imageops, RgbaImage};
+
+/// An effect applied to each captured frame before it is encoded, see
+/// `capture.processors`. Runs on the capture task, so it should be quick.
+pub trait FrameProcessor: Send + Sync {
+    /// Modify the RGBA pixels of a `width`×`height` frame in place
+    fn process(&self, rgba: &mut [u8], width: u32, height: u32);
+}
+
+/// Build `capture.processors`, in the order they run.
+pub fn from_config(configs: &[ProcessorConfig]) -> Vec<Box<dyn FrameProcessor>> {
+    configs
+        .iter()
+        .map(|config| -> Box<dyn FrameProcessor> {
+            match *config {
+                ProcessorConfig::Scanlines { intensity } => Box::new(Scanlines { intensity }),
+                ProcessorConfig::Grayscale => Box::new(Grayscale),
+                ProcessorConfig::BlurRegion { region, sigma } => Box::new(BlurRegion { region, sigma }),
+            }
+        })
+        .collect()
+}
+
+/// Darkens every odd row by `intensity`
+pub struct Scanlines {
+    pub intensity: f32,
+}
+
+impl FrameProcessor for Scanlines {
+    fn process(&self, rgba: &mut [u8], width: u32, _height: u32) {
+        let keep = ((1.0 - self.intensity) * 256.0) as u32;
+        for row in rgba.chunks_exact_mut(width as usize * 4).skip(1).step_by(2) {
+            for pixel in row.chunks_exact_mut(4) {
+                for c in &mut pixel[..3] {
+                    *c = (*c as u32 * keep / 256) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Replaces every pixel with its BT.601 luma, like `ColorFormat::Gray` but
+/// staying RGBA
+pub struct Grayscale;
+
+impl FrameProcessor for Grayscale {
+    fn process(&self, rgba: &mut [u8], _width: u32, _height: u32) {
+        for pixel in rgba.chunks_exact_mut(4) {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(u32::from);
+            let luma = ((r * 299 + g * 587 + b * 114) / 1000) as u8;
+            pixel[..3].fill(luma);
+        }
+    }
+}
+
+/// Blur strength for `RedactionStyle::Blur`, enough to smear text of any
+/// usual size into a smudge
+const REDACTION_BLUR_SIGMA: f32 = 24.0;
+
+/// `capture.redactions`, applied to source frames before cropping
+pub struct Redact {
+    pub redactions: Vec<Redaction>,
+}
+
+impl FrameProcessor for Redact {
+    fn process(&self, rgba: &mut [u8], width: u32, height: u32) {
+        for redaction in &self.redactions {
+            match redaction.style {
+                RedactionStyle::Fill => {
+                    let Some((x, y, region_width, region_height)) = clamp(redaction.region, width, height)
+                    else {
+                        continue;
+                    };
+                    let stride = width as usize * 4;
+                    for row in y..y + region_height {
+                        let start = row as usize * stride + x as usize * 4;
+                        for pixel in rgba[start..start + region_width as usize * 4].chunks_exact_mut(4) {
+                            pixel.copy_from_slice(&[0, 0, 0, 255]);
+                        }
+                    }
+                }
+                RedactionStyle::Blur => BlurRegion {
+                    region: redaction.region,
+                    sigma: REDACTION_BLUR_SIGMA,
+                }
+                .process(rgba, width, height),
+            }
+        }
+    }
+}
+
+/// `region` clipped to a `width`×`height` frame as `(x, y, width, height)`,
+/// or `None` if none of it is inside
+fn clamp(region: CaptureRegion, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+    let x = region.x.min(width);
+    let y = region.y.min(height);
+    let region_width = region.width.min(width - x);
+    let region_height = region.height.min(height - y);
+    (region_width > 0 && region_height > 0).then_some((x, y, region_width, region_height))
+}
+
+/// Gaussian blur over `region`, clamped to the frame
+pub struct BlurRegion {
+    pub region: CaptureRegion,
+    pub sigma: f32,
+}
+
+impl FrameProcessor for BlurRegion {
+    fn process(&self, rgba: &mut [u8], width: u32, height: u32) {
+        let Some((x, y, region_width, region_height)) = clamp(self.region, width, height) else {
+            return;
+        };
+
+        let stride = width as usize * 4;
+        let row_len = region_width as usize * 4;
+        let rows = (y..y + region_height).map(|row| row as usize * stride + x as usize * 4);
+
+        let mut region = Vec::with_capacity(row_len * region_height as usize);
+        for start in rows.clone() {
+            region.extend_from_slice(&rgba[start..start + row_len]);
+        }
+        let Some(region) = RgbaImage::from_raw(region_width, region_height, region) else {
+            return;
+        };
+
+        let blurred = imageops::blur(&region, self.sigma);
+        for (start, blurred_row) in rows.zip(blurred.as_raw().chunks_exact(row_len)) {
+            rgba[start..start + row_len].copy_from_slice(blurred_row);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `width`×`height` frame of mid gray
+    fn gray(width: u32, height: u32) -> Vec<u8> {
+        [128, 128, 128, 255].repeat((width * height) as usize)
+    }
+
+    fn row(rgba: &[u8], width: u32, y: u32) -> &[u8] {
+        let stride = width as usize * 4;
+        &rgba[y as usize * stride..(y as usize + 1) * stride]
+    }
+
+    #[test]
+    fn scanlines_darken_alternating_rows() {
+        let mut rgba = gray(8, 6);
+        Scanlines { intensity: 0.5 }.process(&mut rgba, 8, 6);
+
+        for y in 0..6 {
+            let expected = if y % 2 == 0 { [128, 128, 128, 255] } else { [64, 64, 64, 255] };
+            for pixel in row(&rgba, 8, y).chunks_exact(4) {
+                assert_eq!(pixel, expected, "row {}", y);
+            }
+        }
+    }
+
+    #[test]
+    fn blur_stays_inside_its_region() {
+        let (width, height) = (16, 16);
+        let mut rgba: Vec<u8> = (0..width * height).flat_map(|i| [(i * 37 % 256) as u8, 0, 0, 255]).collect();
+        let original = rgba.clone();
+        let region = CaptureRegion { x: 4, y: 4, width: 8, height: 8 };
+        BlurRegion { region, sigma: 4.0 }.process(&mut rgba, width, height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = ((y * width + x) * 4) as usize;
+                let inside = (4..12).contains(&x) && (4..12).contains(&y);
+                if !inside {
+                    assert_eq!(rgba[i..i + 4], original[i..i + 4], "({}, {})", x, y);
+                }
+            }
+        }
+        assert_ne!(rgba, original);
+    }
+
+    #[test]
+    fn redaction_fills_only_its_region() {
+        let (width, height) = (12, 10);
+        let mut rgba: Vec<u8> = (0..width * height).flat_map(|i| [i as u8, 100, 200, 255]).collect();
+        let original = rgba.clone();
+        let redact = Redact {
+            redactions: vec![Redaction {
+                // Runs off the right edge, clamped to it
+                region: CaptureRegion { x: 8, y: 2, width: 10, height: 3 },
+                style: RedactionStyle::Fill,
+            }],
+        };
+        redact.process(&mut rgba, width, height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = ((y * width + x) * 4) as usize;
+                if x >= 8 && (2..5).contains(&y) {
+                    assert_eq!(rgba[i..i + 4], [0, 0, 0, 255], "({}, {})", x, y);
+                } else {
+                    assert_eq!(rgba[i..i + 4], original[i..i + 4], "({}, {})", x, y);
+                }
+            }
+        }
+    }
+}
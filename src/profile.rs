@@ -0,0 +1,182 @@
+use crate::{
+    compression::{jpeg_quality, Compressor, FrameMessage},
+    config::{CompressionConfig, CompressionFormat, OverflowStrategy},
+    fanout,
+};
+use std::collections::HashMap;
+use std::sync::{atomic::AtomicU64, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+/// An unencoded captured frame, published only while client profiles exist
+pub struct RawFrame {
+    pub rgba: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Frame rate and JPEG quality requested by a client with `set_quality`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Profile {
+    pub fps: u32,
+    /// JPEG quality, 1-100
+    pub quality: u8,
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} FPS, JPEG quality {}", self.fps, self.quality)
+    }
+}
+
+impl Profile {
+    pub fn new(fps: u32, quality: f32) -> Self {
+        Self {
+            fps: fps.max(1),
+            quality: jpeg_quality(quality),
+        }
+    }
+
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.fps as f64)
+    }
+}
+
+/// Encodes the raw capture once per distinct client profile and fans each
+/// encoding out to the clients that asked for it.
+///
+/// Every active profile costs a full JPEG encode per frame on top of the
+/// main stream, so the number of profiles is capped; clients sharing a
+/// profile share its encoder.
+pub struct Profiles {
+    raw_tx: broadcast::Sender<Arc<RawFrame>>,
+    active: ActiveProfiles,
+    max_profiles: usize,
+    compression: CompressionConfig,
+    frame_counter: Arc<AtomicU64>,
+    buffer_size: usize,
+    overflow_strategy: OverflowStrategy,
+}
+
+impl Profiles {
+    pub fn new(
+        compression: CompressionConfig,
+        max_profiles: usize,
+        buffer_size: usize,
+        overflow_strategy: OverflowStrategy,
+        frame_counter: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            raw_tx: broadcast::channel(buffer_size).0,
+            active: ActiveProfiles::default(),
+            max_profiles,
+            compression,
+            frame_counter,
+            buffer_size,
+            overflow_strategy,
+        }
+    }
+
+    /// Sender the capture loop publishes raw frames to
+    pub fn raw_sender(&self) -> broadcast::Sender<Arc<RawFrame>> {
+        self.raw_tx.clone()
+    }
+
+    /// Subscribe to frames encoded for `profile`, starting its encoder if
+    /// no other client uses it. Returns `None` when `max_profiles` distinct
+    /// profiles are already active.
+    pub fn subscribe(&self, profile: Profile) -> Option<fanout::Receiver<FrameMessage>> {
+        let mut active = self.active.lock().unwrap();
+        if let Some(frames) = active.get(&profile) {
+            return Some(frames.subscribe());
+        }
+        if active.len() >= self.max_profiles {
+            return None;
+        }
+
+        let frames = fanout::channel(self.buffer_size, self.overflow_strategy);
+        let rx = frames.subscribe();
+        active.insert(profile, frames.clone());
+        info!("Starting encoder for client profile {} ({} active)", profile, active.len());
+
+        let compression = CompressionConfig {
+            format: CompressionFormat::Jpeg,
+            ..self.compression.clone()
+        };
+        // Shares the main frame counter so IDs stay unique across streams
+        let compressor = Compressor::new(
+            compression,
+            profile.quality as f32 / 100.0,
+            profile.fps,
+            self.frame_counter.clone(),
+        );
+        tokio::spawn(run_encoder(
+            profile,
+            compressor,
+            self.raw_tx.subscribe(),
+            frames,
+            self.active.clone(),
+        ));
+
+        Some(rx)
+    }
+}
+
+type ActiveProfiles = Arc<Mutex<HashMap<Profile, fanout::Sender<FrameMessage>>>>;
+
+/// Encode raw frames for one profile at its frame rate until its last
+/// client leaves.
+async fn run_encoder(
+    profile: Profile,
+    mut compressor: Compressor,
+    mut raw_rx: broadcast::Receiver<Arc<RawFrame>>,
+    frames: fanout::Sender<FrameMessage>,
+    active: ActiveProfiles,
+) {
+    let interval = profile.frame_interval();
+    let mut next_due: Option<Instant> = None;
+
+    loop {
+        let frame = match raw_rx.recv().await {
+            Ok(frame) => frame,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                debug!("Encoder for profile {} skipped {} frames", profile, skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        {
+            // Checked under the lock so a client can't subscribe in between
+            let mut active = active.lock().unwrap();
+            if frames.receiver_count() == 0 {
+                active.remove(&profile);
+                info!("Stopping encoder for client profile {}, no clients left", profile);
+                return;
+            }
+        }
+
+        // A little slack so capture timing jitter doesn't skip a frame
+        // that is due at the same rate as capture
+        let now = Instant::now();
+        if next_due.is_some_and(|due| now + interval / 8 < due) {
+            continue;
+        }
+        let base = match next_due {
+            Some(due) if now < due + interval => due,
+            _ => now,
+        };
+        next_due = Some(base + interval);
+
+        match compressor.create_frame_message(frame.rgba.clone(), frame.width, frame.height) {
+            Ok(message) => {
+                // With `block`, this waits on the profile's slowest client
+                let _ = frames.send(message).await;
+            }
+            Err(e) => warn!("Encoding for client profile {} failed: {}", profile, e),
+        }
+    }
+
+    active.lock().unwrap().remove(&profile);
+}
@@ -0,0 +1,198 @@
+use crate::{
+    compression::read_frame_header,
+    error::{AppError, AppResult},
+};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Start of every recording, followed by `RECORDING_VERSION`
+const RECORDING_MAGIC: [u8; 4] = *b"RSRC";
+
+/// Recording file layout version. Version 1 is the file header followed by
+/// `[u32 LE length][frame message]` records, each message exactly as sent
+/// to clients (see `compression::PROTOCOL_VERSION`).
+const RECORDING_VERSION: u8 = 1;
+
+const RECORDING_HEADER_LEN: u64 = RECORDING_MAGIC.len() as u64 + 1;
+
+/// Longest pause honoured between replayed frames, so a recording that sat
+/// idle doesn't appear to hang on replay
+const MAX_REPLAY_GAP: Duration = Duration::from_secs(5);
+
+/// Appends every frame message to a file for later `--replay`.
+pub struct FrameRecorder {
+    file: File,
+    path: PathBuf,
+    written: u64,
+}
+
+impl FrameRecorder {
+    pub fn new(path: PathBuf) -> AppResult<Self> {
+        let mut file = File::create(&path)?;
+        file.write_all(&RECORDING_MAGIC)?;
+        file.write_all(&[RECORDING_VERSION])?;
+        info!("Recording frames to {}", path.display());
+
+        Ok(Self {
+            file,
+            path,
+            written: 0,
+        })
+    }
+
+    pub fn write(&mut self, message: &[u8]) -> AppResult<()> {
+        // Length and message in one write so a crash can at worst leave a
+        // truncated last record, which replay ignores
+        let mut record = Vec::with_capacity(4 + message.len());
+        record.extend_from_slice(&(message.len() as u32).to_le_bytes());
+        record.extend_from_slice(message);
+        self.file.write_all(&record)?;
+
+        self.written += 1;
+        if self.written.is_multiple_of(300) {
+            debug!("Recorded {} frames to {}", self.written, self.path.display());
+        }
+
+        Ok(())
+    }
+}
+
+/// Plays a recording back frame by frame, looping at the end, with the
+/// gaps between the frames' original timestamps.
+pub struct FrameReplayer {
+    reader: BufReader<File>,
+    path: PathBuf,
+    /// Read ahead so the wait before it is known
+    next: Vec<u8>,
+    last_timestamp: Option<u64>,
+    /// Wait used when timestamps don't give one, e.g. when looping
+    fallback_interval: Duration,
+}
+
+impl FrameReplayer {
+    pub fn open(path: &Path, fallback_interval: Duration) -> AppResult<Self> {
+        let invalid = |reason: &str| {
+            AppError::CaptureError(format!("Cannot replay {}: {}", path.display(), reason))
+        };
+
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0; RECORDING_HEADER_LEN as usize];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| invalid("file too short"))?;
+        if header[..4] != RECORDING_MAGIC {
+            return Err(invalid("not a recording"));
+        }
+        if header[4] != RECORDING_VERSION {
+            return Err(invalid(&format!(
+                "unsupported recording version {} (expected {})",
+                header[4], RECORDING_VERSION
+            )));
+        }
+
+        let first = read_record(&mut reader)?.ok_or_else(|| invalid("no frames recorded"))?;
+        read_frame_header(&first).map_err(|e| invalid(&e.to_string()))?;
+        info!("Replaying frames from {}", path.display());
+
+        Ok(Self {
+            reader,
+            path: path.to_path_buf(),
+            next: first,
+            last_timestamp: None,
+            fallback_interval,
+        })
+    }
+
+    /// How long to wait before sending the next frame
+    pub fn delay(&self) -> Duration {
+        let next = read_frame_header(&self.next).map(|h| h.timestamp).ok();
+        match (self.last_timestamp, next) {
+            (Some(last), Some(next)) if next >= last => {
+                Duration::from_millis(next - last).min(MAX_REPLAY_GAP)
+            }
+            _ => self.fallback_interval,
+        }
+    }
+
+    /// The next recorded frame message, unchanged from when it was recorded
+    pub fn next_frame(&mut self) -> AppResult<Vec<u8>> {
+        let following = match read_record(&mut self.reader)? {
+            Some(message) => message,
+            None => {
+                debug!("End of recording {}, looping", self.path.display());
+                self.reader.seek(SeekFrom::Start(RECORDING_HEADER_LEN))?;
+                read_record(&mut self.reader)?.ok_or_else(|| {
+                    AppError::CaptureError(format!("Recording {} is empty", self.path.display()))
+                })?
+            }
+        };
+
+        let frame = std::mem::replace(&mut self.next, following);
+        self.last_timestamp = read_frame_header(&frame).map(|h| h.timestamp).ok();
+        Ok(frame)
+    }
+}
+
+/// Read one `[u32 LE length][message]` record, or `None` at the end of the
+/// file, including a truncated last record.
+fn read_record(reader: &mut impl Read) -> AppResult<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut message = vec![0; u32::from_le_bytes(len) as usize];
+    match reader.read_exact(&mut message) {
+        Ok(()) => Ok(Some(message)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compression::Compressor, config::Config, testcard, testing::TempPath};
+    use std::sync::{atomic::AtomicU64, Arc};
+
+    fn demo_frames(count: usize) -> Vec<Vec<u8>> {
+        let config = Config::default();
+        let mut compressor = Compressor::new(config.compression, 0.8, 30, Arc::new(AtomicU64::new(0)));
+        (0..count)
+            .map(|i| {
+                let rgba = testcard::demo_frame(64, 36, i as f32 * 0.1);
+                compressor.create_frame_message(rgba, 64, 36).unwrap().to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_recorded_frames_byte_identically() {
+        let path = TempPath::new("replay.rsrc");
+        let frames = demo_frames(3);
+        let mut recorder = FrameRecorder::new(path.0.clone()).unwrap();
+        for frame in &frames {
+            recorder.write(frame).unwrap();
+        }
+        drop(recorder);
+
+        let mut replayer = FrameReplayer::open(&path.0, Duration::from_millis(33)).unwrap();
+        for frame in &frames {
+            assert_eq!(&replayer.next_frame().unwrap(), frame);
+        }
+        // Loops back to the start
+        assert_eq!(replayer.next_frame().unwrap(), frames[0]);
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_recordings() {
+        let path = TempPath::new("not-a-recording");
+        std::fs::write(&path.0, b"hello world").unwrap();
+        assert!(FrameReplayer::open(&path.0, Duration::from_millis(33)).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+use std::time::{Duration, Instant};
+
+/// Viewer sessions, so a client that reconnects with `?session=` or a
+/// `resume` command after a dropped connection counts as the same viewer.
+/// A session can be resumed for `ttl` after its connection ends, by one
+/// connection at a time.
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    next: AtomicU64,
+    ttl: Duration,
+}
+
+enum Session {
+    /// In use by an open connection
+    Attached,
+    /// Its connection ended at this time
+    Detached(Instant),
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            next: AtomicU64::new(0),
+            ttl,
+        }
+    }
+
+    /// Start a new session for a fresh connection, returning its id.
+    pub fn start(&self) -> String {
+        let id = self.generate_id();
+        let mut sessions = self.sessions.lock().unwrap();
+        self.evict_expired(&mut sessions);
+        sessions.insert(id.clone(), Session::Attached);
+        id
+    }
+
+    /// Attach a reconnecting client to session `id`. Fails if the session
+    /// is unknown, expired, or still in use by another connection.
+    pub fn resume(&self, id: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.evict_expired(&mut sessions);
+        match sessions.get_mut(id) {
+            Some(session @ Session::Detached(_)) => {
+                *session = Session::Attached;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Mark session `id` as resumable once its connection has ended.
+    pub fn detach(&self, id: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(id) {
+            *session = Session::Detached(Instant::now());
+        }
+    }
+
+    /// Forget session `id`, e.g. one started for a connection that then
+    /// resumed another.
+    pub fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    fn evict_expired(&self, sessions: &mut HashMap<String, Session>) {
+        sessions.retain(|_, session| match session {
+            Session::Attached => true,
+            Session::Detached(at) => at.elapsed() < self.ttl,
+        });
+    }
+
+    /// 128 unguessable bits as hex. `RandomState` is randomly keyed per
+    /// instance, and the counter keeps ids unique regardless.
+    fn generate_id(&self) -> String {
+        let count = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut halves = [0u64; 2];
+        for half in &mut halves {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(count);
+            *half = hasher.finish();
+        }
+        format!("{:016x}{:016x}", halves[0], halves[1])
+    }
+}
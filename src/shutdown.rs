@@ -0,0 +1,33 @@
+use std::process::ExitCode;
+
+/// Why the server stopped, mapped to a process exit code so supervisors
+/// such as systemd can tell a requested stop from a failure.
+///
+/// | Code | Meaning                                          |
+/// |------|--------------------------------------------------|
+/// | 0    | Clean shutdown on Ctrl+C / SIGINT                |
+/// | 1    | Startup failure (bad config, port in use, ...)   |
+/// | 2    | Capture task returned an error                   |
+/// | 3    | Capture task panicked                            |
+/// | 4    | HTTP server failed                               |
+/// | 5    | HTTP server stopped unexpectedly without error   |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    Signal,
+    CaptureFailed,
+    CapturePanicked,
+    ServerFailed,
+    ServerStopped,
+}
+
+impl ShutdownReason {
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            ShutdownReason::Signal => 0,
+            ShutdownReason::CaptureFailed => 2,
+            ShutdownReason::CapturePanicked => 3,
+            ShutdownReason::ServerFailed => 4,
+            ShutdownReason::ServerStopped => 5,
+        })
+    }
+}
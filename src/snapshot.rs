@@ -0,0 +1,141 @@
+use crate::{
+    compression::{encode_jpeg, encode_png, jpeg_quality, FrameDecoder},
+    error::AppError,
+    AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use image::codecs::png::CompressionType;
+use serde::Deserialize;
+use std::sync::PoisonError;
+use tracing::warn;
+
+/// Image format for `GET /snapshot?format=...`
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    /// At `capture.quality`
+    #[default]
+    Jpeg,
+    /// Lossless
+    Png,
+}
+
+impl SnapshotFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            SnapshotFormat::Jpeg => "image/jpeg",
+            SnapshotFormat::Png => "image/png",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SnapshotQuery {
+    #[serde(default)]
+    format: SnapshotFormat,
+}
+
+/// Return the latest keyframe as a standalone image, whatever the stream's
+/// `compression.format`. Encoding happens per request rather than in the
+/// capture loop, so it is only paid for when someone asks for a still.
+pub async fn snapshot_handler(
+    State(state): State<AppState>,
+    Query(query): Query<SnapshotQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let message = state
+        .latest_frame
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "No frame captured yet".to_string()))?;
+    let quality = jpeg_quality(state.live_config.borrow().capture.quality);
+    let format = query.format;
+    let dictionary = state.dictionary.clone();
+
+    // Decoding and encoding a full frame takes a while, keep it off the runtime
+    let image = tokio::task::spawn_blocking(move || {
+        let (header, rgba) = FrameDecoder::with_dictionary(dictionary)
+            .decode(&message)?
+            .ok_or_else(|| AppError::CompressionError("Latest frame is not a keyframe".to_string()))?;
+        match format {
+            SnapshotFormat::Jpeg => encode_jpeg(&rgba, header.width, header.height, quality),
+            SnapshotFormat::Png => {
+                encode_png(&rgba, header.width, header.height, CompressionType::Default)
+            }
+        }
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| {
+        warn!("Snapshot failed: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+
+    Ok(([(header::CONTENT_TYPE, format.content_type())], image))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compression::Compressor, testcard, testing, AppState};
+    use axum::http::{header, StatusCode};
+    use std::sync::{atomic::AtomicU64, Arc};
+
+    /// App state whose latest frame is a demo frame
+    fn state_with_frame() -> AppState {
+        let state = testing::state(|_| {});
+        let mut compressor =
+            Compressor::new(state.config.compression.clone(), 0.8, 30, Arc::new(AtomicU64::new(0)));
+        let frame = compressor
+            .create_frame_message(testcard::demo_frame(64, 36, 0.0), 64, 36)
+            .unwrap();
+        *state.latest_frame.write().unwrap() = Some(frame);
+        state
+    }
+
+    #[tokio::test]
+    async fn png_snapshot_has_png_signature() {
+        let response = testing::get(&state_with_frame(), "/snapshot?format=png").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let png = testing::body(response).await;
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[tokio::test]
+    async fn snapshot_content_type_follows_format() {
+        let state = state_with_frame();
+        for (uri, content_type) in [
+            ("/snapshot", "image/jpeg"),
+            ("/snapshot?format=jpeg", "image/jpeg"),
+            ("/snapshot?format=png", "image/png"),
+        ] {
+            let response = testing::get(&state, uri).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], content_type, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_before_first_frame_is_unavailable() {
+        let response = testing::get(&testing::state(|_| {}), "/snapshot").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn snapshot_survives_a_poisoned_lock() {
+        let state = state_with_frame();
+        let latest = state.latest_frame.clone();
+        let panicked = std::thread::spawn(move || {
+            let _guard = latest.write().unwrap();
+            panic!("poisoning the latest frame");
+        })
+        .join();
+        assert!(panicked.is_err() && state.latest_frame.is_poisoned());
+
+        let response = testing::get(&state, "/snapshot").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
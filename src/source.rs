@@ -0,0 +1,528 @@
+use crate::{
+    capture,
+    config::{CaptureConfig, CaptureMode, HdrTonemap},
+    error::{AppError, AppResult},
+    testcard,
+};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use xcap::{Monitor, Window};
+
+/// Size of `demo` mode frames when `capture.width`/`capture.height` are unset
+const DEFAULT_DEMO_SIZE: (u32, u32) = (1280, 720);
+
+/// How long the `exclude_windows` rectangles are reused before windows are
+/// listed again. Listing them every frame is too slow, so a moved window is
+/// masked in its new place within this long.
+const EXCLUDED_WINDOWS_REFRESH: Duration = Duration::from_secs(1);
+
+/// Where `ScreenCapture` gets its frames. Everything after the raw pixels
+/// (cropping, scaling, encoding, fallback frames) is shared by all sources.
+pub trait FrameSource: Send + Sync {
+    /// Get ready to capture, e.g. find the monitor. Retried with backoff
+    /// at startup, see `capture.init_retries`, and called again after
+    /// repeated capture failures, see `capture.reinit_after_failures`.
+    fn open(&mut self) -> AppResult<()> {
+        Ok(())
+    }
+
+    /// The next frame as RGBA pixels, with its width and height
+    fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)>;
+
+    /// Switch to another monitor, for sources that have them. Takes effect
+    /// on the next `open`.
+    fn select_monitor(&mut self, _index: Option<usize>) {}
+}
+
+/// Build the source for `capture.mode`.
+pub fn from_config(config: &CaptureConfig) -> AppResult<Box<dyn FrameSource>> {
+    Ok(match config.mode {
+        CaptureMode::Auto | CaptureMode::Screen => Box::new(MonitorSource::new(config)),
+        CaptureMode::Demo => {
+            let width = config.width.unwrap_or(DEFAULT_DEMO_SIZE.0);
+            let height = config.height.unwrap_or(DEFAULT_DEMO_SIZE.1);
+            Box::new(DemoSource::new(width, height))
+        }
+        CaptureMode::File => {
+            let path = config.source_file.as_deref().ok_or_else(|| {
+                AppError::ConfigError("capture.mode = \"file\" needs capture.source_file".to_string())
+            })?;
+            Box::new(FileSource::open(path, config.hdr_tonemap)?)
+        }
+        CaptureMode::Window => Box::new(WindowSource::new(config)),
+    })
+}
+
+/// Captures a monitor, or all of them with `capture_all_monitors`, with
+/// xcap. Monitors are only looked up in `open`, so capture fails until then.
+pub struct MonitorSource {
+    /// The chosen monitor, or every monitor
+    monitors: Vec<Monitor>,
+    index: Option<usize>,
+    all_monitors: bool,
+    exclude_windows: Vec<String>,
+    window_exclusion_warned: bool,
+    /// Desktop rectangles of the windows matching `exclude_windows`
+    excluded: Vec<WindowRect>,
+    excluded_listed_at: Option<Instant>,
+}
+
+/// Where a window sits on the virtual desktop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WindowRect {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+}
+
+/// One monitor's capture and where it sits on the virtual desktop
+struct Tile {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+/// Paint `windows` black in a `width`×`height` frame whose top-left corner
+/// is at desktop position `origin`, clamped to the frame
+fn black_out(rgba: &mut [u8], width: u32, height: u32, origin: (i64, i64), windows: &[WindowRect]) {
+    let (origin_x, origin_y) = origin;
+    for window in windows {
+        let x0 = (window.x as i64 - origin_x).clamp(0, width as i64) as usize;
+        let y0 = (window.y as i64 - origin_y).clamp(0, height as i64) as usize;
+        let x1 = (window.x as i64 - origin_x + window.width as i64).clamp(0, width as i64) as usize;
+        let y1 = (window.y as i64 - origin_y + window.height as i64).clamp(0, height as i64) as usize;
+
+        for y in y0..y1 {
+            let row = y * width as usize * 4;
+            for px in rgba[row + x0 * 4..row + x1 * 4].chunks_exact_mut(4) {
+                px.copy_from_slice(&[0, 0, 0, 255]);
+            }
+        }
+    }
+}
+
+/// Lay `tiles` out by their desktop position in one frame just big enough
+/// to hold them all, black wherever none of them covers. Overlapping tiles
+/// are drawn in order.
+fn composite(tiles: &[Tile]) -> (Vec<u8>, u32, u32) {
+    let left = tiles.iter().map(|t| t.x as i64).min().unwrap_or(0);
+    let top = tiles.iter().map(|t| t.y as i64).min().unwrap_or(0);
+    let right = tiles.iter().map(|t| t.x as i64 + t.width as i64).max().unwrap_or(0);
+    let bottom = tiles.iter().map(|t| t.y as i64 + t.height as i64).max().unwrap_or(0);
+    let width = (right - left) as usize;
+    let height = (bottom - top) as usize;
+
+    let mut rgba = [0, 0, 0, 255].repeat(width * height);
+    for tile in tiles {
+        let x = (tile.x as i64 - left) as usize;
+        let y = (tile.y as i64 - top) as usize;
+        let row_len = tile.width as usize * 4;
+        for (row, pixels) in tile.rgba.chunks_exact(row_len).enumerate() {
+            let start = ((y + row) * width + x) * 4;
+            rgba[start..start + row_len].copy_from_slice(pixels);
+        }
+    }
+
+    (rgba, width as u32, height as u32)
+}
+
+impl MonitorSource {
+    pub fn new(config: &CaptureConfig) -> Self {
+        Self {
+            monitors: Vec::new(),
+            index: config.monitor_index,
+            all_monitors: config.capture_all_monitors,
+            exclude_windows: config.exclude_windows.clone(),
+            window_exclusion_warned: false,
+            excluded: Vec::new(),
+            excluded_listed_at: None,
+        }
+    }
+
+    /// Pick the configured monitor, falling back to the primary (or first)
+    /// one if no index is set or it is out of range.
+    fn find_monitor(index: Option<usize>) -> AppResult<Monitor> {
+        let monitors = Monitor::all()
+            .map_err(|e| AppError::CaptureError(format!("Failed to list monitors: {}", e)))?;
+
+        let primary = monitors
+            .iter()
+            .position(|m| m.is_primary().unwrap_or(false))
+            .unwrap_or(0);
+        let index = match index {
+            Some(i) if i < monitors.len() => i,
+            Some(i) => {
+                warn!(
+                    "monitor_index {} is out of range ({} monitors found), using the primary monitor",
+                    i,
+                    monitors.len()
+                );
+                primary
+            }
+            None => primary,
+        };
+
+        monitors.into_iter().nth(index).ok_or(AppError::NoMonitorAvailable)
+    }
+
+    /// Black out windows listed in `exclude_windows` so a viewer running on
+    /// the captured machine doesn't show the stream inside itself. xcap has
+    /// no native exclusion, so windows stacked above an excluded one are
+    /// masked as well. `origin` is the desktop position of the frame's
+    /// top-left corner.
+    fn mask_excluded_windows(&mut self, rgba: &mut [u8], width: u32, height: u32, origin: (i64, i64)) {
+        if self.exclude_windows.is_empty() {
+            return;
+        }
+        if self.excluded_listed_at.is_none_or(|at| at.elapsed() >= EXCLUDED_WINDOWS_REFRESH) {
+            self.excluded = self.list_excluded_windows();
+            self.excluded_listed_at = Some(Instant::now());
+        }
+        black_out(rgba, width, height, origin, &self.excluded);
+    }
+
+    /// Rectangles of the visible windows whose title matches
+    /// `exclude_windows`, or none where windows can't be listed
+    fn list_excluded_windows(&mut self) -> Vec<WindowRect> {
+        let windows = match Window::all() {
+            Ok(windows) => windows,
+            Err(e) => {
+                if !self.window_exclusion_warned {
+                    warn!("Window exclusion unsupported on this platform ({}), capturing all windows", e);
+                    self.window_exclusion_warned = true;
+                }
+                return Vec::new();
+            }
+        };
+
+        windows
+            .iter()
+            .filter(|window| !window.is_minimized().unwrap_or(false))
+            .filter(|window| {
+                let title = window.title().unwrap_or_default();
+                self.exclude_windows.iter().any(|p| title.contains(p.as_str()))
+            })
+            .filter_map(|window| {
+                Some(WindowRect {
+                    x: window.x().ok()?,
+                    y: window.y().ok()?,
+                    width: window.width().ok()?,
+                    height: window.height().ok()?,
+                })
+            })
+            .collect()
+    }
+
+}
+
+impl FrameSource for MonitorSource {
+    fn open(&mut self) -> AppResult<()> {
+        self.monitors = if self.all_monitors {
+            let monitors = Monitor::all()
+                .map_err(|e| AppError::CaptureError(format!("Failed to list monitors: {}", e)))?;
+            if monitors.is_empty() {
+                return Err(AppError::NoMonitorAvailable);
+            }
+            let names: Vec<String> = monitors
+                .iter()
+                .map(|m| m.name().unwrap_or_else(|_| "<unknown>".to_string()))
+                .collect();
+            info!("Capturing all {} monitors: {}", monitors.len(), names.join(", "));
+            monitors
+        } else {
+            let monitor = Self::find_monitor(self.index)?;
+            info!(
+                "Capturing monitor {}",
+                monitor.name().unwrap_or_else(|_| "<unknown>".to_string())
+            );
+            vec![monitor]
+        };
+        Ok(())
+    }
+
+    fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+        if self.monitors.is_empty() {
+            return Err(AppError::NoMonitorAvailable);
+        }
+
+        let mut tiles = Vec::with_capacity(self.monitors.len());
+        for monitor in &self.monitors {
+            let image = monitor
+                .capture_image()
+                .map_err(|e| AppError::CaptureError(format!("Screen capture failed: {}", e)))?;
+            let (width, height) = image.dimensions();
+            tiles.push(Tile {
+                x: monitor.x().unwrap_or(0),
+                y: monitor.y().unwrap_or(0),
+                width,
+                height,
+                rgba: image.into_raw(),
+            });
+        }
+
+        let origin = (
+            tiles.iter().map(|t| t.x as i64).min().unwrap_or(0),
+            tiles.iter().map(|t| t.y as i64).min().unwrap_or(0),
+        );
+        // A single monitor needs no compositing
+        let (mut rgba, width, height) = match <[Tile; 1]>::try_from(tiles) {
+            Ok([tile]) => (tile.rgba, tile.width, tile.height),
+            Err(tiles) => composite(&tiles),
+        };
+        self.mask_excluded_windows(&mut rgba, width, height, origin);
+        Ok((rgba, width, height))
+    }
+
+    fn select_monitor(&mut self, index: Option<usize>) {
+        self.index = index;
+        // Found again on the next open
+        self.monitors.clear();
+    }
+}
+
+/// Captures a single window with xcap, found by id or title in `open`.
+pub struct WindowSource {
+    window: Option<Window>,
+    id: Option<u32>,
+    title: Option<String>,
+}
+
+impl WindowSource {
+    pub fn new(config: &CaptureConfig) -> Self {
+        Self {
+            window: None,
+            id: config.window_id,
+            title: config.window_title.clone(),
+        }
+    }
+
+    /// Whether a window with this id and title is the one the config asked
+    /// for. The id wins over the title, which matches as a substring.
+    fn is_target(&self, id: Option<u32>, title: Option<&str>) -> bool {
+        match (self.id, &self.title) {
+            (Some(wanted), _) => id == Some(wanted),
+            (None, Some(wanted)) => title.is_some_and(|t| t.contains(wanted.as_str())),
+            (None, None) => false,
+        }
+    }
+
+    /// What the config asked for, for log and error messages
+    fn describe(&self) -> String {
+        match (self.id, &self.title) {
+            (Some(id), _) => format!("window {}", id),
+            (None, Some(title)) => format!("window titled \"{}\"", title),
+            (None, None) => "window".to_string(),
+        }
+    }
+}
+
+impl FrameSource for WindowSource {
+    fn open(&mut self) -> AppResult<()> {
+        self.window = None;
+        let windows = Window::all()
+            .map_err(|e| AppError::CaptureError(format!("Failed to list windows: {}", e)))?;
+
+        let window = windows
+            .into_iter()
+            .find(|w| self.is_target(w.id().ok(), w.title().ok().as_deref()))
+            .ok_or_else(|| AppError::CaptureError(format!("No {} found", self.describe())))?;
+
+        info!(
+            "Capturing window \"{}\" ({})",
+            window.title().unwrap_or_default(),
+            window.app_name().unwrap_or_default()
+        );
+        self.window = Some(window);
+        Ok(())
+    }
+
+    fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+        let window = self
+            .window
+            .as_ref()
+            .ok_or_else(|| AppError::CaptureError(format!("No {} found", self.describe())))?;
+
+        if window.is_minimized().unwrap_or(false) {
+            return Err(AppError::CaptureError(format!("The {} is minimized", self.describe())));
+        }
+
+        match window.capture_image() {
+            Ok(image) => {
+                let (width, height) = image.dimensions();
+                Ok((image.into_raw(), width, height))
+            }
+            Err(e) => {
+                // Most likely closed; found again, if it comes back, on the
+                // next `open`
+                self.window = None;
+                Err(AppError::CaptureError(format!(
+                    "Cannot capture the {}, was it closed? ({})",
+                    self.describe(),
+                    e
+                )))
+            }
+        }
+    }
+}
+
+/// The animated demo pattern, for running without a display.
+pub struct DemoSource {
+    width: u32,
+    height: u32,
+    frame_count: u64,
+}
+
+impl DemoSource {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            frame_count: 0,
+        }
+    }
+}
+
+impl FrameSource for DemoSource {
+    fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+        let time = self.frame_count as f32 * 0.1;
+        self.frame_count += 1;
+        Ok((testcard::demo_frame(self.width, self.height, time), self.width, self.height))
+    }
+}
+
+/// Streams one still image, loaded once up front.
+pub struct FileSource {
+    rgba: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl FileSource {
+    /// Load `path`, tone mapping it with `tonemap` if it is a high bit
+    /// depth image such as a 16-bit PNG or a Radiance `.hdr`.
+    pub fn open(path: &Path, tonemap: HdrTonemap) -> AppResult<Self> {
+        let image = image::open(path)
+            .map_err(|e| AppError::CaptureError(format!("Cannot load {}: {}", path.display(), e)))?;
+        let color = image.color();
+        let image = capture::tonemap_to_srgb(image, tonemap);
+        let (width, height) = image.dimensions();
+        info!("Streaming {} ({}x{}, {:?})", path.display(), width, height, color);
+
+        Ok(Self {
+            rgba: image.into_raw(),
+            width,
+            height,
+        })
+    }
+}
+
+impl FrameSource for FileSource {
+    fn capture(&mut self) -> AppResult<(Vec<u8>, u32, u32)> {
+        Ok((self.rgba.clone(), self.width, self.height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn demo_mode_uses_the_demo_source() {
+        let mut config = Config::default().capture;
+        config.mode = CaptureMode::Demo;
+        let (rgba, width, height) = from_config(&config).unwrap().capture().unwrap();
+        assert_eq!((width, height), DEFAULT_DEMO_SIZE);
+        assert_eq!(rgba, testcard::demo_frame(width, height, 0.0));
+
+        (config.width, config.height) = (Some(64), Some(48));
+        let (_, width, height) = from_config(&config).unwrap().capture().unwrap();
+        assert_eq!((width, height), (64, 48));
+    }
+
+    #[test]
+    fn only_auto_and_window_modes_fall_back() {
+        assert!(CaptureMode::Auto.uses_fallback());
+        assert!(CaptureMode::Window.uses_fallback());
+        assert!(!CaptureMode::Screen.uses_fallback());
+        assert!(!CaptureMode::Demo.uses_fallback());
+        assert!(!CaptureMode::File.uses_fallback());
+    }
+
+    #[test]
+    fn file_mode_needs_a_file() {
+        let mut config = Config::default().capture;
+        config.mode = CaptureMode::File;
+        assert!(matches!(from_config(&config), Err(AppError::ConfigError(_))));
+    }
+
+    #[test]
+    fn window_is_found_by_id_or_title() {
+        let mut config = Config::default().capture;
+        config.window_title = Some("Terminal".to_string());
+        let by_title = WindowSource::new(&config);
+        assert!(by_title.is_target(Some(1), Some("~/src - Terminal")));
+        assert!(!by_title.is_target(Some(1), Some("Browser")));
+        assert!(!by_title.is_target(Some(1), None));
+
+        config.window_id = Some(7);
+        let by_id = WindowSource::new(&config);
+        assert!(by_id.is_target(Some(7), Some("Browser")));
+        assert!(!by_id.is_target(Some(8), Some("~/src - Terminal")));
+
+        assert!(!WindowSource::new(&Config::default().capture).is_target(Some(7), Some("Terminal")));
+    }
+
+    fn tile(x: i32, y: i32, width: u32, height: u32, pixel: [u8; 4]) -> Tile {
+        let rgba = pixel.repeat((width * height) as usize);
+        Tile { x, y, width, height, rgba }
+    }
+
+    #[test]
+    fn composite_puts_the_seam_at_the_monitor_edge() {
+        const RED: [u8; 4] = [255, 0, 0, 255];
+        const BLUE: [u8; 4] = [0, 0, 255, 255];
+        const BLACK: [u8; 4] = [0, 0, 0, 255];
+        // A 4x2 monitor with a 3x3 one to its right, one row higher
+        let (rgba, width, height) = composite(&[tile(0, 0, 4, 2, RED), tile(4, -1, 3, 3, BLUE)]);
+        assert_eq!((width, height), (7, 3));
+
+        let pixel = |x: usize, y: usize| -> [u8; 4] { rgba[(y * 7 + x) * 4..][..4].try_into().unwrap() };
+        for y in 1..3 {
+            assert_eq!(pixel(3, y), RED);
+            assert_eq!(pixel(4, y), BLUE);
+        }
+        // Above the shorter monitor nothing is captured
+        assert_eq!(pixel(0, 0), BLACK);
+        assert_eq!(pixel(3, 0), BLACK);
+        assert_eq!(pixel(4, 0), BLUE);
+    }
+
+    #[test]
+    fn composite_handles_monitors_left_of_the_primary() {
+        let (rgba, width, height) = composite(&[tile(0, 0, 2, 1, [1; 4]), tile(-3, 0, 3, 1, [2; 4])]);
+        assert_eq!((width, height), (5, 1));
+        assert_eq!(rgba, [[2; 4].repeat(3), [1; 4].repeat(2)].concat());
+    }
+
+    #[test]
+    fn excluded_windows_are_blacked_out_within_the_frame() {
+        let mut rgba = vec![200; 8 * 6 * 4];
+        // Frame at (100, 50) on the desktop; the window hangs off its left edge
+        let window = WindowRect { x: 96, y: 52, width: 6, height: 2 };
+        black_out(&mut rgba, 8, 6, (100, 50), &[window]);
+
+        for y in 0..6 {
+            for x in 0..8 {
+                let pixel = &rgba[(y * 8 + x) * 4..][..4];
+                let masked = x < 2 && (2..4).contains(&y);
+                let expected = if masked { [0, 0, 0, 255] } else { [200; 4] };
+                assert_eq!(pixel, expected, "({}, {})", x, y);
+            }
+        }
+    }
+}
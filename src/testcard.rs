@@ -0,0 +1,202 @@
+// "NO SIGNAL" test card shown to viewers when real capture is unavailable.
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+// Classic 75% SMPTE colour bars: white, yellow, cyan, green, magenta, red, blue
+const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+
+/// Rows of a 5x7 bitmap glyph, most significant of the low 5 bits on the left.
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'N' => [0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        ':' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+        _ => [0; 7],
+    }
+}
+
+/// Animated retro pattern at `time`, which advances by 0.1 per frame.
+pub fn demo_frame(width: u32, height: u32, time: f32) -> Vec<u8> {
+    let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
+
+    for y in 0..height {
+        for x in 0..width {
+            let fx = x as f32 / width as f32;
+            let fy = y as f32 / height as f32;
+
+            // Create animated retro patterns
+            let wave1 = ((fx * 10.0 + time).sin() * 0.5 + 0.5) * 255.0;
+            let wave2 = ((fy * 8.0 + time * 1.3).cos() * 0.5 + 0.5) * 255.0;
+            let wave3 = (((fx + fy) * 6.0 + time * 0.8).sin() * 0.5 + 0.5) * 255.0;
+
+            // Retro green/cyan color scheme
+            let r = (wave3 * 0.1) as u8;
+            let g = ((wave1 + wave3) * 0.4) as u8;
+            let b = (wave2 * 0.3) as u8;
+            let a = 255u8;
+
+            rgba_data.extend_from_slice(&[r, g, b, a]);
+        }
+    }
+
+    rgba_data
+}
+
+pub fn black_frame(width: u32, height: u32) -> Vec<u8> {
+    [0u8, 0, 0, 255].repeat((width * height) as usize)
+}
+
+/// Render colour bars with a "NO SIGNAL" banner and the current UTC time.
+pub fn no_signal_frame(width: u32, height: u32) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity((width * height * 4) as usize);
+    let bars_height = height * 2 / 3;
+
+    for y in 0..height {
+        for x in 0..width {
+            let [r, g, b] = if y < bars_height {
+                BARS[(x * BARS.len() as u32 / width) as usize]
+            } else {
+                [16, 16, 16]
+            };
+            rgba.extend_from_slice(&[r, g, b, 255]);
+        }
+    }
+
+    // Title banner across the middle of the bars
+    let title = "NO SIGNAL";
+    let title_scale = (height / 90).max(1);
+    let (title_w, title_h) = text_size(title, title_scale);
+    let banner_pad = title_scale * 4;
+    let banner_y = (bars_height / 2).saturating_sub(title_h / 2 + banner_pad);
+    fill_rect(
+        &mut rgba,
+        width,
+        height,
+        (width.saturating_sub(title_w) / 2).saturating_sub(banner_pad),
+        banner_y,
+        title_w + banner_pad * 2,
+        title_h + banner_pad * 2,
+        [0, 0, 0],
+    );
+    draw_text(
+        &mut rgba,
+        width,
+        height,
+        width.saturating_sub(title_w) / 2,
+        banner_y + banner_pad,
+        title,
+        title_scale,
+        [255, 255, 255],
+    );
+
+    // Timestamp in the lower band
+    let timestamp = utc_timestamp();
+    let ts_scale = (height / 180).max(1);
+    let (ts_w, ts_h) = text_size(&timestamp, ts_scale);
+    draw_text(
+        &mut rgba,
+        width,
+        height,
+        width.saturating_sub(ts_w) / 2,
+        bars_height + (height - bars_height).saturating_sub(ts_h) / 2,
+        &timestamp,
+        ts_scale,
+        [200, 200, 200],
+    );
+
+    rgba
+}
+
+fn utc_timestamp() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        % 86_400;
+    format!("{:02}:{:02}:{:02} UTC", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn text_size(text: &str, scale: u32) -> (u32, u32) {
+    let chars = text.chars().count() as u32;
+    // No spacing after the last glyph
+    let w = (chars * (GLYPH_WIDTH + 1) * scale).saturating_sub(scale);
+    (w, GLYPH_HEIGHT * scale)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_text(
+    rgba: &mut [u8],
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    text: &str,
+    scale: u32,
+    color: [u8; 3],
+) {
+    for (i, c) in text.chars().enumerate() {
+        let origin_x = x + i as u32 * (GLYPH_WIDTH + 1) * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                    fill_rect(
+                        rgba,
+                        width,
+                        height,
+                        origin_x + col * scale,
+                        y + row as u32 * scale,
+                        scale,
+                        scale,
+                        color,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fill_rect(
+    rgba: &mut [u8],
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    [r, g, b]: [u8; 3],
+) {
+    for py in y..(y + h).min(height) {
+        for px in x..(x + w).min(width) {
+            let i = ((py * width + px) * 4) as usize;
+            rgba[i..i + 4].copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+}
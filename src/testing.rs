@@ -0,0 +1,106 @@
+//! Helpers shared by the unit tests of the binary's modules
+
+use crate::{config::Config, metrics::Metrics, router, AppState};
+use axum::{
+    body::Body,
+    extract::connect_info::MockConnectInfo,
+    http::{Request, Response},
+};
+use futures_util::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::{atomic::AtomicU64, Arc};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use tower::ServiceExt;
+
+/// Address requests made with `get` come from
+pub const CLIENT_ADDR: ([u8; 4], u16) = ([192, 168, 1, 20], 50000);
+
+/// App state for the default config, changed by `configure`
+pub fn state(configure: impl FnOnce(&mut Config)) -> AppState {
+    let mut config = Config::default();
+    configure(&mut config);
+    AppState::new(Arc::new(config), Arc::new(Metrics::new()), Arc::new(AtomicU64::new(0))).unwrap()
+}
+
+/// Send `request` through the full router, as if from `CLIENT_ADDR`
+pub async fn send(state: &AppState, request: Request<Body>) -> Response<Body> {
+    router(state.clone())
+        .layer(MockConnectInfo(SocketAddr::from(CLIENT_ADDR)))
+        .oneshot(request)
+        .await
+        .unwrap()
+}
+
+/// `GET uri` through the full router
+pub async fn get(state: &AppState, uri: &str) -> Response<Body> {
+    send(state, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+/// A path in the temp dir unique to this test process, removed on drop
+pub struct TempPath(pub std::path::PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("retrostream-{}-{}", std::process::id(), name)))
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+pub type Client = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Longest a test waits for any one message
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the full router on a free local port
+pub async fn serve(state: &AppState) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, service).await });
+    addr
+}
+
+/// Open a WebSocket to `/stream` with `query`, e.g. `"pull=true"`
+pub async fn connect(addr: SocketAddr, query: &str) -> Client {
+    let url = format!("ws://{}/stream?{}", addr, query);
+    tokio_tungstenite::connect_async(url).await.unwrap().0
+}
+
+/// The next text or binary message, skipping protocol pings
+pub async fn next_message(client: &mut Client) -> Message {
+    loop {
+        let message = tokio::time::timeout(RECV_TIMEOUT, client.next())
+            .await
+            .expect("timed out waiting for a message")
+            .expect("connection closed")
+            .unwrap();
+        if !matches!(message, Message::Ping(_) | Message::Pong(_)) {
+            return message;
+        }
+    }
+}
+
+/// The next text message, parsed
+pub async fn next_json(client: &mut Client) -> serde_json::Value {
+    match next_message(client).await {
+        Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        other => panic!("expected a text message, got {:?}", other),
+    }
+}
+
+/// The whole body of `response`
+pub async fn body(response: Response<Body>) -> Vec<u8> {
+    axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+}
+
+/// Send a client command
+pub async fn send_json(client: &mut Client, command: serde_json::Value) {
+    client.send(Message::Text(command.to_string())).await.unwrap();
+}
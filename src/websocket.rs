@@ -1,60 +1,635 @@
-use crate::{AppState, error::AppResult};
+use crate::{
+    AppState,
+    admission::{Admit, QueueTicket, Slot},
+    auth,
+    capture,
+    clock,
+    compression::{read_frame_header, FrameMessage, PROTOCOL_VERSION},
+    config::CompressionFormat,
+    connections::Connection,
+    error::AppResult,
+    fanout::{self, RecvError, TryRecvError},
+    metrics::Metrics,
+    motion::MotionEvent,
+    profile::Profile,
+};
 use axum::{
-    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, State},
-    response::Response,
+    extract::{ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade}, ConnectInfo, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
 use tracing::{info, warn, debug};
 
+/// JSON text messages sent from the server to clients.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Sent once on connect, before any frames, describing the stream.
+    /// Not sent again when a client resumes its session.
+    Hello {
+        /// Reconnect with `?session=`, or send it in a `resume` command, to
+        /// count as the same viewer
+        session: String,
+        #[serde(flatten)]
+        info: StreamInfo,
+    },
+    /// Waiting for a free slot; sent whenever the position changes
+    Queued { position: usize },
+    /// Significant on-screen motion detected by the capture loop
+    Motion(MotionEvent),
+    /// A `set_monitor` command was accepted
+    MonitorSet { index: usize },
+    /// A `set_redactions` command was accepted
+    RedactionsSet { enabled: bool },
+    /// A `grab` was accepted; the next captured keyframe follows as a
+    /// binary message
+    Grabbing,
+    /// A `set_quality` command was accepted; these settings now apply
+    QualitySet { fps: u32, quality: f32 },
+    /// Frames stopped after a `pause` command
+    Paused,
+    /// Frames resume after a `resume` command
+    Resumed,
+    /// A `resume` command with a session was accepted; this connection
+    /// now carries on as that session's viewer
+    SessionResumed { session: String },
+    /// Reply to a `ping` command, sent straight away
+    Pong {
+        /// The ping's nonce, unchanged
+        nonce: serde_json::Value,
+        /// When the ping was answered, on the same clock as frame
+        /// timestamps (see `clock`)
+        server_time: u64,
+    },
+    /// A client command was rejected
+    Error { code: ErrorCode, message: String },
+}
+
+/// Why a client command was rejected, for clients to act on without
+/// parsing `message`
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The text message isn't JSON
+    MalformedCommand,
+    /// JSON, but not a valid command: no `cmd`, or missing or mistyped
+    /// fields
+    InvalidCommand,
+    /// A `cmd` this server doesn't have, e.g. from a newer client
+    UnknownCommand,
+    /// A valid command that couldn't be carried out
+    CommandFailed,
+    /// A command that needs a token the client didn't give
+    Unauthorized,
+}
+
+/// What a client should expect from the shared stream, so it can size its
+/// canvas and pick a decoder before the first frame arrives
+#[derive(Debug, Serialize)]
+pub struct StreamInfo {
+    /// Version of the binary frame layout, see `compression::PROTOCOL_VERSION`
+    pub protocol_version: u8,
+    /// Resolution of the last captured frame; `None` before the first one
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: u32,
+    pub format: CompressionFormat,
+    /// Wall-clock start of the clock frame timestamps are read from, in ms
+    /// since the Unix epoch. A timestamp minus this is monotonic time since.
+    pub clock_base: u64,
+}
+
+impl StreamInfo {
+    fn current(state: &AppState) -> Self {
+        let config = state.live_config.borrow().clone();
+        let resolution = *state.resolution.borrow();
+
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            width: resolution.map(|(width, _)| width),
+            height: resolution.map(|(_, height)| height),
+            fps: config.capture.fps,
+            format: config.compression.format,
+            clock_base: clock::base_ms(),
+        }
+    }
+}
+
+/// JSON text commands sent from clients to the server.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ClientCommand {
+    /// Switch capture to another monitor, as listed by `GET /monitors`
+    SetMonitor { index: usize },
+    /// Switch `capture.redactions` off or back on, for everyone. Only
+    /// accepted with `server.admin_token`, since viewers are who the
+    /// redactions hide things from.
+    SetRedactions { enabled: bool, token: Option<String> },
+    /// Receive a personal JPEG stream at this rate and quality (0.0-1.0).
+    /// Omitted values default to the server's; omitting both returns to
+    /// the shared stream.
+    SetQuality {
+        fps: Option<u32>,
+        quality: Option<f32>,
+    },
+    /// Stop sending frames, e.g. while the viewer's tab is hidden. The
+    /// connection, pings and text messages carry on as usual.
+    Pause,
+    /// Start sending frames again, from the next keyframe. With `session`,
+    /// instead carry on as the viewer of an earlier connection's session,
+    /// like `?session=`; only accepted before any other command.
+    Resume { session: Option<String> },
+    /// Send one fresh frame, for clients connected with `?pull=true`
+    Grab,
+    /// Answered with a `pong` carrying the same nonce, for measuring round
+    /// trips. Unrelated to WebSocket protocol pings, which browsers don't
+    /// expose.
+    Ping { nonce: serde_json::Value },
+    /// Any other `cmd`, answered with an `unknown_command` error
+    #[serde(other)]
+    Unknown,
+}
+
+/// How long a new connection has to resume a session with a command
+/// before it is counted as a new viewer
+const RESUME_WINDOW: Duration = Duration::from_secs(2);
+
+/// Whether a connection has been counted in `total_connections`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Viewer {
+    /// Not yet counted: it may still resume a session within
+    /// `RESUME_WINDOW`
+    Pending,
+    /// Counted as a new viewer
+    New,
+    /// Carries on an earlier session, so not counted again
+    Resumed,
+}
+
+/// Longest gap between frames sent to a lagging client, in frames
+const MAX_FRAME_SKIP: u32 = 8;
+/// Lag-free frames required before stepping the send rate back up
+const RECOVERY_FRAMES: u32 = 150;
+
+/// Per-client frame rate that backs off when the client lags behind the
+/// broadcast and recovers once frames flow cleanly again.
+struct AdaptiveRate {
+    /// Send one of every `skip` frames
+    skip: u32,
+    clean_frames: u32,
+    received: u64,
+}
+
+impl AdaptiveRate {
+    fn new() -> Self {
+        Self {
+            skip: 1,
+            clean_frames: 0,
+            received: 0,
+        }
+    }
+
+    fn on_lag(&mut self) {
+        self.skip = (self.skip * 2).min(MAX_FRAME_SKIP);
+        self.clean_frames = 0;
+    }
+
+    /// Whether this frame should be sent, decaying the skip while clean.
+    fn should_send(&mut self) -> bool {
+        self.received += 1;
+        self.clean_frames += 1;
+        if self.skip > 1 && self.clean_frames >= RECOVERY_FRAMES {
+            self.skip -= 1;
+            self.clean_frames = 0;
+        }
+        self.received.is_multiple_of(self.skip as u64)
+    }
+
+    fn effective_fps(&self, fps: u32) -> f32 {
+        fps as f32 / self.skip as f32
+    }
+}
+
+/// Spaces out frame sends for one client and measures how far the actual
+/// gaps between sends stray from the frame interval.
+struct Pacer {
+    enabled: bool,
+    next_send: tokio::time::Instant,
+    last_sent: Option<tokio::time::Instant>,
+}
+
+impl Pacer {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            next_send: tokio::time::Instant::now(),
+            last_sent: None,
+        }
+    }
+
+    /// When pacing, wait for this frame's slot. A frame that is already
+    /// late goes out immediately and restarts the cadence from now, so
+    /// there is never a catch-up burst.
+    async fn wait(&mut self, interval: Duration) {
+        if !self.enabled {
+            return;
+        }
+
+        let now = tokio::time::Instant::now();
+        if now < self.next_send {
+            tokio::time::sleep_until(self.next_send).await;
+            self.next_send += interval;
+        } else {
+            self.next_send = now + interval;
+        }
+    }
+
+    /// Record a send, returning its deviation from the expected interval.
+    fn sent(&mut self, interval: Duration) -> Option<Duration> {
+        let now = tokio::time::Instant::now();
+        let gap = self.last_sent.replace(now).map(|last| now - last)?;
+        Some(gap.abs_diff(interval))
+    }
+}
+
+/// Sliding window `max_bitrate_kbps` is measured over
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Keeps one client under `max_bitrate_kbps` by skipping whole frames.
+struct BitrateLimit {
+    /// Bytes allowed per `BITRATE_WINDOW`, `None` when unlimited
+    budget: Option<usize>,
+    sent: VecDeque<(tokio::time::Instant, usize)>,
+    /// A keyframe was skipped, so deltas are useless until the next one
+    awaiting_keyframe: bool,
+}
+
+impl BitrateLimit {
+    fn new(max_bitrate_kbps: u64) -> Self {
+        Self {
+            budget: (max_bitrate_kbps > 0).then_some((max_bitrate_kbps * 1000 / 8) as usize),
+            sent: VecDeque::new(),
+            awaiting_keyframe: false,
+        }
+    }
+
+    /// Whether a frame of `len` bytes fits the budget. A frame larger than
+    /// the whole budget still goes out once the window is empty, or the
+    /// client would never see a keyframe again.
+    fn admit(&mut self, len: usize, keyframe: bool) -> bool {
+        let Some(budget) = self.budget else {
+            return true;
+        };
+
+        let now = tokio::time::Instant::now();
+        while self.sent.front().is_some_and(|&(at, _)| now - at >= BITRATE_WINDOW) {
+            self.sent.pop_front();
+        }
+
+        let in_window: usize = self.sent.iter().map(|&(_, bytes)| bytes).sum();
+        let fits = in_window == 0 || in_window + len <= budget;
+        if keyframe {
+            self.awaiting_keyframe = !fits;
+        }
+        fits && !self.awaiting_keyframe
+    }
+
+    fn record(&mut self, len: usize) {
+        if self.budget.is_some() {
+            self.sent.push_back((tokio::time::Instant::now(), len));
+        }
+    }
+}
+
+/// Whether frames are forwarded to a client, see `ClientCommand::Pause`
+enum Delivery {
+    Active,
+    /// Held only for its `Drop`
+    Paused(#[allow(dead_code)] PausedConnection),
+    /// Deltas are useless until the next keyframe: after resuming, after
+    /// switching streams with `set_quality`, or after joining without the
+    /// latest keyframe
+    AwaitingKeyframe,
+    /// Pull mode between grabs: frames are drained but not sent
+    Idle,
+    /// Pull mode after a `grab`: the next keyframe is sent, then back to
+    /// `Idle`
+    Grab,
+    /// Pull mode after a `grab` answered with the cached keyframe, sent
+    /// right after the `grabbing` reply
+    Grabbed(FrameMessage),
+}
+
+/// Counts a client in `paused_connections` and shows it as paused in
+/// `GET /connections` for as long as it's held
+struct PausedConnection(Arc<Metrics>, Arc<Connection>);
+
+impl PausedConnection {
+    fn new(metrics: Arc<Metrics>, connection: Arc<Connection>) -> Self {
+        metrics.increment_paused_connections();
+        connection.set_paused(true);
+        Self(metrics, connection)
+    }
+}
+
+impl Drop for PausedConnection {
+    fn drop(&mut self) {
+        self.0.decrement_paused_connections();
+        self.1.set_paused(false);
+    }
+}
+
+impl ServerMessage {
+    fn to_message(&self) -> AppResult<Message> {
+        Ok(Message::Text(serde_json::to_string(self)?))
+    }
+
+    fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    /// Session from an earlier connection's hello, see `Sessions`
+    session: Option<String>,
+    /// Send frames only on `grab` instead of streaming them
+    #[serde(default)]
+    pull: bool,
+}
+
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<StreamQuery>,
     State(state): State<AppState>,
 ) -> Response {
-    ws.on_upgrade(move |socket| handle_websocket(socket, state))
+    let Some(admit) = state.admission.try_admit() else {
+        warn!("Rejecting connection from {}: server at capacity", remote_addr);
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server at capacity").into_response();
+    };
+
+    // These limits guard the read side. The write buffer is left unbounded
+    // so large outbound frames are never rejected.
+    let max_bytes = state.config.server.max_ws_message_bytes;
+    ws.max_message_size(max_bytes)
+        .max_frame_size(max_bytes)
+        .on_upgrade(move |socket| handle_websocket(socket, remote_addr, state, admit, query.session, query.pull))
 }
 
-async fn handle_websocket(socket: WebSocket, state: AppState) {
-    let remote_addr = get_socket_addr(&socket);
-    info!("WebSocket connection established from {:?}", remote_addr);
-    
+async fn handle_websocket(
+    mut socket: WebSocket,
+    remote_addr: SocketAddr,
+    state: AppState,
+    admit: Admit,
+    resume: Option<String>,
+    pull: bool,
+) {
+    // Held until the connection ends, freeing the slot for the next client
+    let _slot = match admit {
+        Admit::Now(slot) => slot,
+        Admit::Queued(ticket) => {
+            info!("Server at capacity, queued {} at position {}", remote_addr, ticket.position());
+            match wait_in_queue(&mut socket, ticket).await {
+                Some(slot) => slot,
+                None => {
+                    debug!("Client {} left the queue", remote_addr);
+                    return;
+                }
+            }
+        }
+    };
+
+    info!("WebSocket connection established from {}", remote_addr);
+
+    // An unknown or expired session just gets a new one
+    let resumed = resume.filter(|id| state.sessions.resume(id));
     state.metrics.increment_connections();
+    let (mut session, mut viewer) = match resumed {
+        Some(id) => {
+            info!("Client {} resumed session {}", remote_addr, id);
+            state.metrics.record_session_resumed();
+            (id, Viewer::Resumed)
+        }
+        None => (state.sessions.start(), Viewer::Pending),
+    };
+    state.metrics.record_client_ip(remote_addr.ip());
+    let connected_at = Instant::now();
     
-    let result = handle_client(socket, state.clone()).await;
+    let result = handle_client(socket, remote_addr, state.clone(), &mut session, &mut viewer, pull).await;
     
+    count_new_viewer(&state, &mut viewer);
+    state.sessions.detach(&session);
     state.metrics.decrement_connections();
+    state.metrics.observe_connection_duration(connected_at.elapsed());
     
     match result {
-        Ok(_) => info!("WebSocket connection closed cleanly for {:?}", remote_addr),
-        Err(e) => warn!("WebSocket connection error for {:?}: {}", remote_addr, e),
+        Ok(_) => info!("WebSocket connection closed cleanly for {}", remote_addr),
+        Err(e) => warn!("WebSocket connection error for {}: {}", remote_addr, e),
     }
 }
 
-async fn handle_client(mut socket: WebSocket, state: AppState) -> AppResult<()> {
-    let mut frame_rx = state.frame_tx.subscribe();
-    let mut ping_interval = tokio::time::interval(std::time::Duration::from_secs(30));
+async fn handle_client(
+    mut socket: WebSocket,
+    remote_addr: SocketAddr,
+    state: AppState,
+    session: &mut String,
+    viewer: &mut Viewer,
+    pull: bool,
+) -> AppResult<()> {
+    let registered = state.connections.open(remote_addr, pull);
+    let connection = registered.connection();
+    let mut motion_rx = state.motion_tx.subscribe();
+    let mut audio_rx = state.audio_tx.subscribe();
+    let mut shutdown = state.shutdown.subscribe();
+    let mut ping_interval =
+        tokio::time::interval(Duration::from_millis(state.config.server.ping_interval_ms));
+    let pong_timeout = Duration::from_millis(state.config.server.pong_timeout_ms);
+    let mut last_pong = tokio::time::Instant::now();
     let mut frame_count = 0u64;
+    let mut rate = AdaptiveRate::new();
+    let mut pacer = Pacer::new(state.config.server.pacing);
+    let mut bitrate = BitrateLimit::new(state.config.server.max_bitrate_kbps);
+    let mut profile: Option<Profile> = None;
+    let resume_window = tokio::time::sleep(RESUME_WINDOW);
+    tokio::pin!(resume_window);
+
+    if *viewer == Viewer::Pending {
+        let hello = ServerMessage::Hello {
+            session: session.clone(),
+            info: StreamInfo::current(&state),
+        };
+        if socket.send(hello.to_message()?).await.is_err() {
+            debug!("Failed to send hello, client disconnected");
+            return Ok(());
+        }
+    }
+
+    // Show something immediately instead of waiting for the next capture.
+    // The cached frame is the latest keyframe, so the deltas that follow
+    // it render correctly straight away.
+    let mut sent_keyframe = None;
+    if state.config.server.send_latest_on_connect && !pull {
+        let latest = state.latest_frame.read().unwrap_or_else(PoisonError::into_inner).clone();
+        if let Some(frame_data) = latest {
+            let len = frame_data.len();
+            if socket.send(Message::Binary(frame_data.to_vec())).await.is_err() {
+                debug!("Failed to send latest frame, client disconnected");
+                return Ok(());
+            }
+            frame_count += 1;
+            state.metrics.increment_frames_delivered();
+            connection.record_frame_delivered();
+            state.metrics.add_bytes_sent(len);
+            connection.record_bytes_sent(len);
+            bitrate.record(len);
+            sent_keyframe = Some(frame_data);
+        }
+    }
+
+    // Subscribing only after the keyframe was picked keeps deltas of an
+    // older keyframe out of frame_rx. Deltas are still useless until the
+    // next keyframe if none was sent, or a newer one was captured meanwhile.
+    // Pull clients only subscribe while a grab waits for its frame, so they
+    // don't keep capture from idling.
+    let mut frame_rx = (!pull).then(|| state.frame_tx.subscribe());
+    if !pull {
+        state.viewer_joined.notify_one();
+    }
+    let latest = state.latest_frame.read().unwrap_or_else(PoisonError::into_inner).clone();
+    let mut delivery = match (sent_keyframe, latest) {
+        _ if pull => Delivery::Idle,
+        (Some(sent), Some(latest)) if Arc::ptr_eq(&sent, &latest) => Delivery::Active,
+        _ => Delivery::AwaitingKeyframe,
+    };
     
     loop {
         tokio::select! {
             // Handle incoming frames
-            frame_result = frame_rx.recv() => {
+            frame_result = next_frame(&mut frame_rx) => {
                 match frame_result {
                     Ok(frame_data) => {
+                        // Paused clients still drain the channel so they
+                        // don't come back to a backlog
+                        match delivery {
+                            Delivery::Paused(_) | Delivery::Idle | Delivery::Grabbed(_) => continue,
+                            Delivery::AwaitingKeyframe | Delivery::Grab if !is_keyframe(&frame_data) => continue,
+                            Delivery::AwaitingKeyframe => delivery = Delivery::Active,
+                            Delivery::Grab => {
+                                delivery = Delivery::Idle;
+                                frame_rx = None;
+                                frame_count += 1;
+                                if send_grabbed(&mut socket, &state, connection, frame_data).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                            Delivery::Active => {}
+                        }
+                        // Only pull clients go without, and they are never `Active`
+                        let Some(frame_rx) = frame_rx.as_mut() else {
+                            continue;
+                        };
+
+                        let frame_data = if state.config.server.latest_frame_only {
+                            let (base, newest) = take_latest(frame_rx, frame_data, &state);
+                            if let Some(keyframe) = base {
+                                let len = keyframe.len();
+                                if socket.send(Message::Binary(keyframe.to_vec())).await.is_err() {
+                                    debug!("Failed to send keyframe, client disconnected");
+                                    break;
+                                }
+                                state.metrics.increment_frames_delivered();
+                                connection.record_frame_delivered();
+                                state.metrics.add_bytes_sent(len);
+                                connection.record_bytes_sent(len);
+                                bitrate.record(len);
+                            }
+                            newest
+                        } else {
+                            frame_data
+                        };
+
+                        // Deltas that follow depend on every keyframe
+                        let keyframe = is_keyframe(&frame_data);
+                        if !keyframe && is_expired(&frame_data, state.config.server.max_frame_age_ms) {
+                            state.metrics.increment_expired_frames();
+                            continue;
+                        }
+
+                        let skip = rate.skip;
+                        let send = rate.should_send();
+                        if rate.skip != skip {
+                            debug!("Client recovering, effective rate {:.1} FPS", rate.effective_fps(stream_fps(&state, profile)));
+                        }
+                        if !send && !keyframe {
+                            continue;
+                        }
+
+                        // A newer frame is already waiting, so pacing this
+                        // one would only deliver it late
+                        if pacer.enabled && !frame_rx.is_empty() && !keyframe {
+                            state.metrics.increment_expired_frames();
+                            continue;
+                        }
+
+                        if !bitrate.admit(frame_data.len(), keyframe) {
+                            state.metrics.increment_throttled_frames();
+                            continue;
+                        }
+
+                        let interval = profile
+                            .map_or_else(|| state.live_config.borrow().frame_interval(), |p| p.frame_interval())
+                            * rate.skip;
+                        pacer.wait(interval).await;
+                        // Waiting for the slot may have taken it past the deadline
+                        if !keyframe && is_expired(&frame_data, state.config.server.max_frame_age_ms) {
+                            state.metrics.increment_expired_frames();
+                            continue;
+                        }
+
                         frame_count += 1;
+                        let len = frame_data.len();
                         
-                        if socket.send(Message::Binary(frame_data)).await.is_err() {
+                        // axum's Message owns its buffer, so this is the one
+                        // copy per client; the fan-out itself shares it
+                        if socket.send(Message::Binary(frame_data.to_vec())).await.is_err() {
                             debug!("Failed to send frame {}, client disconnected", frame_count);
                             break;
                         }
                         
                         state.metrics.increment_frames_delivered();
+                        connection.record_frame_delivered();
+                        state.metrics.add_bytes_sent(len);
+                        connection.record_bytes_sent(len);
+                        bitrate.record(len);
+                        if let Some(jitter) = pacer.sent(interval) {
+                            state.metrics.record_frame_jitter(jitter);
+                        }
                         
-                        if frame_count % 100 == 0 {
-                            debug!("Delivered {} frames to client", frame_count);
+                        if frame_count.is_multiple_of(100) {
+                            match profile {
+                                Some(profile) => debug!("Delivered {} frames to client {} ({})", frame_count, remote_addr, profile),
+                                None => debug!("Delivered {} frames to client {}", frame_count, remote_addr),
+                            }
                         }
                     }
-                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
-                        warn!("Client lagging, skipped {} frames", skipped);
+                    Err(RecvError::Lagged(skipped)) => {
+                        rate.on_lag();
+                        warn!(
+                            "Client {} lagging, skipped {} frames, effective rate now {:.1} FPS",
+                            remote_addr,
+                            skipped,
+                            rate.effective_fps(stream_fps(&state, profile))
+                        );
                         state.metrics.increment_dropped_frames();
                         continue;
                     }
@@ -65,8 +640,53 @@ async fn handle_client(mut socket: WebSocket, state: AppState) -> AppResult<()>
                 }
             }
             
-            // Send periodic pings
+            // Forward motion events
+            Ok(event) = motion_rx.recv() => {
+                if socket.send(ServerMessage::Motion(event).to_message()?).await.is_err() {
+                    debug!("Failed to send motion event, client disconnected");
+                    break;
+                }
+            }
+            
+            Ok(packet) = audio_rx.recv() => {
+                if !matches!(delivery, Delivery::Paused(_)) {
+                    let len = packet.len();
+                    if socket.send(Message::Binary(packet.to_vec())).await.is_err() {
+                        debug!("Failed to send audio, client disconnected");
+                        break;
+                    }
+                    state.metrics.add_bytes_sent(len);
+                    connection.record_bytes_sent(len);
+                }
+            }
+            
+            // Didn't resume a session in time, so a new viewer
+            () = &mut resume_window, if *viewer == Viewer::Pending => {
+                count_new_viewer(&state, viewer);
+            }
+            
+            // Close cleanly when the server shuts down
+            _ = async { shutdown.wait_for(|&stopping| stopping).await.is_ok() } => {
+                let grace = std::time::Duration::from_millis(state.config.server.shutdown_grace_ms);
+                close_for_shutdown(&mut socket, grace).await;
+                break;
+            }
+            
+            // Send periodic pings, dropping clients that stopped answering
             _ = ping_interval.tick() => {
+                if !pong_timeout.is_zero() && last_pong.elapsed() > pong_timeout {
+                    info!(
+                        "No pong from {} for {:.0}s, closing connection",
+                        remote_addr,
+                        last_pong.elapsed().as_secs_f32()
+                    );
+                    let close = Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "Ping timeout".into(),
+                    }));
+                    let _ = socket.send(close).await;
+                    break;
+                }
                 if socket.send(Message::Ping(vec![])).await.is_err() {
                     debug!("Failed to send ping, client disconnected");
                     break;
@@ -78,6 +698,7 @@ async fn handle_client(mut socket: WebSocket, state: AppState) -> AppResult<()>
                 match msg_result {
                     Some(Ok(Message::Pong(_))) => {
                         debug!("Received pong from client");
+                        last_pong = tokio::time::Instant::now();
                     }
                     Some(Ok(Message::Close(_))) => {
                         debug!("Client requested close");
@@ -85,10 +706,34 @@ async fn handle_client(mut socket: WebSocket, state: AppState) -> AppResult<()>
                     }
                     Some(Ok(Message::Text(text))) => {
                         debug!("Received text from client: {}", text);
-                        // Could handle client commands here
+                        let reply = match serde_json::from_str::<ClientCommand>(&text) {
+                            Ok(ClientCommand::Resume { session: Some(id) }) => {
+                                resume_session(&state, remote_addr, session, viewer, id)
+                            }
+                            Ok(command) => {
+                                count_new_viewer(&state, viewer);
+                                handle_command(command, remote_addr, &state, connection, &mut frame_rx, &mut profile, &mut delivery)
+                            }
+                            Err(e) if e.is_syntax() || e.is_eof() => {
+                                ServerMessage::error(ErrorCode::MalformedCommand, format!("Malformed command: {}", e))
+                            }
+                            Err(e) => ServerMessage::error(ErrorCode::InvalidCommand, format!("Invalid command: {}", e)),
+                        };
+                        if socket.send(reply.to_message()?).await.is_err() {
+                            debug!("Failed to send command reply, client disconnected");
+                            break;
+                        }
+                        if let Delivery::Grabbed(frame_data) = &delivery {
+                            let frame_data = frame_data.clone();
+                            delivery = Delivery::Idle;
+                            frame_count += 1;
+                            if send_grabbed(&mut socket, &state, connection, frame_data).await.is_err() {
+                                break;
+                            }
+                        }
                     }
                     Some(Err(e)) => {
-                        warn!("WebSocket message error: {}", e);
+                        warn!("WebSocket message error from {}: {}", remote_addr, e);
                         break;
                     }
                     None => {
@@ -106,8 +751,805 @@ async fn handle_client(mut socket: WebSocket, state: AppState) -> AppResult<()>
     Ok(())
 }
 
-fn get_socket_addr(_socket: &WebSocket) -> Option<SocketAddr> {
-    // This is a placeholder - axum doesn't expose remote addr directly
-    // In a real implementation, you'd extract this from the request
-    None
+/// Send a close frame and wait for the client to acknowledge it.
+async fn close_for_shutdown(socket: &mut WebSocket, grace: std::time::Duration) {
+    let close = Message::Close(Some(CloseFrame {
+        code: close_code::AWAY,
+        reason: "Server shutting down".into(),
+    }));
+    if socket.send(close).await.is_err() {
+        return;
+    }
+
+    let acknowledged = tokio::time::timeout(grace, async {
+        while let Some(Ok(msg)) = socket.recv().await {
+            if matches!(msg, Message::Close(_)) {
+                break;
+            }
+        }
+    });
+    if acknowledged.await.is_err() {
+        debug!("Client did not acknowledge close before the grace period ended");
+    }
+}
+
+/// Frame rate of the stream a client receives
+fn stream_fps(state: &AppState, profile: Option<Profile>) -> u32 {
+    profile.map_or_else(|| state.live_config.borrow().capture.fps, |p| p.fps)
+}
+
+/// Resolve a `set_quality` request to the frame stream that serves it.
+fn set_quality(
+    state: &AppState,
+    fps: Option<u32>,
+    quality: Option<f32>,
+) -> Result<(fanout::Receiver<FrameMessage>, Option<Profile>), String> {
+    if fps.is_none() && quality.is_none() {
+        return Ok((state.frame_tx.subscribe(), None));
+    }
+
+    // As capture runs now, after any reload
+    let (capture_fps, capture_quality) = {
+        let config = state.live_config.borrow();
+        (config.capture.fps, config.capture.quality)
+    };
+    let fps = fps.unwrap_or(capture_fps);
+    let quality = quality.unwrap_or(capture_quality);
+    if !(1..=capture_fps).contains(&fps) {
+        return Err(format!("fps must be between 1 and the capture rate ({})", capture_fps));
+    }
+    if !(0.0..=1.0).contains(&quality) {
+        return Err("quality must be between 0.0 and 1.0".to_string());
+    }
+
+    let profile = Profile::new(fps, quality);
+    let frames = state.profiles.subscribe(profile).ok_or_else(|| {
+        format!(
+            "Too many distinct quality profiles in use (max {})",
+            state.config.server.max_client_profiles
+        )
+    })?;
+    Ok((frames, Some(profile)))
+}
+
+/// Count a connection that didn't resume a session as a new viewer
+fn count_new_viewer(state: &AppState, viewer: &mut Viewer) {
+    if *viewer == Viewer::Pending {
+        state.metrics.count_new_viewer();
+        *viewer = Viewer::New;
+    }
+}
+
+/// Carry on as the viewer of session `id` instead of the new session this
+/// connection's hello started
+fn resume_session(
+    state: &AppState,
+    remote_addr: SocketAddr,
+    session: &mut String,
+    viewer: &mut Viewer,
+    id: String,
+) -> ServerMessage {
+    if *viewer != Viewer::Pending {
+        return ServerMessage::error(
+            ErrorCode::CommandFailed,
+            "A session can only be resumed before any other command",
+        );
+    }
+    if !state.sessions.resume(&id) {
+        count_new_viewer(state, viewer);
+        return ServerMessage::error(ErrorCode::CommandFailed, "Unknown or expired session, or still in use");
+    }
+
+    info!("Client {} resumed session {}", remote_addr, id);
+    state.sessions.remove(session);
+    state.metrics.record_session_resumed();
+    *session = id;
+    *viewer = Viewer::Resumed;
+    ServerMessage::SessionResumed {
+        session: session.clone(),
+    }
+}
+
+fn handle_command(
+    command: ClientCommand,
+    remote_addr: SocketAddr,
+    state: &AppState,
+    connection: &Arc<Connection>,
+    frame_rx: &mut Option<fanout::Receiver<FrameMessage>>,
+    profile: &mut Option<Profile>,
+    delivery: &mut Delivery,
+) -> ServerMessage {
+    match command {
+        ClientCommand::Ping { nonce } => ServerMessage::Pong {
+            nonce,
+            server_time: clock::now_ms(),
+        },
+        ClientCommand::Pause => {
+            if !matches!(delivery, Delivery::Paused(_)) {
+                debug!("Client {} paused", remote_addr);
+                // A pending grab is dropped along with its subscription
+                if connection.is_pull() {
+                    *frame_rx = None;
+                }
+                *delivery = Delivery::Paused(PausedConnection::new(state.metrics.clone(), connection.clone()));
+            }
+            ServerMessage::Paused
+        }
+        ClientCommand::Resume { .. } => {
+            if matches!(delivery, Delivery::Paused(_)) {
+                debug!("Client {} resumed", remote_addr);
+                *delivery = if connection.is_pull() {
+                    Delivery::Idle
+                } else {
+                    Delivery::AwaitingKeyframe
+                };
+            }
+            ServerMessage::Resumed
+        }
+        ClientCommand::Grab => match delivery {
+            _ if !connection.is_pull() => ServerMessage::error(
+                ErrorCode::CommandFailed,
+                "grab is only for pull mode, connect with ?pull=true",
+            ),
+            Delivery::Paused(_) => ServerMessage::error(ErrorCode::CommandFailed, "Paused, resume first"),
+            _ => {
+                if let Some(keyframe) = current_keyframe(state).filter(|_| profile.is_none()) {
+                    *delivery = Delivery::Grabbed(keyframe);
+                    return ServerMessage::Grabbing;
+                }
+                if frame_rx.is_none() {
+                    let frames = match *profile {
+                        Some(profile) => state.profiles.subscribe(profile),
+                        None => Some(state.frame_tx.subscribe()),
+                    };
+                    let Some(frames) = frames else {
+                        return ServerMessage::error(
+                            ErrorCode::CommandFailed,
+                            format!(
+                                "Too many distinct quality profiles in use (max {})",
+                                state.config.server.max_client_profiles
+                            ),
+                        );
+                    };
+                    *frame_rx = Some(frames);
+                    state.viewer_joined.notify_one();
+                }
+                *delivery = Delivery::Grab;
+                ServerMessage::Grabbing
+            }
+        },
+        ClientCommand::SetQuality { fps, quality } => match set_quality(state, fps, quality) {
+            Ok((frames, requested)) => {
+                // Pull clients only hold a stream while a grab waits
+                if !connection.is_pull() || frame_rx.is_some() {
+                    *frame_rx = Some(frames);
+                }
+                *profile = requested;
+                // Deltas on the new stream are of keyframes this client
+                // hasn't seen
+                if matches!(delivery, Delivery::Active) {
+                    *delivery = Delivery::AwaitingKeyframe;
+                }
+                debug!("Client {} switched to {:?}", remote_addr, requested);
+                ServerMessage::QualitySet {
+                    fps: stream_fps(state, requested),
+                    quality: requested
+                        .map_or_else(|| state.live_config.borrow().capture.quality, |p| p.quality as f32 / 100.0),
+                }
+            }
+            Err(message) => ServerMessage::error(ErrorCode::CommandFailed, message),
+        },
+        ClientCommand::SetMonitor { index } => {
+            let count = match capture::list_monitors() {
+                Ok(monitors) => monitors.len(),
+                Err(e) => return ServerMessage::error(ErrorCode::CommandFailed, e.to_string()),
+            };
+            if index >= count {
+                return ServerMessage::error(
+                    ErrorCode::CommandFailed,
+                    format!("Monitor index {} out of range ({} monitors)", index, count),
+                );
+            }
+
+            info!("Client {} switched capture to monitor {}", remote_addr, index);
+            state.monitor_select.send_replace(Some(index));
+            ServerMessage::MonitorSet { index }
+        }
+        ClientCommand::SetRedactions { enabled, token } => {
+            // Not by address: behind a reverse proxy every client looks local
+            let admin = state.config.server.admin_token.as_deref();
+            let authorized = admin
+                .zip(token.as_deref())
+                .is_some_and(|(admin, token)| auth::constant_time_eq(token.as_bytes(), admin.as_bytes()));
+            if !authorized {
+                warn!("Client {} tried to switch redactions without the admin token", remote_addr);
+                return ServerMessage::error(ErrorCode::Unauthorized, "Switching redactions needs the admin token");
+            }
+
+            info!(
+                "Client {} switched redactions {}",
+                remote_addr,
+                if enabled { "on" } else { "off" }
+            );
+            state.redactions_enabled.send_replace(enabled);
+            ServerMessage::RedactionsSet { enabled }
+        }
+        ClientCommand::Unknown => ServerMessage::error(ErrorCode::UnknownCommand, "Unknown command"),
+    }
+}
+
+/// Hold a queued client until a slot frees up, keeping it informed of its
+/// position. Returns `None` if the client disconnects while waiting.
+async fn wait_in_queue(socket: &mut WebSocket, ticket: QueueTicket) -> Option<Slot> {
+    let mut status_interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    let mut last_position = 0;
+    let slot = ticket.wait();
+    tokio::pin!(slot);
+
+    loop {
+        tokio::select! {
+            slot = &mut slot => return Some(slot),
+
+            _ = status_interval.tick() => {
+                // 0 once handed a slot, about to be admitted
+                let position = ticket.position();
+                if position != 0 && position != last_position {
+                    let msg = ServerMessage::Queued { position }.to_message().ok()?;
+                    socket.send(msg).await.ok()?;
+                    last_position = position;
+                }
+            }
+
+            msg_result = socket.recv() => match msg_result {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The next frame on `frame_rx`, or never without one
+async fn next_frame(frame_rx: &mut Option<fanout::Receiver<FrameMessage>>) -> Result<FrameMessage, RecvError> {
+    match frame_rx {
+        Some(frame_rx) => frame_rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The cached keyframe, if it is still the newest frame: captured within
+/// the last frame interval, while capture is running
+fn current_keyframe(state: &AppState) -> Option<FrameMessage> {
+    if state.metrics.is_capture_idle() {
+        return None;
+    }
+    let latest = state.latest_frame.read().unwrap_or_else(PoisonError::into_inner).clone()?;
+    let header = read_frame_header(&latest).ok()?;
+    let interval = state.live_config.borrow().frame_interval();
+    let age = Duration::from_millis(clock::now_ms().saturating_sub(header.timestamp));
+    (age <= interval).then_some(latest)
+}
+
+/// Send a frame answering a `grab` as is: pacing, rate and bitrate limits
+/// are for streams, not single frames
+async fn send_grabbed(
+    socket: &mut WebSocket,
+    state: &AppState,
+    connection: &Connection,
+    frame_data: FrameMessage,
+) -> Result<(), axum::Error> {
+    let len = frame_data.len();
+    if let Err(e) = socket.send(Message::Binary(frame_data.to_vec())).await {
+        debug!("Failed to send grabbed frame, client disconnected");
+        return Err(e);
+    }
+    state.metrics.increment_frames_delivered();
+    connection.record_frame_delivered();
+    state.metrics.add_bytes_sent(len);
+    connection.record_bytes_sent(len);
+    Ok(())
+}
+
+/// Skip past every queued frame to the newest one, for `latest_frame_only`.
+/// Also returns the newest skipped keyframe when the newest frame is a delta
+/// that needs it.
+fn take_latest(
+    frame_rx: &mut fanout::Receiver<FrameMessage>,
+    mut newest: FrameMessage,
+    state: &AppState,
+) -> (Option<FrameMessage>, FrameMessage) {
+    let mut keyframe = None;
+    let mut skipped = 0;
+
+    loop {
+        match frame_rx.try_recv() {
+            Ok(frame_data) => {
+                let older = std::mem::replace(&mut newest, frame_data);
+                if is_keyframe(&older) {
+                    keyframe = Some(older);
+                }
+                skipped += 1;
+            }
+            Err(TryRecvError::Lagged(_)) => {
+                state.metrics.increment_dropped_frames();
+            }
+            Err(_) => break,
+        }
+    }
+
+    let keyframe = keyframe.filter(|_| !is_keyframe(&newest));
+    state
+        .metrics
+        .add_superseded_frames(skipped - keyframe.is_some() as u64);
+    (keyframe, newest)
+}
+
+fn is_keyframe(frame_data: &[u8]) -> bool {
+    read_frame_header(frame_data).is_ok_and(|header| header.is_keyframe)
+}
+
+/// Whether a frame is older than `max_age_ms` (zero disables the check).
+fn is_expired(frame_data: &[u8], max_age_ms: u64) -> bool {
+    if max_age_ms == 0 {
+        return false;
+    }
+
+    let Ok(header) = read_frame_header(frame_data) else {
+        return false;
+    };
+    clock::now_ms().saturating_sub(header.timestamp) > max_age_ms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::{parse_frame_message, Compressor, FrameDecoder, FRAME_HEADER_LEN},
+        connections::ConnectionState,
+        testing,
+    };
+    use std::sync::atomic::AtomicU64;
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite;
+
+    #[tokio::test]
+    async fn hello_describes_the_stream() {
+        let state = testing::state(|c| c.capture.fps = 24);
+        let mut client = testing::connect(testing::serve(&state).await, "").await;
+
+        let hello = testing::next_json(&mut client).await;
+        assert_eq!(hello["type"], "hello");
+        assert_eq!(hello["fps"], 24);
+        assert_eq!(hello["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(hello["format"], "zstd");
+        assert!(hello["session"].is_string());
+    }
+
+    /// `count` keyframes of 16x16 uncompressed pixels, each different so
+    /// none becomes a hold marker
+    fn keyframes(count: u8) -> Vec<FrameMessage> {
+        let mut config = crate::config::Config::default().compression;
+        config.enabled = false;
+        let mut compressor = Compressor::new(config, 0.8, 30, Arc::new(AtomicU64::new(0)));
+        (0..count)
+            .map(|i| compressor.create_frame_message(vec![i; 16 * 16 * 4], 16, 16).unwrap())
+            .collect()
+    }
+
+    /// A keyframe every `interval` frames with deltas in between, each frame
+    /// one pixel different from the last
+    fn delta_stream(count: u8, interval: u64) -> Vec<FrameMessage> {
+        let mut config = crate::config::Config::default().compression;
+        config.keyframe_interval = interval;
+        config.max_hold_frames = 0;
+        let mut compressor = Compressor::new(config, 0.8, 30, Arc::new(AtomicU64::new(0)));
+        let mut rgba = vec![0; 16 * 16 * 4];
+        (0..count)
+            .map(|i| {
+                rgba[i as usize * 4] = 255;
+                compressor.create_frame_message(rgba.clone(), 16, 16).unwrap()
+            })
+            .collect()
+    }
+
+    /// Connect and wait until the client is subscribed to the shared stream
+    async fn subscribed(state: &AppState, query: &str) -> testing::Client {
+        let mut client = testing::connect(testing::serve(state).await, query).await;
+        testing::next_json(&mut client).await;
+        while state.frame_tx.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        client
+    }
+
+    /// Binary messages received until none arrives for a while
+    async fn drain_frames(client: &mut testing::Client) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Ok(message) = tokio::time::timeout(Duration::from_millis(300), testing::next_message(client)).await {
+            if let tungstenite::Message::Binary(frame) = message {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn bitrate_cap_delivers_fewer_frames_than_captured() {
+        // 1000 bytes a second, less than two frames
+        let state = testing::state(|c| c.server.max_bitrate_kbps = 8);
+        let mut client = subscribed(&state, "").await;
+
+        let frames = keyframes(10);
+        for frame in &frames {
+            state.frame_tx.send(frame.clone()).await.unwrap();
+        }
+
+        let delivered = drain_frames(&mut client).await;
+        assert!(!delivered.is_empty());
+        assert!(delivered.len() < frames.len(), "{} of {} delivered", delivered.len(), frames.len());
+
+        let summary = state.metrics.get_summary();
+        assert_eq!(summary.frames_delivered, delivered.len() as u64);
+        assert_eq!(summary.frames_throttled, (frames.len() - delivered.len()) as u64);
+        let bytes: usize = delivered.iter().map(Vec::len).sum();
+        assert_eq!(summary.bytes_sent, bytes as u64);
+        assert_eq!(state.connections.list()[0].bytes_sent, bytes as u64);
+    }
+
+    #[tokio::test]
+    async fn bytes_sent_has_no_per_client_series() {
+        let state = testing::state(|_| {});
+        let mut client = subscribed(&state, "").await;
+        state.frame_tx.send(keyframes(1).remove(0)).await.unwrap();
+        assert_eq!(drain_frames(&mut client).await.len(), 1);
+
+        let text = state.metrics.get_summary().to_prometheus();
+        assert!(!text.contains("client="));
+        assert!(text.lines().any(|line| line.starts_with("retrostream_bytes_sent_total ")));
+    }
+
+    /// Wait up to five seconds for `done`
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while !done() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        done()
+    }
+
+    #[tokio::test]
+    async fn client_that_never_pongs_is_dropped() {
+        let state = testing::state(|c| {
+            c.server.ping_interval_ms = 20;
+            c.server.pong_timeout_ms = 100;
+        });
+        // Pongs are only sent while the client reads, and it stops after the hello
+        let mut client = testing::connect(testing::serve(&state).await, "").await;
+        testing::next_json(&mut client).await;
+        assert_eq!(state.metrics.get_active_connections(), 1);
+
+        assert!(eventually(|| state.metrics.get_active_connections() == 0).await);
+        assert!(state.connections.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_that_pongs_stays_connected() {
+        let state = testing::state(|c| {
+            c.server.ping_interval_ms = 20;
+            c.server.pong_timeout_ms = 100;
+        });
+        let mut client = testing::connect(testing::serve(&state).await, "").await;
+        testing::next_json(&mut client).await;
+
+        // Reading answers pings
+        let reading = tokio::time::timeout(Duration::from_millis(400), testing::next_message(&mut client)).await;
+        assert!(reading.is_err(), "unexpected message {:?}", reading);
+        assert_eq!(state.metrics.get_active_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn pause_stops_frames_and_resume_restarts_them() {
+        let state = testing::state(|_| {});
+        let mut client = subscribed(&state, "").await;
+        let frames = keyframes(4);
+
+        testing::send_json(&mut client, serde_json::json!({"cmd": "pause"})).await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "paused");
+        assert_eq!(state.metrics.get_summary().paused_connections, 1);
+        assert!(matches!(state.connections.list()[0].state, ConnectionState::Paused));
+
+        for frame in &frames[..2] {
+            state.frame_tx.send(frame.clone()).await.unwrap();
+        }
+        assert!(drain_frames(&mut client).await.is_empty());
+
+        testing::send_json(&mut client, serde_json::json!({"cmd": "resume"})).await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "resumed");
+        assert_eq!(state.metrics.get_summary().paused_connections, 0);
+
+        for frame in &frames[2..] {
+            state.frame_tx.send(frame.clone()).await.unwrap();
+        }
+        let delivered = drain_frames(&mut client).await;
+        assert_eq!(delivered, [frames[2].to_vec(), frames[3].to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn resumed_session_is_not_counted_again() {
+        let state = testing::state(|_| {});
+        let addr = testing::serve(&state).await;
+
+        let mut first = testing::connect(addr, "").await;
+        let session = testing::next_json(&mut first).await["session"].as_str().unwrap().to_string();
+        // Any other command settles it as a new viewer
+        testing::send_json(&mut first, serde_json::json!({"cmd": "ping", "nonce": 1})).await;
+        assert_eq!(testing::next_json(&mut first).await["type"], "pong");
+        first.close(None).await.unwrap();
+        assert!(eventually(|| state.metrics.get_summary().active_connections == 0).await);
+        assert_eq!(state.metrics.get_summary().total_connections, 1);
+
+        let mut second = testing::connect(addr, "").await;
+        assert_eq!(testing::next_json(&mut second).await["type"], "hello");
+        testing::send_json(&mut second, serde_json::json!({"cmd": "resume", "session": session})).await;
+        let reply = testing::next_json(&mut second).await;
+        assert_eq!(reply["type"], "session_resumed");
+        assert_eq!(reply["session"], session.as_str());
+
+        // Too late to resume once it has been settled
+        testing::send_json(&mut second, serde_json::json!({"cmd": "resume", "session": session})).await;
+        assert_eq!(testing::next_json(&mut second).await["code"], "command_failed");
+
+        second.close(None).await.unwrap();
+        assert!(eventually(|| state.metrics.get_summary().active_connections == 0).await);
+        let summary = state.metrics.get_summary();
+        assert_eq!(summary.total_connections, 1);
+        assert_eq!(summary.sessions_resumed, 1);
+    }
+
+    #[tokio::test]
+    async fn ping_echoes_its_nonce() {
+        let state = testing::state(|_| {});
+        let mut client = testing::connect(testing::serve(&state).await, "").await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "hello");
+
+        for nonce in [serde_json::json!(12345), serde_json::json!("abc"), serde_json::json!({"seq": [1, 2]})] {
+            let before = clock::now_ms();
+            testing::send_json(&mut client, serde_json::json!({"cmd": "ping", "nonce": nonce})).await;
+            let pong = testing::next_json(&mut client).await;
+            assert_eq!(pong["type"], "pong");
+            assert_eq!(pong["nonce"], nonce);
+            assert!(pong["server_time"].as_u64().unwrap() >= before);
+        }
+
+        testing::send_json(&mut client, serde_json::json!({"cmd": "ping"})).await;
+        assert_eq!(testing::next_json(&mut client).await["code"], "invalid_command");
+    }
+
+    #[tokio::test]
+    async fn late_joiner_starts_with_the_latest_keyframe() {
+        let state = testing::state(|_| {});
+        let frames = delta_stream(4, 4);
+        assert!(is_keyframe(&frames[0]) && !is_keyframe(&frames[2]));
+        // The capture loop caches each keyframe it sends
+        *state.latest_frame.write().unwrap() = Some(frames[0].clone());
+
+        let mut client = subscribed(&state, "").await;
+        state.frame_tx.send(frames[2].clone()).await.unwrap();
+
+        let delivered = drain_frames(&mut client).await;
+        assert_eq!(delivered, [frames[0].to_vec(), frames[2].to_vec()]);
+        assert!(is_keyframe(&delivered[0]));
+    }
+
+    /// `frame` as if captured `age_ms` earlier
+    fn aged(frame: &FrameMessage, age_ms: u64) -> FrameMessage {
+        let (mut header, payload) = parse_frame_message(frame).unwrap();
+        header.timestamp -= age_ms;
+        // The header sits right before the payload, after the prefix
+        let start = frame.len() - payload.len() - FRAME_HEADER_LEN;
+        let mut bytes = frame.to_vec();
+        bytes[start..start + FRAME_HEADER_LEN].copy_from_slice(&header.to_bytes());
+        bytes.into()
+    }
+
+    #[tokio::test]
+    async fn expired_keyframes_are_still_sent() {
+        let state = testing::state(|c| c.server.max_frame_age_ms = 1000);
+        let frames = delta_stream(6, 3);
+        assert!(is_keyframe(&frames[3]) && !is_keyframe(&frames[4]));
+        let late = aged(&frames[3], 5000);
+        let mut client = subscribed(&state, "").await;
+        for frame in [&frames[0], &aged(&frames[1], 5000), &frames[2], &late, &frames[4], &frames[5]] {
+            state.frame_tx.send(frame.clone()).await.unwrap();
+        }
+
+        // Only the expired delta is dropped
+        let delivered = drain_frames(&mut client).await;
+        let expected = [&frames[0], &frames[2], &late, &frames[4], &frames[5]].map(|frame| frame.to_vec());
+        assert_eq!(delivered, expected);
+
+        // and the deltas after the late keyframe still apply
+        let mut decoder = FrameDecoder::default();
+        let decoded: Vec<_> = delivered.iter().map(|frame| decoder.decode(frame).unwrap()).collect();
+        let (_, rgba) = decoded[4].as_ref().expect("delta after the late keyframe");
+        assert_eq!(rgba[..6 * 4].iter().step_by(4).collect::<Vec<_>>(), [&255; 6]);
+    }
+
+    #[tokio::test]
+    async fn frames_expiring_while_paced_are_dropped() {
+        // 200ms slots, longer than frames may age
+        let state = testing::state(|c| {
+            c.capture.fps = 5;
+            c.server.pacing = true;
+            c.server.max_frame_age_ms = 50;
+        });
+        let frames = delta_stream(2, 3);
+        let mut client = subscribed(&state, "").await;
+        for frame in &frames {
+            state.frame_tx.send(frame.clone()).await.unwrap();
+        }
+
+        assert_eq!(drain_frames(&mut client).await, [frames[0].to_vec()]);
+        assert_eq!(state.metrics.get_summary().frames_expired, 1);
+    }
+
+    #[tokio::test]
+    async fn switching_streams_waits_for_a_keyframe() {
+        let state = testing::state(|_| {});
+        let frames = delta_stream(6, 4);
+        let mut client = subscribed(&state, "").await;
+        state.frame_tx.send(frames[0].clone()).await.unwrap();
+        state.frame_tx.send(frames[1].clone()).await.unwrap();
+        assert_eq!(drain_frames(&mut client).await.len(), 2);
+
+        // Back to the shared stream, as good as any other stream switch
+        testing::send_json(&mut client, serde_json::json!({"cmd": "set_quality"})).await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "quality_set");
+        for frame in &frames[2..] {
+            state.frame_tx.send(frame.clone()).await.unwrap();
+        }
+
+        let delivered = drain_frames(&mut client).await;
+        assert_eq!(delivered, [frames[4].to_vec(), frames[5].to_vec()]);
+    }
+
+    #[test]
+    fn parses_client_commands() {
+        let parse = |text: &str| serde_json::from_str::<ClientCommand>(text);
+        assert!(matches!(parse(r#"{"cmd":"set_monitor","index":1}"#), Ok(ClientCommand::SetMonitor { index: 1 })));
+        assert!(matches!(
+            parse(r#"{"cmd":"set_quality","fps":15}"#),
+            Ok(ClientCommand::SetQuality { fps: Some(15), quality: None })
+        ));
+        assert!(matches!(parse(r#"{"cmd":"resume"}"#), Ok(ClientCommand::Resume { session: None })));
+        assert!(matches!(parse(r#"{"cmd":"grab","extra":true}"#), Ok(ClientCommand::Grab)));
+        assert!(matches!(parse(r#"{"cmd":"from_the_future"}"#), Ok(ClientCommand::Unknown)));
+
+        assert!(parse(r#"{"cmd":"set_monitor"}"#).is_err());
+        assert!(parse(r#"{"cmd":"set_monitor","index":"one"}"#).is_err());
+        assert!(parse(r#"{"index":1}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_commands_get_an_error_reply() {
+        let state = testing::state(|_| {});
+        let mut client = testing::connect(testing::serve(&state).await, "").await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "hello");
+
+        for (text, code) in [
+            ("not json", "malformed_command"),
+            (r#"{"cmd":"pause""#, "malformed_command"),
+            (r#"{"index":1}"#, "invalid_command"),
+            (r#"{"cmd":"set_monitor","index":-1}"#, "invalid_command"),
+            (r#"{"cmd":"from_the_future"}"#, "unknown_command"),
+            (r#"{"cmd":"grab"}"#, "command_failed"),
+        ] {
+            client.send(tungstenite::Message::Text(text.to_string())).await.unwrap();
+            let reply = testing::next_json(&mut client).await;
+            assert_eq!(reply["type"], "error", "{}", text);
+            assert_eq!(reply["code"], code, "{}", text);
+            assert!(reply["message"].is_string());
+        }
+
+        // Still connected and answering
+        testing::send_json(&mut client, serde_json::json!({"cmd": "pause"})).await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "paused");
+    }
+
+    #[tokio::test]
+    async fn switching_redactions_needs_the_admin_token() {
+        let state = testing::state(|c| c.server.admin_token = Some("letmein".to_string()));
+        let mut client = testing::connect(testing::serve(&state).await, "").await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "hello");
+
+        for command in [
+            serde_json::json!({"cmd": "set_redactions", "enabled": false}),
+            serde_json::json!({"cmd": "set_redactions", "enabled": false, "token": "letmeout"}),
+        ] {
+            testing::send_json(&mut client, command).await;
+            assert_eq!(testing::next_json(&mut client).await["code"], "unauthorized");
+            assert!(*state.redactions_enabled.borrow());
+        }
+
+        testing::send_json(&mut client, serde_json::json!({"cmd": "set_redactions", "enabled": false, "token": "letmein"})).await;
+        let reply = testing::next_json(&mut client).await;
+        assert_eq!(reply["type"], "redactions_set");
+        assert_eq!(reply["enabled"], false);
+        assert!(!*state.redactions_enabled.borrow());
+    }
+
+    #[tokio::test]
+    async fn redactions_stay_on_without_an_admin_token() {
+        let state = testing::state(|_| {});
+        let mut client = testing::connect(testing::serve(&state).await, "").await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "hello");
+
+        testing::send_json(&mut client, serde_json::json!({"cmd": "set_redactions", "enabled": false, "token": ""})).await;
+        assert_eq!(testing::next_json(&mut client).await["code"], "unauthorized");
+        assert!(*state.redactions_enabled.borrow());
+    }
+
+    #[tokio::test]
+    async fn pull_mode_only_sends_grabbed_frames() {
+        let state = testing::state(|_| {});
+        let frames = keyframes(3);
+        let mut client = testing::connect(testing::serve(&state).await, "pull=true").await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "hello");
+        testing::send_json(&mut client, serde_json::json!({"cmd": "ping", "nonce": 1})).await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "pong");
+
+        // Not a viewer keeping capture running
+        assert_eq!(state.frame_tx.receiver_count(), 0);
+        assert!(drain_frames(&mut client).await.is_empty());
+
+        testing::send_json(&mut client, serde_json::json!({"cmd": "grab"})).await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "grabbing");
+        assert!(eventually(|| state.frame_tx.receiver_count() == 1).await);
+        state.frame_tx.send(frames[0].clone()).await.unwrap();
+        assert_eq!(drain_frames(&mut client).await, [frames[0].to_vec()]);
+
+        // One frame per grab
+        assert!(eventually(|| state.frame_tx.receiver_count() == 0).await);
+        assert!(state.frame_tx.send(frames[1].clone()).await.is_err());
+        assert!(drain_frames(&mut client).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn grab_is_answered_from_a_current_keyframe() {
+        // A second between captures, so the cached keyframe stays current
+        let state = testing::state(|c| c.capture.fps = 1);
+        let mut client = testing::connect(testing::serve(&state).await, "pull=true").await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "hello");
+
+        let latest = keyframes(1).remove(0);
+        *state.latest_frame.write().unwrap() = Some(latest.clone());
+        testing::send_json(&mut client, serde_json::json!({"cmd": "grab"})).await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "grabbing");
+        assert_eq!(drain_frames(&mut client).await, [latest.to_vec()]);
+        assert_eq!(state.frame_tx.receiver_count(), 0);
+
+        // While capture idles the cached keyframe may be long out of date
+        state.metrics.start_capture_idle();
+        testing::send_json(&mut client, serde_json::json!({"cmd": "grab"})).await;
+        assert_eq!(testing::next_json(&mut client).await["type"], "grabbing");
+        assert!(eventually(|| state.frame_tx.receiver_count() == 1).await);
+        assert!(drain_frames(&mut client).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn quality_requests_follow_the_reloaded_capture_rate() {
+        let state = testing::state(|c| c.capture.fps = 30);
+        let mut reloaded = (*state.config).clone();
+        reloaded.capture.fps = 10;
+        reloaded.capture.quality = 0.5;
+        state.live_config.send_replace(Arc::new(reloaded));
+
+        let error = set_quality(&state, Some(20), None).err().expect("above the capture rate");
+        assert!(error.contains("(10)"), "{}", error);
+        assert_eq!(stream_fps(&state, None), 10);
+
+        let (_frames, profile) = set_quality(&state, None, Some(0.8)).unwrap();
+        assert_eq!(stream_fps(&state, profile), 10);
+        let (_frames, profile) = set_quality(&state, Some(5), None).unwrap();
+        assert_eq!(profile.unwrap().quality, 50);
+    }
 }